@@ -0,0 +1,104 @@
+// throughput and memory of transaction stores: cargo bench --bench tx_store [-- deposits]
+use rand::Rng;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tx::tx::{Amount, Asset, Transaction, TransactionId, TransactionType};
+use tx::tx_dense_store::DenseTransactionStore;
+use tx::tx_disk_store::DiskTransactionStore;
+use tx::tx_service::{TransactionService, TransactionStore, TransactionWithState};
+
+// heap bytes in use, to report memory of the stores
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn deposit(tx_id: TransactionId) -> TransactionWithState {
+    TransactionWithState::new(Transaction {
+        tx_type: TransactionType::Deposit,
+        client_id: (tx_id % 1000) as u16,
+        tx_id,
+        amount: Some(Amount::from_units(tx_id as u64)),
+        asset: Asset::default(),
+    })
+}
+
+// puts every deposit, gets random ones and gets the most recent ones (hot entries),
+// prints millions of operations per second and MiB of memory per million deposits
+// (store created by `open` is counted too)
+fn bench<S: TransactionStore>(name: &str, open: impl FnOnce() -> S, deposits: u32) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let mut store = open();
+    let rate = |start: Instant, ops: u32| ops as f64 / start.elapsed().as_secs_f64() / 1e6;
+
+    let start = Instant::now();
+    for tx_id in 0..deposits {
+        store.insert(deposit(tx_id)).unwrap();
+    }
+    let put = rate(start, deposits);
+    let memory = (ALLOCATED.load(Ordering::Relaxed) - before) as f64 / deposits as f64 * 1e6;
+
+    let mut rng = rand::thread_rng();
+    let lookups = deposits.min(1_000_000);
+    let start = Instant::now();
+    for _ in 0..lookups {
+        let tx_id = rng.gen_range(0..deposits);
+        assert!(store.get(tx_id).unwrap().is_some());
+    }
+    let random = rate(start, lookups);
+
+    let hot = deposits - deposits.min(1_000);
+    let start = Instant::now();
+    for _ in 0..lookups {
+        let tx_id = rng.gen_range(hot..deposits);
+        assert!(store.get(tx_id).unwrap().is_some());
+    }
+    let recent = rate(start, lookups);
+
+    println!(
+        "{:<24} put {:>6.2}  random get {:>6.2}  recent get {:>6.2}  M ops/s  {:>7.1} MiB/M",
+        name,
+        put,
+        random,
+        recent,
+        memory / (1 << 20) as f64
+    );
+}
+
+fn main() {
+    let deposits = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(2_000_000);
+    println!("{} deposits", deposits);
+
+    bench("memory (HashMap)", TransactionService::new, deposits);
+    bench(
+        "dense",
+        || DenseTransactionStore::new(Default::default(), 0, Default::default()),
+        deposits,
+    );
+    for cache in [10_000, 1_000_000] {
+        let dir = tempfile::tempdir().expect("Cannot create temporary directory");
+        let open = || {
+            DiskTransactionStore::open(dir.path(), cache, Default::default())
+                .expect("Cannot open transaction store")
+        };
+        bench(&format!("disk, cache {}", cache), open, deposits);
+    }
+}
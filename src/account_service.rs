@@ -1,23 +1,67 @@
+use crate::amount::FormattedAmount;
+use crate::policy::{DisputeShortfall, LockedAccounts};
 use crate::tx::*;
 
 use serde::Serialize;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt;
+use std::io;
+use strum_macros::{EnumString, IntoStaticStr};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Account {
     pub client_id: ClientId,
-    pub available: AmountDecimal,
-    pub held: AmountDecimal,
-    pub locked: bool,
+    pub asset: Asset,
+    pub available: Amount,
+    pub held: Amount,
+    pub status: AccountStatus,
+    // amount owed by the client, available is below zero by it (DisputeShortfall::Negative)
+    pub receivable: Amount,
+    // disputed amount still to be held from new deposits (DisputeShortfall::HoldTarget)
+    pub hold_pending: Amount,
+}
+
+#[derive(EnumString, Debug, Copy, Clone, PartialEq, Default, Serialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    // temporary, only disputes, resolves and chargebacks are accepted until unlock
+    Frozen,
+    // after a chargeback, nothing is accepted until unlock (see LockedAccounts)
+    Locked,
+    // final, available funds were paid out
+    Closed,
+}
+
+// storage of accounts, TransactionProcessor works against it
+pub trait AccountStore {
+    // account of the client in the asset, created with zero balance when missing
+    fn ensure_account(&mut self, client_id: ClientId, asset: Asset) -> &mut Account;
+
+    fn account(&mut self, client_id: ClientId, asset: Asset) -> Option<&mut Account>;
+
+    // replaces the account with the same client and asset
+    fn insert(&mut self, account: Account);
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = &Account> + '_>;
 }
 
-type AccountStorage = HashMap<ClientId, Account>;
+// client balances are kept separately for every asset
+type AccountStorage = HashMap<(ClientId, Asset), Account>;
 
 pub struct AccountService {
     accounts: AccountStorage,
 }
 
+impl Default for AccountService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // in-memory account storage
 impl AccountService {
     pub fn new() -> Self {
@@ -26,76 +70,224 @@ impl AccountService {
         }
     }
 
-    pub fn ensure_account(&mut self, client_id: ClientId) -> &mut Account {
-        let account = self
-            .accounts
-            .entry(client_id)
-            .or_insert_with(|| Account::new(client_id, 0));
-        account
-    }
-
-    pub fn iter(&self) -> AccountIter {
+    pub fn iter(&self, format: AmountFormat) -> AccountIter<'_> {
         AccountIter {
             inner: self.accounts.values(),
+            format,
         }
     }
 }
 
+impl AccountStore for AccountService {
+    fn ensure_account(&mut self, client_id: ClientId, asset: Asset) -> &mut Account {
+        self.accounts
+            .entry((client_id, asset))
+            .or_insert_with(|| Account::new(client_id, asset, Amount::ZERO))
+    }
+
+    fn account(&mut self, client_id: ClientId, asset: Asset) -> Option<&mut Account> {
+        self.accounts.get_mut(&(client_id, asset))
+    }
+
+    fn insert(&mut self, account: Account) {
+        self.accounts
+            .insert((account.client_id, account.asset), account);
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = &Account> + '_> {
+        Box::new(self.accounts.values())
+    }
+}
+
 // should provide 'atomic' operations on account balance
 impl Account {
-    pub fn new(client_id: ClientId, available: AmountDecimal) -> Self {
+    pub fn new(client_id: ClientId, asset: Asset, available: Amount) -> Self {
         Self {
             client_id,
+            asset,
             available,
-            held: 0,
-            locked: false,
+            held: Amount::ZERO,
+            status: AccountStatus::Active,
+            receivable: Amount::ZERO,
+            hold_pending: Amount::ZERO,
+        }
+    }
+
+    // no transactions are accepted (administration operations aside)
+    pub fn is_locked(&self) -> bool {
+        matches!(self.status, AccountStatus::Locked | AccountStatus::Closed)
+    }
+
+    // fails when the status does not accept a transaction of the type
+    pub fn check_status(
+        &self,
+        tx_type: TransactionType,
+        locked: LockedAccounts,
+    ) -> Result<(), AccountServiceError> {
+        let dispute = matches!(
+            tx_type,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        );
+        match self.status {
+            AccountStatus::Active => Ok(()),
+            AccountStatus::Frozen if dispute => Ok(()),
+            AccountStatus::Locked if dispute && locked == LockedAccounts::AllowDisputes => Ok(()),
+            AccountStatus::Frozen => Err(AccountServiceError::AccountFrozen),
+            AccountStatus::Locked => Err(AccountServiceError::AccountLocked),
+            AccountStatus::Closed => Err(AccountServiceError::AccountClosed),
         }
     }
 
-    pub fn deposit(&mut self, amount: AmountDecimal) -> Result<(), AccountServiceError> {
+    // available and held funds, without the receivable; it cannot overflow, deposit(),
+    // held() and hold_withdrawn() check the total they raise and other operations move
+    // funds between available and held or take them out of the account
+    pub fn total(&self) -> Amount {
+        self.available + self.held
+    }
+
+    // total minus the receivable, can be negative
+    pub fn net_total(&self) -> i128 {
+        self.total().units() as i128 - self.receivable.units() as i128
+    }
+
+    pub fn deposit(&mut self, amount: Amount) -> Result<(), AccountServiceError> {
         checked_add(checked_add(self.available, self.held)?, amount)?;
-        self.available += amount;
+        self.credit(amount);
         Ok(())
     }
 
-    pub fn held(&mut self, amount: AmountDecimal) -> Result<(), AccountServiceError> {
-        if self.available < amount {
-            // TODO max possible amount should be held ?? (this would complicate resolve/chargeback)
-            return Err(AccountServiceError::InsufficientBalance);
-        }
-        self.held = checked_add(self.held, amount)?;
-        self.available -= amount;
+    // incoming funds pay the receivable and top up pending holds first
+    fn credit(&mut self, amount: Amount) {
+        let paid = amount.min(self.receivable);
+        self.receivable -= paid;
+        let topped_up = (amount - paid).min(self.hold_pending);
+        self.hold_pending -= topped_up;
+        self.held += topped_up;
+        self.available += amount - paid - topped_up;
+    }
+
+    // holds a disputed amount, `shortfall` decides what happens when available is not enough,
+    // returns the part which is not held yet
+    pub fn held(
+        &mut self,
+        amount: Amount,
+        shortfall: DisputeShortfall,
+    ) -> Result<Amount, AccountServiceError> {
+        let covered = amount.min(self.available);
+        let missing = amount - covered;
+        let pending = match shortfall {
+            _ if missing == Amount::ZERO => {
+                self.held = checked_add(self.held, amount)?;
+                Amount::ZERO
+            }
+            DisputeShortfall::Reject => return Err(AccountServiceError::InsufficientBalance),
+            DisputeShortfall::HoldTarget => {
+                self.held = checked_add(self.held, covered)?;
+                self.hold_pending = checked_add(self.hold_pending, missing)?;
+                missing
+            }
+            DisputeShortfall::Negative => {
+                // the missing part is held on top of the total
+                checked_add(self.total(), missing)?;
+                self.held = checked_add(self.held, amount)?;
+                self.receivable = checked_add(self.receivable, missing)?;
+                Amount::ZERO
+            }
+        };
+        self.available -= covered;
+        Ok(pending)
+    }
+
+    // withdrawn amount comes back as held, available is unchanged
+    pub fn hold_withdrawn(&mut self, amount: Amount) -> Result<(), AccountServiceError> {
+        checked_add(self.total(), amount)?;
+        self.held += amount;
         Ok(())
     }
 
-    pub fn resolve(&mut self, amount: AmountDecimal) -> Result<(), AccountServiceError> {
-        if self.held < amount {
+    // held amount leaves the account, a part not held yet is no longer pending; `pending` is
+    // what the dispute had not held (see held()), deposits may have topped it up since
+    pub fn release_held(
+        &mut self,
+        amount: Amount,
+        pending: Amount,
+    ) -> Result<(), AccountServiceError> {
+        let from_pending = amount.min(pending).min(self.hold_pending);
+        let from_held = amount - from_pending;
+        if self.held < from_held {
             return Err(AccountServiceError::InsufficientHeldBalance);
         }
+        self.hold_pending -= from_pending;
+        self.held -= from_held;
+        Ok(())
+    }
 
-        self.available = checked_add(self.available, amount)?;
-        self.held -= amount;
+    // held amount is available again, a part not held yet is no longer pending
+    pub fn resolve(&mut self, amount: Amount, pending: Amount) -> Result<(), AccountServiceError> {
+        let from_pending = amount.min(pending).min(self.hold_pending);
+        let from_held = amount - from_pending;
+        if self.held < from_held {
+            return Err(AccountServiceError::InsufficientHeldBalance);
+        }
+        checked_add(self.available, from_held)?;
+        self.hold_pending -= from_pending;
+        self.held -= from_held;
+        self.credit(from_held);
         Ok(())
     }
 }
 
-#[derive(Debug, PartialEq)]
+// reason code is the snake_case variant name, ie. insufficient_balance
+#[derive(Debug, PartialEq, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum AccountServiceError {
     BalanceOverflow,
     AccountLocked,
+    AccountFrozen,
+    AccountClosed,
+    // administration operation of an account which does not exist
+    AccountNotFound,
+    // close of an account with held funds, a pending hold or a receivable
+    AccountNotSettled,
+    // close amount is not the available balance paid out
+    PayoutMismatch,
     TransactionNotFound,
     TransactionDuplicate,
     InsufficientBalance,
     AlreadyRefunded,
+    // dispute of a resolved transaction (ResolvedDisputes::Reject)
+    AlreadyResolved,
     DisputeWrongTransactionType(TransactionType),
     InsufficientHeldBalance,
     MismatchedClient(ClientId, ClientId),
+    MismatchedAsset(Asset, Asset),
     EmptyTransactionAmount,
-    TransactionAmountShouldBeEmpty,
+    // dispute, resolve or chargeback amount is larger than the undisputed (or disputed) part
+    DisputeAmountExceeded,
+    // reasons of deliberately skipped transactions
+    AlreadyDisputed,
+    NotDisputed,
+    AlreadyFrozen,
+    NotLocked,
+    // i/o error of the transaction store, the account is left unchanged
+    StorageFailed(String),
+}
+
+impl AccountServiceError {
+    // stable identifier for reports
+    pub fn code(&self) -> &'static str {
+        self.into()
+    }
 }
 
 impl std::error::Error for AccountServiceError {}
 
+impl From<io::Error> for AccountServiceError {
+    fn from(e: io::Error) -> Self {
+        AccountServiceError::StorageFailed(e.to_string())
+    }
+}
+
 impl fmt::Display for AccountServiceError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // TODO:
@@ -107,33 +299,57 @@ impl fmt::Display for AccountServiceError {
     }
 }
 
+// order of accounts in the output, ties are ordered by client and asset
+#[derive(EnumString, Debug, Copy, Clone, PartialEq, Default)]
+#[strum(serialize_all = "kebab-case")]
+pub enum AccountOrder {
+    #[default]
+    Client,
+    // largest total balance first
+    Total,
+    // locked accounts first
+    Locked,
+}
+
+impl AccountOrder {
+    pub fn compare(self, a: &Account, b: &Account) -> Ordering {
+        let by_client = || (a.client_id, a.asset).cmp(&(b.client_id, b.asset));
+        match self {
+            AccountOrder::Client => by_client(),
+            AccountOrder::Total => b.net_total().cmp(&a.net_total()).then_with(by_client),
+            AccountOrder::Locked => b.is_locked().cmp(&a.is_locked()).then_with(by_client),
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct AccountResult {
-    client: ClientId,
-    #[serde(with = "amount_decimal")]
-    available: AmountDecimal,
-    #[serde(with = "amount_decimal")]
-    held: AmountDecimal,
-    #[serde(with = "amount_decimal")]
-    total: AmountDecimal,
-    locked: bool,
+    pub client: ClientId,
+    pub asset: Asset,
+    pub available: FormattedAmount,
+    pub held: FormattedAmount,
+    pub total: FormattedAmount,
+    pub locked: bool,
+    pub status: AccountStatus,
 }
 
-mod amount_decimal {
-    use super::*;
-    use serde::{self, Serializer};
-
-    pub fn serialize<S>(value: &AmountDecimal, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer
-    {
-        let f: f64 = (*value as f64) / (AMOUNT_BASE as f64);
-        serializer.serialize_f64(f)
+impl AccountResult {
+    pub fn new(a: &Account, format: AmountFormat) -> Self {
+        Self {
+            client: a.client_id,
+            asset: a.asset,
+            available: format.display_difference(a.available, a.receivable),
+            held: format.display(a.held),
+            total: format.display_difference(a.total(), a.receivable),
+            locked: a.is_locked(),
+            status: a.status,
+        }
     }
 }
 
 pub struct AccountIter<'a> {
-    inner: std::collections::hash_map::Values<'a, ClientId, Account>,
+    inner: std::collections::hash_map::Values<'a, (ClientId, Asset), Account>,
+    format: AmountFormat,
 }
 
 impl<'a> Iterator for AccountIter<'a> {
@@ -141,20 +357,11 @@ impl<'a> Iterator for AccountIter<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         let a = self.inner.next()?;
-        Some(AccountResult {
-            client: a.client_id,
-            available: a.available,
-            held: a.held,
-            total: a.available + a.held, // checked_add ?
-            locked: a.locked,
-        })
+        Some(AccountResult::new(a, self.format))
     }
 }
 
-pub fn checked_add(
-    balance: AmountDecimal,
-    val: AmountDecimal,
-) -> Result<AmountDecimal, AccountServiceError> {
+pub fn checked_add(balance: Amount, val: Amount) -> Result<Amount, AccountServiceError> {
     let res = balance.checked_add(val);
     match res {
         None => Err(AccountServiceError::BalanceOverflow),
@@ -169,27 +376,85 @@ mod tests {
 
     #[test]
     fn create_account() {
-        let account: Account = Account::new(1, 0);
+        let account: Account = Account::new(1, Asset::default(), Amount::ZERO);
         assert_eq!(account.client_id, 1);
-        assert_eq!(account.available, 0);
-        assert_eq!(account.held, 0);
-        assert_eq!(account.locked, false);
+        assert_eq!(account.available, Amount::ZERO);
+        assert_eq!(account.held, Amount::ZERO);
+        assert_eq!(account.status, AccountStatus::Active);
+    }
+
+    #[test]
+    fn negative_hold_cannot_overflow_total() {
+        let mut account = Account::new(1, Asset::default(), Amount::ZERO);
+        account.deposit(Amount::from_units(u64::MAX - 10)).unwrap();
+        account
+            .held(Amount::from_units(u64::MAX - 10), DisputeShortfall::Reject)
+            .unwrap();
+        assert_eq!(
+            Err(AccountServiceError::BalanceOverflow),
+            account.held(Amount::from_units(20), DisputeShortfall::Negative)
+        );
+        assert_eq!(Amount::from_units(u64::MAX - 10), account.total());
+        assert_eq!(Amount::ZERO, account.receivable);
     }
 
     #[test]
     fn account_balance_balance() {
-        let mut account: Account = Account::new(1, 0);
-        assert_eq!(account.available, 0);
-        account.deposit(100).unwrap();
-        assert_eq!(account.available, 100);
-        assert_eq!(account.held, 0);
-
-        account.held(50).unwrap();
-        assert_eq!(account.available, 50);
-        assert_eq!(account.held, 50);
-
-        account.resolve(50).unwrap();
-        assert_eq!(account.available, 100);
-        assert_eq!(account.held, 0);
+        let mut account: Account = Account::new(1, Asset::default(), Amount::ZERO);
+        assert_eq!(account.available, Amount::ZERO);
+        account.deposit(Amount::from_units(100)).unwrap();
+        assert_eq!(account.available, Amount::from_units(100));
+        assert_eq!(account.held, Amount::ZERO);
+
+        account
+            .held(Amount::from_units(50), DisputeShortfall::Reject)
+            .unwrap();
+        assert_eq!(account.available, Amount::from_units(50));
+        assert_eq!(account.held, Amount::from_units(50));
+
+        account
+            .resolve(Amount::from_units(50), Amount::ZERO)
+            .unwrap();
+        assert_eq!(account.available, Amount::from_units(100));
+        assert_eq!(account.held, Amount::ZERO);
+    }
+
+    #[test]
+    fn account_result_uses_format() {
+        let mut service = AccountService::new();
+        service
+            .ensure_account(3, Asset::default())
+            .deposit(Amount::from_units(12_345))
+            .unwrap();
+
+        let format = AmountFormat::new(4, crate::amount::Rounding::Reject).unwrap();
+        let result = service.iter(format).next().unwrap();
+        assert_eq!(result.available.to_string(), "1.2345");
+        assert_eq!(result.held.to_string(), "0.0000");
+        assert_eq!(result.total.amount(), Amount::from_units(12_345));
+
+        // receivable is shown as negative available
+        let account = service.ensure_account(3, Asset::default());
+        account
+            .held(Amount::from_units(20_000), DisputeShortfall::Negative)
+            .unwrap();
+        let result = service.iter(format).next().unwrap();
+        assert_eq!(result.available.to_string(), "-0.7655");
+        assert_eq!(result.held.to_string(), "2.0000");
+        assert_eq!(result.total.to_string(), "1.2345");
+    }
+
+    #[test]
+    fn account_order() {
+        let mut a = Account::new(1, Asset::default(), Amount::from_units(5));
+        let mut b = Account::new(2, Asset::default(), Amount::from_units(9));
+        assert_eq!(AccountOrder::Client.compare(&a, &b), Ordering::Less);
+        assert_eq!(AccountOrder::Total.compare(&a, &b), Ordering::Greater);
+        a.status = AccountStatus::Locked;
+        assert_eq!(AccountOrder::Locked.compare(&a, &b), Ordering::Less);
+        b.status = AccountStatus::Closed;
+        b.asset = "EUR".parse().unwrap();
+        a.client_id = 2;
+        assert_eq!(AccountOrder::Locked.compare(&a, &b), Ordering::Less);
     }
 }
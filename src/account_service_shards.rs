@@ -1,46 +1,150 @@
-use crate::account_service::AccountService;
-use crate::tx::Transaction;
-use crate::tx_processor::TransactionProcessor;
-use crate::tx_service::TransactionService;
+use crate::account_service::{
+    Account, AccountOrder, AccountResult, AccountService, AccountServiceError, AccountStore,
+};
+use crate::amount::AmountFormat;
+use crate::audit::AuditEntry;
+use crate::input::InputStats;
+use crate::rejections::{Rejection, RejectionKind};
+use crate::snapshot::{self, EncodedShard, SnapshotRecord, SnapshotWriter};
+use crate::tx::{ClientId, Transaction, TransactionId, TransactionRow};
+use crate::tx_dense_store::{DenseTransactionStore, DenseTransactions};
+use crate::tx_disk_store::{DiskStoreConfig, DiskTransactionStore};
+use crate::tx_ids::TxIdSet;
+use crate::tx_processor::{self, Outcome, TransactionProcessor};
+use crate::tx_service::{
+    BoxedTransactionStore, TransactionService, TransactionStore, TransactionWithState,
+};
+use crate::wal::{WalConfig, WalHeader, WriteAheadLog};
 
 use async_channel;
 use futures_lite::future;
-use std::sync::{Arc, Mutex};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::io;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, OnceLock};
 use std::thread;
 
 // single channel capacity
 const CHANNEL_CAP: usize = 256;
+// snapshot parts a worker encodes ahead of the writer
+const SNAPSHOT_PARTS: usize = 4;
+
+#[derive(Debug, Default, Clone)]
+pub struct ShardsConfig {
+    // scale and rounding of amounts read from input and written to output
+    pub amount_format: AmountFormat,
+    // write-ahead log, when set shard state is recovered from it in run()
+    pub wal: Option<WalConfig>,
+    // rejected and ignored transactions are sent here, otherwise errors are printed on stderr
+    pub rejections: Option<async_channel::Sender<Rejection>>,
+    // applied administration operations (freeze, unlock, close) are sent here
+    pub audit: Option<async_channel::Sender<AuditEntry>>,
+    // business rules, ie. handling of disputed withdrawals
+    pub processor: TransactionProcessor,
+    // transactions are stored on disk instead of in memory
+    pub disk_store: Option<DiskStoreConfig>,
+    // transactions are packed into slots indexed by id (unless stored on disk)
+    pub dense_store: bool,
+}
+
+// called by the worker with the outcome of a transaction, ie. to acknowledge it to a client
+pub type Ack = Box<dyn FnOnce(&Result<Outcome, AccountServiceError>) + Send>;
+
+// messages are handled by the worker in order, so a snapshot request sees
+// every transaction sent to the shard before it
+enum ShardMessage {
+    // outcome is counted in the stats of the input the transaction was read from,
+    // the id of a duplicate was already claimed when it was routed
+    Transaction {
+        row: TransactionRow,
+        duplicate: bool,
+        stats: Option<Arc<InputStats>>,
+        ack: Option<Ack>,
+    },
+    Snapshot(async_channel::Sender<io::Result<EncodedShard>>),
+    // copy of the accounts, for output while running
+    Accounts(async_channel::Sender<Vec<Account>>),
+    // queries answered by the worker, which owns the shard state
+    ClientAccounts(ClientId, async_channel::Sender<Vec<Account>>),
+    LockedAccounts(async_channel::Sender<Vec<Account>>),
+    TransactionState(
+        TransactionId,
+        async_channel::Sender<io::Result<Option<TransactionWithState>>>,
+    ),
+}
 
 pub struct AccountShards {
     shards: usize,
-    // TODO: implement Iterator adaptor and remove 'pub'
-    pub account_services: Vec<Arc<Mutex<AccountService>>>,
-    tx_services: Vec<Arc<Mutex<TransactionService>>>,
+    config: ShardsConfig,
+    account_services: Vec<Arc<Mutex<AccountService>>>,
+    tx_services: Vec<Arc<Mutex<BoxedTransactionStore>>>,
+    // transaction ids are unique across shards
+    tx_ids: Arc<TxIdSet>,
 
     // channels to pass transactions to threads/shards
     channels: Vec<(
-        async_channel::Sender<Transaction>,
-        async_channel::Receiver<Transaction>,
+        async_channel::Sender<ShardMessage>,
+        async_channel::Receiver<ShardMessage>,
     )>,
     handles: Vec<thread::JoinHandle<()>>,
+    // first error which stopped a worker, its channel is closed so routing stops
+    failure: Arc<OnceLock<String>>,
+    // state was loaded by load_snapshot(), run() does not load the base of the log
+    snapshot_loaded: bool,
 }
 
 impl AccountShards {
     pub fn new(shards: usize) -> Self {
+        Self::with_config(shards, ShardsConfig::default())
+    }
+
+    pub fn with_config(shards: usize, config: ShardsConfig) -> Self {
         let mut new_shards = Self {
             shards,
+            config,
             account_services: Vec::with_capacity(shards),
             tx_services: Vec::with_capacity(shards),
+            tx_ids: Arc::new(TxIdSet::new()),
             channels: Vec::with_capacity(shards),
             handles: Vec::with_capacity(shards),
+            failure: Arc::default(),
+            snapshot_loaded: false,
         };
-        for _i in 0..shards {
+        // slots are shared, every shard uses the ones of the ids claimed for it
+        let slots = new_shards
+            .config
+            .dense_store
+            .then(|| Arc::new(DenseTransactions::new()));
+        // transactions kept on disk by an earlier run are put again by the replay of its log,
+        // without one they would not match the accounts
+        let replayed = new_shards.config.wal.as_ref().is_some_and(|wal| {
+            wal.has_records(shards)
+                .expect("Cannot read write-ahead log")
+        });
+        for i in 0..shards {
             new_shards
                 .account_services
                 .push(Arc::new(Mutex::new(AccountService::new())));
+            let ids = Arc::clone(&new_shards.tx_ids);
+            let tx_service: BoxedTransactionStore = match (&new_shards.config.disk_store, &slots) {
+                (Some(config), _) => {
+                    let mut store =
+                        DiskTransactionStore::open(&config.shard_path(i), config.cache, ids)
+                            .expect("Cannot open transaction store");
+                    if !replayed {
+                        store.clear().expect("Cannot clear transaction store");
+                    }
+                    Box::new(store)
+                }
+                (None, Some(slots)) => {
+                    Box::new(DenseTransactionStore::new(Arc::clone(slots), i as u16, ids))
+                }
+                (None, None) => Box::new(TransactionService::with_ids(ids)),
+            };
             new_shards
                 .tx_services
-                .push(Arc::new(Mutex::new(TransactionService::new())));
+                .push(Arc::new(Mutex::new(tx_service)));
             new_shards
                 .channels
                 .push(async_channel::bounded(CHANNEL_CAP));
@@ -48,43 +152,581 @@ impl AccountShards {
         new_shards
     }
 
-    pub fn run(&mut self) {
+    pub fn amount_format(&self) -> AmountFormat {
+        self.config.amount_format
+    }
+
+    // returns once the state of every shard is recovered from its log, the ids replayed
+    // are claimed before new transactions are routed; a shard which cannot be recovered
+    // (ie. its log cannot be read or replayed) is an error, join() before dropping
+    pub fn run(&mut self) -> io::Result<()> {
+        // the log is replayed on top of the snapshot it was started from
+        if let Some(base) = self.config.wal.as_ref().map(WalConfig::base_path) {
+            if !self.snapshot_loaded && base.exists() {
+                self.read_snapshot(&base)?;
+            }
+        }
+        let (recovered, recovery) = mpsc::channel();
         for i in 0..self.shards {
             let a_service = Arc::clone(&self.account_services[i]);
             let t_service = Arc::clone(&self.tx_services[i]);
             let receiver = self.channels[i].1.clone();
+            let wal_config = self.config.wal.clone();
+            let rejections = self.config.rejections.clone();
+            let audit = self.config.audit.clone();
+            let amount_format = self.config.amount_format;
+            let processor = self.config.processor;
+            let recovered = recovered.clone();
+            let failure = Arc::clone(&self.failure);
+            let wal_header = WalHeader {
+                shard: i as u32,
+                shards: self.shards as u32,
+                scale: self.config.amount_format.scale(),
+                policy: processor.policy.fingerprint(),
+            };
 
             self.handles.push(thread::spawn(move || {
-                let mut a_service = a_service.lock().unwrap();
-                let mut t_service = t_service.lock().unwrap();
-
-                while let Ok(tx) = future::block_on(receiver.recv()) {
-                    if let Err(err) =
-                        TransactionProcessor::process(&mut a_service, &mut t_service, tx)
-                    {
-                        eprintln!("Transaction {} failed: {}", tx.tx_id, err);
+                let closing = receiver.clone();
+                let worker = move || -> io::Result<()> {
+                    let mut a_service = a_service.lock().unwrap();
+                    let mut t_service = t_service.lock().unwrap();
+
+                    // rebuild state from the log before accepting new transactions
+                    let mut wal = match wal_config {
+                        Some(config) => {
+                            let path = config.shard_path(i);
+                            let wal = WriteAheadLog::open(&path, wal_header, config.sync, |tx| {
+                                // rejected transactions are rejected again, no need to report
+                                // them, but a store which cannot be written cannot be recovered
+                                match processor.process(&mut *a_service, &mut *t_service, tx) {
+                                    Err(AccountServiceError::StorageFailed(e)) => {
+                                        Err(io::Error::other(e))
+                                    }
+                                    _ => Ok(()),
+                                }
+                            })
+                            .map_err(|e| failed("cannot open write-ahead log", e))?;
+                            Some(wal)
+                        }
+                        None => None,
+                    };
+                    let _ = recovered.send(());
+                    drop(recovered);
+
+                    while let Ok(msg) = future::block_on(receiver.recv()) {
+                        let (row, duplicate, stats, ack) = match msg {
+                            ShardMessage::Transaction {
+                                row,
+                                duplicate,
+                                stats,
+                                ack,
+                            } => (row, duplicate, stats, ack),
+                            ShardMessage::Snapshot(parts) => {
+                                // waits while the writer is behind, so only a few parts are
+                                // buffered
+                                let encoded =
+                                    EncodedShard::encode(&*a_service, &*t_service, |part| {
+                                        future::block_on(parts.send(Ok(part))).is_ok()
+                                    });
+                                if let Err(e) = encoded {
+                                    // requester gone, nothing to do
+                                    let _ = future::block_on(parts.send(Err(e)));
+                                }
+                                continue;
+                            }
+                            ShardMessage::Accounts(reply) => {
+                                let accounts = a_service.accounts().cloned().collect();
+                                let _ = future::block_on(reply.send(accounts));
+                                continue;
+                            }
+                            ShardMessage::ClientAccounts(client_id, reply) => {
+                                let accounts = a_service
+                                    .accounts()
+                                    .filter(|a| a.client_id == client_id)
+                                    .cloned()
+                                    .collect();
+                                let _ = future::block_on(reply.send(accounts));
+                                continue;
+                            }
+                            ShardMessage::LockedAccounts(reply) => {
+                                let accounts = a_service
+                                    .accounts()
+                                    .filter(|a| a.is_locked())
+                                    .cloned()
+                                    .collect();
+                                let _ = future::block_on(reply.send(accounts));
+                                continue;
+                            }
+                            ShardMessage::TransactionState(tx_id, reply) => {
+                                let _ = future::block_on(reply.send(t_service.get(tx_id)));
+                                continue;
+                            }
+                        };
+                        let tx = row.tx;
+                        // duplicates are not logged, replay claims the ids of logged
+                        // transactions
+                        let outcome = if duplicate {
+                            Err(AccountServiceError::TransactionDuplicate)
+                        } else {
+                            if let Some(wal) = wal.as_mut() {
+                                wal.append(&tx)
+                                    .map_err(|e| failed("write-ahead log append failed", e))?;
+                            }
+                            processor.apply(&mut *a_service, &mut *t_service, tx)
+                        };
+                        if let Some(stats) = stats {
+                            stats.record(&outcome);
+                        }
+                        if let Some(ack) = ack {
+                            ack(&outcome);
+                        }
+                        let (reason, kind) = match outcome {
+                            Ok(Outcome::Applied) => continue,
+                            Ok(Outcome::Administered(change)) => {
+                                if let Some(sink) = &audit {
+                                    let entry = AuditEntry::new(&row, i, &change, amount_format);
+                                    future::block_on(sink.send(entry))
+                                        .map_err(|_| stopped("audit writer stopped"))?;
+                                }
+                                continue;
+                            }
+                            Ok(Outcome::Ignored(reason)) => (reason, RejectionKind::Ignored),
+                            Err(err) => (err, RejectionKind::Error),
+                        };
+                        match &rejections {
+                            Some(sink) => {
+                                let rejection =
+                                    Rejection::new(&tx, i, &reason, kind, amount_format);
+                                future::block_on(sink.send(rejection))
+                                    .map_err(|_| stopped("rejection writer stopped"))?;
+                            }
+                            None if kind == RejectionKind::Error => {
+                                eprintln!("Transaction {} failed: {}", tx.tx_id, reason);
+                            }
+                            None => {}
+                        }
+                    }
+
+                    if let Some(wal) = wal {
+                        wal.close()
+                            .map_err(|e| failed("write-ahead log sync failed", e))?;
                     }
+                    Ok(())
+                };
+                if let Err(e) = worker() {
+                    let _ = failure.set(format!("shard {}: {}", i, e));
+                    // nothing more is routed to the shard, queued transactions are dropped
+                    // with their acks
+                    closing.close();
+                    while closing.try_recv().is_ok() {}
                 }
             }));
         }
+        drop(recovered);
+        for _ in 0..self.shards {
+            if recovery.recv().is_err() {
+                return Err(self.failure());
+            }
+        }
+        Ok(())
     }
 
-    pub fn join(&mut self) {
+    // the error of the first worker which stopped, if any
+    pub fn join(&mut self) -> io::Result<()> {
         // close channels, the remaining messages can still be received
         for q in self.channels.iter() {
             q.0.close();
         }
 
         // wait for all threads to finish
+        let mut panicked = false;
         while let Some(handle) = self.handles.pop() {
-            handle.join().unwrap();
+            panicked |= handle.join().is_err();
+        }
+        match (self.failure.get(), panicked) {
+            (None, false) => Ok(()),
+            _ => Err(self.failure()),
+        }
+    }
+
+    fn failure(&self) -> io::Error {
+        match self.failure.get() {
+            Some(failure) => io::Error::other(failure.clone()),
+            None => io::Error::other("shard worker stopped"),
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        !self.handles.is_empty()
+    }
+
+    // while running the snapshot includes every transaction passed to process() so far
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        let mut writer = SnapshotWriter::create(path, self.config.amount_format.scale())?;
+        if self.is_running() {
+            // state is owned by the workers, ask them all to encode it, then write the parts
+            // of one shard after another
+            let shards = self
+                .channels
+                .iter()
+                .map(|(sender, _)| {
+                    let (parts, received) = async_channel::bounded(SNAPSHOT_PARTS);
+                    future::block_on(sender.send(ShardMessage::Snapshot(parts)))
+                        .map_err(|_| io::Error::other("shard is closed"))?;
+                    Ok(received)
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            for received in shards {
+                let mut last = false;
+                while let Ok(part) = future::block_on(received.recv()) {
+                    let part = part?;
+                    last = part.is_last();
+                    writer.write_encoded_shard(&part)?;
+                }
+                if !last {
+                    return Err(io::Error::other("shard worker stopped"));
+                }
+            }
+        } else {
+            for i in 0..self.shards {
+                let a_service = self.account_services[i].lock().unwrap();
+                let t_service = self.tx_services[i].lock().unwrap();
+                writer.write_shard(&*a_service, &*t_service)?;
+            }
         }
+        // read after all shards replied, ids claimed through a ShardsHandle in the meantime
+        // are included too
+        writer.write_tx_ids(&self.tx_ids)?;
+        writer.finish()
+    }
+
+    fn request<T>(
+        &self,
+        message: impl Fn(async_channel::Sender<T>) -> ShardMessage,
+    ) -> io::Result<Vec<T>> {
+        request(self.channels.iter().map(|(sender, _)| sender), message)
     }
 
-    pub fn process(&mut self, tx: Transaction) {
-        // because number of workers can change in the future would be better to use consistent hashing
-        let hash = (tx.client_id as usize) % self.shards;
-        future::block_on(self.channels[hash].0.send(tx)).unwrap();
+    // copy of the accounts, while running it includes every transaction passed to process() so far
+    pub fn copy_accounts(&self) -> io::Result<AccountsCopy> {
+        let shards = if self.is_running() {
+            self.request(ShardMessage::Accounts)?
+        } else {
+            self.account_services
+                .iter()
+                .map(|service| service.lock().unwrap().accounts().cloned().collect())
+                .collect()
+        };
+        Ok(AccountsCopy {
+            shards,
+            format: self.config.amount_format,
+        })
+    }
+
+    // snapshot can be taken with a different number of shards, records are routed by client,
+    // a write-ahead log with records would be replayed on top of it, so it is refused; into
+    // an empty log it becomes the base which later runs load before the replay
+    pub fn load_snapshot(&mut self, path: &Path) -> io::Result<()> {
+        if self.is_running() {
+            return Err(io::Error::other("snapshot can be loaded only before run()"));
+        }
+        if let Some(wal) = &self.config.wal {
+            if wal.has_records(self.shards)? {
+                return Err(io::Error::other(
+                    "snapshot cannot be loaded with a write-ahead log which has records",
+                ));
+            }
+            // a copy is kept with the logs, so later runs replay them on top of the same state
+            let base = wal.base_path();
+            let copy = base.with_extension("tmp");
+            std::fs::copy(path, &copy)?;
+            std::fs::File::open(&copy)?.sync_all()?;
+            self.read_snapshot(&copy)?;
+            std::fs::rename(&copy, &base)?;
+            self.snapshot_loaded = true;
+            return Ok(());
+        }
+        self.read_snapshot(path)?;
+        self.snapshot_loaded = true;
+        Ok(())
+    }
+
+    fn read_snapshot(&mut self, path: &Path) -> io::Result<()> {
+        let shards = self.shards;
+        let account_services = &self.account_services;
+        let tx_services = &self.tx_services;
+        let tx_ids = &self.tx_ids;
+        snapshot::read(
+            path,
+            self.config.amount_format.scale(),
+            |record| match record {
+                SnapshotRecord::Account(account) => {
+                    let hash = (account.client_id as usize) % shards;
+                    account_services[hash].lock().unwrap().insert(account);
+                    Ok(())
+                }
+                SnapshotRecord::Transaction(tx_state) => {
+                    let hash = (tx_state.tx.client_id as usize) % shards;
+                    tx_services[hash].lock().unwrap().insert(tx_state)
+                }
+                SnapshotRecord::TxIds(index, words) => {
+                    tx_ids.insert_page(index, &words);
+                    Ok(())
+                }
+            },
+        )
+    }
+
+    // locks account storage of every shard, use after join()
+    pub fn lock_accounts(&self) -> LockedAccounts<'_> {
+        LockedAccounts {
+            services: self
+                .account_services
+                .iter()
+                .map(|service| service.lock().unwrap())
+                .collect(),
+            format: self.config.amount_format,
+        }
+    }
+
+    // error when the worker of the shard stopped, the transaction is not processed
+    pub fn process(&mut self, tx: Transaction) -> io::Result<()> {
+        self.send(tx.into(), None)
+    }
+
+    // outcome of the transaction is added to `stats` by the worker
+    pub fn process_counted(
+        &mut self,
+        row: TransactionRow,
+        stats: &Arc<InputStats>,
+    ) -> io::Result<()> {
+        self.send(row, Some(Arc::clone(stats)))
+    }
+
+    // handle to pass transactions from other threads while running, until join()
+    pub fn handle(&self) -> ShardsHandle {
+        ShardsHandle {
+            senders: self
+                .channels
+                .iter()
+                .map(|(sender, _)| sender.clone())
+                .collect(),
+            tx_ids: Arc::clone(&self.tx_ids),
+        }
+    }
+
+    fn send(&mut self, row: TransactionRow, stats: Option<Arc<InputStats>>) -> io::Result<()> {
+        let hash = shard_of(row.tx.client_id, self.shards);
+        let duplicate = is_duplicate(&self.tx_ids, &row.tx);
+        let ack = None;
+        future::block_on(self.channels[hash].0.send(ShardMessage::Transaction {
+            row,
+            duplicate,
+            stats,
+            ack,
+        }))
+        .map_err(|_| self.failure())
+    }
+}
+
+// error of a worker whose sink no longer receives, the writer has the cause
+fn stopped(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, what)
+}
+
+// error of a worker with what it was doing
+fn failed(what: &str, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", what, e))
+}
+
+fn shard_of(client_id: ClientId, shards: usize) -> usize {
+    // because number of workers can change in the future would be better to use consistent hashing
+    (client_id as usize) % shards
+}
+
+// ids are claimed by the routing thread in input order, so the outcome does not depend
+// on which shard gets to a shared id first
+fn is_duplicate(tx_ids: &TxIdSet, tx: &Transaction) -> bool {
+    tx_processor::claims_id(tx) && !tx_ids.insert(tx.tx_id)
+}
+
+// sends a request to every worker and waits for all replies
+fn request<'a, T>(
+    senders: impl Iterator<Item = &'a async_channel::Sender<ShardMessage>>,
+    message: impl Fn(async_channel::Sender<T>) -> ShardMessage,
+) -> io::Result<Vec<T>> {
+    let replies: Vec<_> = senders
+        .map(|sender| {
+            let (reply, response) = async_channel::bounded(1);
+            future::block_on(sender.send(message(reply)))
+                .map_err(|_| io::Error::other("shard is closed"))?;
+            Ok(response)
+        })
+        .collect::<io::Result<_>>()?;
+    replies
+        .into_iter()
+        .map(|response| {
+            future::block_on(response.recv()).map_err(|_| io::Error::other("shard worker stopped"))
+        })
+        .collect()
+}
+
+// transactions of a client always go to the same shard channel, so they are processed
+// in the order they were passed to any of the handles
+#[derive(Clone)]
+pub struct ShardsHandle {
+    senders: Vec<async_channel::Sender<ShardMessage>>,
+    tx_ids: Arc<TxIdSet>,
+}
+
+impl ShardsHandle {
+    // `ack` is called by the worker with the outcome of the transaction
+    pub fn process_acked(
+        &self,
+        row: TransactionRow,
+        ack: impl FnOnce(&Result<Outcome, AccountServiceError>) + Send + 'static,
+    ) -> io::Result<()> {
+        let hash = shard_of(row.tx.client_id, self.senders.len());
+        let message = ShardMessage::Transaction {
+            duplicate: is_duplicate(&self.tx_ids, &row.tx),
+            row,
+            stats: None,
+            ack: Some(Box::new(ack)),
+        };
+        future::block_on(self.senders[hash].send(message))
+            .map_err(|_| io::Error::other("shard is closed"))
+    }
+
+    // accounts of the client in every asset, answered by the owning shard
+    pub fn client_accounts(&self, client_id: ClientId) -> io::Result<Vec<Account>> {
+        let hash = shard_of(client_id, self.senders.len());
+        let (reply, response) = async_channel::bounded(1);
+        future::block_on(self.senders[hash].send(ShardMessage::ClientAccounts(client_id, reply)))
+            .map_err(|_| io::Error::other("shard is closed"))?;
+        future::block_on(response.recv()).map_err(|_| io::Error::other("shard worker stopped"))
+    }
+
+    // ordered by client and asset
+    pub fn locked_accounts(&self) -> io::Result<Vec<Account>> {
+        let mut accounts: Vec<_> = request(self.senders.iter(), ShardMessage::LockedAccounts)?
+            .into_iter()
+            .flatten()
+            .collect();
+        accounts.sort_by(|a, b| AccountOrder::Client.compare(a, b));
+        Ok(accounts)
+    }
+
+    // transaction ids are not routed, so every shard is asked
+    pub fn transaction(&self, tx_id: TransactionId) -> io::Result<Option<TransactionWithState>> {
+        let found = request(self.senders.iter(), |reply| {
+            ShardMessage::TransactionState(tx_id, reply)
+        })?;
+        let found = found.into_iter().collect::<io::Result<Vec<_>>>()?;
+        Ok(found.into_iter().flatten().next())
+    }
+}
+
+pub struct LockedAccounts<'a> {
+    services: Vec<MutexGuard<'a, AccountService>>,
+    format: AmountFormat,
+}
+
+impl LockedAccounts<'_> {
+    // every shard is sorted on its own and shards are merged lazily,
+    // only references to accounts are held
+    pub fn sorted(&self, order: AccountOrder) -> SortedAccounts<'_> {
+        let shards = self
+            .services
+            .iter()
+            .map(|service| service.accounts().collect())
+            .collect();
+        SortedAccounts::merge(shards, order, self.format)
+    }
+}
+
+pub struct AccountsCopy {
+    shards: Vec<Vec<Account>>,
+    format: AmountFormat,
+}
+
+impl AccountsCopy {
+    pub fn sorted(&self, order: AccountOrder) -> SortedAccounts<'_> {
+        let shards = self.shards.iter().map(|s| s.iter().collect()).collect();
+        SortedAccounts::merge(shards, order, self.format)
+    }
+}
+
+// head of a shard in the merge, accounts of a client are never split between shards
+struct MergeEntry<'a> {
+    account: &'a Account,
+    shard: usize,
+    order: AccountOrder,
+}
+
+impl Ord for MergeEntry<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order.compare(self.account, other.account)
+    }
+}
+
+impl PartialOrd for MergeEntry<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MergeEntry<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeEntry<'_> {}
+
+pub struct SortedAccounts<'a> {
+    shards: Vec<std::vec::IntoIter<&'a Account>>,
+    heap: BinaryHeap<Reverse<MergeEntry<'a>>>,
+    format: AmountFormat,
+}
+
+impl<'a> SortedAccounts<'a> {
+    fn merge(shards: Vec<Vec<&'a Account>>, order: AccountOrder, format: AmountFormat) -> Self {
+        let mut shards: Vec<_> = shards
+            .into_iter()
+            .map(|mut accounts| {
+                accounts.sort_unstable_by(|a, b| order.compare(a, b));
+                accounts.into_iter()
+            })
+            .collect();
+        let heap = shards
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(shard, accounts)| {
+                let account = accounts.next()?;
+                Some(Reverse(MergeEntry {
+                    account,
+                    shard,
+                    order,
+                }))
+            })
+            .collect();
+        SortedAccounts {
+            shards,
+            heap,
+            format,
+        }
+    }
+}
+
+impl Iterator for SortedAccounts<'_> {
+    type Item = AccountResult;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse(entry) = self.heap.pop()?;
+        if let Some(account) = self.shards[entry.shard].next() {
+            self.heap.push(Reverse(MergeEntry { account, ..entry }));
+        }
+        Some(AccountResult::new(entry.account, self.format))
     }
 }
 
@@ -93,6 +735,8 @@ mod tests {
 
     use super::*;
     use crate::tx::*;
+    use crate::tx_service::TransactionState;
+    use crate::wal::SyncPolicy;
     use rand::Rng;
 
     #[test]
@@ -100,7 +744,7 @@ mod tests {
         let mut shards = AccountShards::new(16);
         assert_eq!(shards.shards, 16);
         assert_eq!(shards.account_services.len(), 16);
-        shards.run();
+        shards.run().unwrap();
 
         let mut rng = rand::thread_rng();
 
@@ -109,19 +753,21 @@ mod tests {
                 tx_id: i,
                 tx_type: TransactionType::Deposit,
                 client_id: i as u16,
-                amount: Some(1000 * rng.gen::<u32>() as AmountDecimal),
+                amount: Some(Amount::from_units(1000 * rng.gen::<u32>() as u64)),
+                asset: Asset::default(),
             };
-            shards.process(tx);
+            shards.process(tx).unwrap();
         }
-        
+
         for i in 10_000..20_000 {
             let tx = Transaction {
                 tx_id: i,
                 tx_type: TransactionType::Withdrawal,
                 client_id: (i - 10_000) as u16,
-                amount: Some((rng.gen::<u16>() % 1000) as AmountDecimal),
+                amount: Some(Amount::from_units((rng.gen::<u16>() % 1000) as u64)),
+                asset: Asset::default(),
             };
-            shards.process(tx);
+            shards.process(tx).unwrap();
         }
 
         for i in 20_000..30_000 {
@@ -129,9 +775,10 @@ mod tests {
                 tx_id: i,
                 tx_type: TransactionType::Deposit,
                 client_id: i as u16 - 20_000,
-                amount: Some(100 * rng.gen::<u32>() as AmountDecimal),
+                amount: Some(Amount::from_units(100 * rng.gen::<u32>() as u64)),
+                asset: Asset::default(),
             };
-            shards.process(tx);
+            shards.process(tx).unwrap();
         }
 
         for i in 0..10_000 {
@@ -140,8 +787,9 @@ mod tests {
                 tx_type: TransactionType::Dispute,
                 client_id: i as u16,
                 amount: None,
+                asset: Asset::default(),
             };
-            shards.process(tx);
+            shards.process(tx).unwrap();
         }
 
         for i in 0..5_000 {
@@ -150,8 +798,9 @@ mod tests {
                 tx_type: TransactionType::Chargeback,
                 client_id: i as u16,
                 amount: None,
+                asset: Asset::default(),
             };
-            shards.process(tx);
+            shards.process(tx).unwrap();
         }
 
         for i in 5_000..10_000 {
@@ -160,10 +809,666 @@ mod tests {
                 tx_type: TransactionType::Resolve,
                 client_id: i as u16,
                 amount: None,
+                asset: Asset::default(),
+            };
+            shards.process(tx).unwrap();
+        }
+
+        shards.join().unwrap();
+    }
+
+    fn balances(shards: &AccountShards) -> Vec<(ClientId, Amount, Amount)> {
+        shards
+            .lock_accounts()
+            .sorted(AccountOrder::Client)
+            .map(|a| (a.client, a.available.amount(), a.held.amount()))
+            .collect()
+    }
+
+    #[test]
+    fn recover_state_from_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ShardsConfig {
+            wal: Some(WalConfig {
+                dir: dir.path().to_path_buf(),
+                sync: SyncPolicy::EveryRecords(10),
+            }),
+            ..ShardsConfig::default()
+        };
+        let tx = |tx_type, tx_id: TransactionId, amount: Option<u64>| Transaction {
+            tx_type,
+            client_id: (tx_id % 7) as ClientId,
+            tx_id,
+            amount: amount.map(Amount::from_units),
+            asset: Asset::default(),
+        };
+
+        let mut shards = AccountShards::with_config(4, config.clone());
+        shards.run().unwrap();
+        for i in 0..100 {
+            shards
+                .process(tx(TransactionType::Deposit, i, Some(1000)))
+                .unwrap();
+        }
+        for i in 0..10 {
+            shards
+                .process(tx(TransactionType::Dispute, i, None))
+                .unwrap();
+        }
+        shards.join().unwrap();
+        let expected = balances(&shards);
+
+        let mut recovered = AccountShards::with_config(4, config.clone());
+        recovered.run().unwrap();
+        recovered.join().unwrap();
+        assert_eq!(balances(&recovered), expected);
+
+        // recovered shards keep appending to the same log
+        let mut recovered = AccountShards::with_config(4, config.clone());
+        recovered.run().unwrap();
+        recovered
+            .process(tx(TransactionType::Resolve, 0, None))
+            .unwrap();
+        recovered.join().unwrap();
+        assert_ne!(balances(&recovered), expected);
+
+        // the log would be replayed on top of a snapshot
+        let path = dir.path().join("state.snap");
+        shards.save_snapshot(&path).unwrap();
+        let mut restored = AccountShards::with_config(4, config);
+        assert!(restored.load_snapshot(&path).is_err());
+        let empty = tempfile::tempdir().unwrap();
+        let config = ShardsConfig {
+            wal: Some(WalConfig {
+                dir: empty.path().to_path_buf(),
+                sync: SyncPolicy::EveryRecords(10),
+            }),
+            ..ShardsConfig::default()
+        };
+        let mut restored = AccountShards::with_config(4, config);
+        restored.load_snapshot(&path).unwrap();
+        restored.run().unwrap();
+        restored.join().unwrap();
+        assert_eq!(balances(&restored), expected);
+    }
+
+    #[test]
+    fn wal_continues_from_loaded_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.snap");
+        let wal = dir.path().join("wal");
+        std::fs::create_dir(&wal).unwrap();
+        let config = ShardsConfig {
+            wal: Some(WalConfig {
+                dir: wal,
+                sync: SyncPolicy::OnJoin,
+            }),
+            ..ShardsConfig::default()
+        };
+        let deposit = |client_id, tx_id, units| Transaction {
+            tx_type: TransactionType::Deposit,
+            client_id,
+            tx_id,
+            amount: Some(Amount::from_units(units)),
+            asset: Asset::default(),
+        };
+
+        let mut shards = AccountShards::new(2);
+        shards.run().unwrap();
+        shards.process(deposit(1, 1, 50000)).unwrap();
+        shards.join().unwrap();
+        shards.save_snapshot(&path).unwrap();
+
+        // every restart replays the log on top of the snapshot it started from
+        let mut expected = 50000;
+        for (tx_id, units) in [(2, 10000), (3, 10000), (4, 0)] {
+            let mut shards = AccountShards::with_config(2, config.clone());
+            if tx_id == 2 {
+                shards.load_snapshot(&path).unwrap();
+            }
+            shards.run().unwrap();
+            if units > 0 {
+                shards.process(deposit(1, tx_id, units)).unwrap();
+                // ids of the snapshot stay claimed
+                shards.process(deposit(1, 1, units)).unwrap();
+            }
+            shards.join().unwrap();
+            expected += units;
+            assert_eq!(
+                balances(&shards),
+                vec![(1, Amount::from_units(expected), Amount::from_units(0))]
+            );
+        }
+
+        // the base cannot be replaced under the records
+        let mut shards = AccountShards::with_config(2, config);
+        assert!(shards.load_snapshot(&path).is_err());
+    }
+
+    #[test]
+    fn wal_errors_stop_the_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = |dir: &Path, sync| ShardsConfig {
+            wal: Some(WalConfig {
+                dir: dir.to_path_buf(),
+                sync,
+            }),
+            ..ShardsConfig::default()
+        };
+        let deposit = |tx_id| Transaction {
+            tx_type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id,
+            amount: Some(Amount::from_units(1000)),
+            asset: Asset::default(),
+        };
+
+        // more transactions than the channel holds, routing would block on a stopped worker
+        let mut shards =
+            AccountShards::with_config(1, config(dir.path(), SyncPolicy::FailAfter(5)));
+        shards.run().unwrap();
+        let sent = (0..1000)
+            .map(|i| shards.process(deposit(i)))
+            .position(|r| r.is_err());
+        assert!(sent.is_some());
+        let err = shards.join().unwrap_err();
+        assert!(err.to_string().contains("write-ahead log append failed"));
+
+        // log which cannot be opened
+        let missing = dir.path().join("missing");
+        let mut shards = AccountShards::with_config(2, config(&missing, SyncPolicy::OnJoin));
+        assert!(shards.run().is_err());
+        assert!(shards.join().is_err());
+    }
+
+    #[test]
+    fn disk_store_is_kept_with_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        for wal in ["wal", "empty"] {
+            std::fs::create_dir(dir.path().join(wal)).unwrap();
+        }
+        let config = |wal: &str| ShardsConfig {
+            wal: Some(WalConfig {
+                dir: dir.path().join(wal),
+                sync: SyncPolicy::EveryRecords(10),
+            }),
+            disk_store: Some(DiskStoreConfig {
+                dir: dir.path().join("store"),
+                cache: 16,
+            }),
+            ..ShardsConfig::default()
+        };
+        let tx = |tx_type, tx_id: TransactionId| Transaction {
+            tx_type,
+            client_id: (tx_id % 7) as ClientId,
+            tx_id,
+            amount: match tx_type {
+                TransactionType::Deposit => Some(Amount::from_units(1000)),
+                _ => None,
+            },
+            asset: Asset::default(),
+        };
+        let stored = |shards: &AccountShards| -> usize {
+            let count =
+                |store: &Arc<Mutex<BoxedTransactionStore>>| store.lock().unwrap().iter().count();
+            shards.tx_services.iter().map(count).sum()
+        };
+
+        let mut shards = AccountShards::with_config(2, config("wal"));
+        shards.run().unwrap();
+        for i in 0..300 {
+            shards.process(tx(TransactionType::Deposit, i)).unwrap();
+        }
+        for i in 0..30 {
+            shards.process(tx(TransactionType::Dispute, i)).unwrap();
+        }
+        shards.join().unwrap();
+        let expected = balances(&shards);
+        drop(shards);
+
+        // runs of the first run are replayed over
+        let mut recovered = AccountShards::with_config(2, config("wal"));
+        recovered.run().unwrap();
+        recovered
+            .process(tx(TransactionType::Chargeback, 5))
+            .unwrap();
+        recovered.join().unwrap();
+        assert_eq!(300, stored(&recovered));
+        let charged: Vec<_> = expected
+            .iter()
+            .map(|&(client, available, held)| match client {
+                5 => (client, available, held - Amount::from_units(1000)),
+                _ => (client, available, held),
+            })
+            .collect();
+        assert_eq!(balances(&recovered), charged);
+        let locked: Vec<_> = recovered
+            .lock_accounts()
+            .sorted(AccountOrder::Client)
+            .filter(|a| a.locked)
+            .map(|a| a.client)
+            .collect();
+        assert_eq!(locked, vec![5]);
+        for tx_id in [0, 5, 29, 30, 299] {
+            let store = &recovered.tx_services[tx_id as usize % 7 % 2];
+            let state = store.lock().unwrap().get(tx_id).unwrap().unwrap().state;
+            let expected = match tx_id {
+                5 => TransactionState::Refunded,
+                0..=29 => TransactionState::Disputed,
+                _ => TransactionState::Valid,
+            };
+            assert_eq!(state, expected, "transaction {}", tx_id);
+        }
+        drop(recovered);
+
+        // without records in the log the accounts start empty, so do the transactions
+        let mut fresh = AccountShards::with_config(2, config("empty"));
+        fresh.run().unwrap();
+        fresh.join().unwrap();
+        assert_eq!(0, stored(&fresh));
+    }
+
+    #[test]
+    fn save_snapshot_while_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.snap");
+        let tx = |tx_type, tx_id: TransactionId| Transaction {
+            tx_type,
+            client_id: (tx_id % 13) as ClientId,
+            tx_id,
+            amount: match tx_type {
+                TransactionType::Deposit => Some(Amount::from_units(1000)),
+                _ => None,
+            },
+            asset: Asset::default(),
+        };
+
+        // several parts per shard
+        let mut shards = AccountShards::new(2);
+        shards.run().unwrap();
+        for i in 0..10_000 {
+            shards.process(tx(TransactionType::Deposit, i)).unwrap();
+        }
+        for i in (0..10_000).step_by(9) {
+            shards.process(tx(TransactionType::Dispute, i)).unwrap();
+        }
+        shards.save_snapshot(&path).unwrap();
+        shards.join().unwrap();
+
+        let mut restored = AccountShards::new(3);
+        restored.load_snapshot(&path).unwrap();
+        assert_eq!(balances(&restored), balances(&shards));
+        let stored: usize = restored
+            .tx_services
+            .iter()
+            .map(|store| store.lock().unwrap().iter().count())
+            .sum();
+        assert_eq!(10_000, stored);
+    }
+
+    #[test]
+    fn restore_snapshot_into_different_shard_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.snap");
+        let tx = |tx_type, tx_id: TransactionId, client_id: ClientId| Transaction {
+            tx_type,
+            client_id,
+            tx_id,
+            amount: match tx_type {
+                TransactionType::Deposit => Some(Amount::from_units(1000)),
+                _ => None,
+            },
+            asset: Asset::default(),
+        };
+
+        let mut shards = AccountShards::new(3);
+        shards.run().unwrap();
+        for i in 0..50 {
+            shards
+                .process(tx(TransactionType::Deposit, i, (i % 11) as ClientId))
+                .unwrap();
+        }
+        shards.process(tx(TransactionType::Dispute, 4, 4)).unwrap();
+        shards.join().unwrap();
+        assert!(shards.save_snapshot(&path).is_ok());
+
+        let mut restored = AccountShards::new(5);
+        restored.load_snapshot(&path).unwrap();
+        assert_eq!(balances(&restored), balances(&shards));
+
+        // transaction history and state is restored as well
+        restored.run().unwrap();
+        restored
+            .process(tx(TransactionType::Resolve, 4, 4))
+            .unwrap();
+        restored
+            .process(tx(TransactionType::Deposit, 7, 7))
+            .unwrap(); // duplicate
+        restored
+            .process(tx(TransactionType::Deposit, 100, 7))
+            .unwrap();
+        restored.join().unwrap();
+        let client_4 = balances(&restored).into_iter().find(|b| b.0 == 4).unwrap();
+        assert_eq!(client_4.2, Amount::ZERO);
+        assert_eq!(balances(&restored).len(), 11);
+        let client_7 = balances(&restored).into_iter().find(|b| b.0 == 7).unwrap();
+        assert_eq!(client_7.1, Amount::from_units(5000));
+    }
+
+    #[test]
+    fn transactions_on_disk_or_dense() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.snap");
+        let tx = |tx_type, tx_id: TransactionId| Transaction {
+            tx_type,
+            client_id: (tx_id % 5) as ClientId,
+            tx_id,
+            amount: match tx_type {
+                TransactionType::Deposit => Some(Amount::from_units(1000 + tx_id as u64)),
+                _ => None,
+            },
+            asset: Asset::default(),
+        };
+        let run = |config: ShardsConfig| {
+            let mut shards = AccountShards::with_config(3, config);
+            shards.run().unwrap();
+            for i in 0..2_000 {
+                shards.process(tx(TransactionType::Deposit, i)).unwrap();
+            }
+            // disputes of transactions which are only in runs by now
+            for i in (0..2_000).step_by(7) {
+                shards.process(tx(TransactionType::Dispute, i)).unwrap();
+            }
+            for i in (0..2_000).step_by(14) {
+                shards.process(tx(TransactionType::Chargeback, i)).unwrap();
+            }
+            shards.join().unwrap();
+            shards
+        };
+
+        let expected = balances(&run(ShardsConfig::default()));
+        let on_disk = run(ShardsConfig {
+            disk_store: Some(DiskStoreConfig {
+                dir: dir.path().join("store"),
+                cache: 64,
+            }),
+            ..ShardsConfig::default()
+        });
+        assert_eq!(balances(&on_disk), expected);
+        let dense = run(ShardsConfig {
+            dense_store: true,
+            ..ShardsConfig::default()
+        });
+        assert_eq!(balances(&dense), expected);
+
+        on_disk.save_snapshot(&path).unwrap();
+        let mut restored = AccountShards::new(2);
+        restored.load_snapshot(&path).unwrap();
+        assert_eq!(
+            restored.tx_services[0].lock().unwrap().iter().count(),
+            1_200
+        );
+    }
+
+    #[test]
+    fn report_rejections() {
+        let (sender, receiver) = async_channel::unbounded();
+        let config = ShardsConfig {
+            rejections: Some(sender),
+            ..ShardsConfig::default()
+        };
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client_id: 3,
+            tx_id: 1,
+            amount: Some(Amount::from_units(1000)),
+            asset: Asset::default(),
+        };
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            amount: None,
+            ..deposit
+        };
+
+        let mut shards = AccountShards::with_config(2, config);
+        shards.run().unwrap();
+        shards.process(deposit).unwrap();
+        shards.process(deposit).unwrap();
+        shards.process(dispute).unwrap();
+        shards.process(dispute).unwrap();
+        shards.join().unwrap();
+
+        let reported: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|r| (r.tx_type, r.shard, r.reason, r.kind))
+            .collect();
+        assert_eq!(
+            reported,
+            vec![
+                (
+                    TransactionType::Deposit,
+                    1,
+                    "transaction_duplicate",
+                    RejectionKind::Error
+                ),
+                (
+                    TransactionType::Dispute,
+                    1,
+                    "already_disputed",
+                    RejectionKind::Ignored
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn stopped_rejection_writer_stops_the_shards() {
+        let (sender, receiver) = async_channel::bounded(1);
+        drop(receiver);
+        let config = ShardsConfig {
+            rejections: Some(sender),
+            ..ShardsConfig::default()
+        };
+        let withdrawal = |tx_id| Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client_id: 1,
+            tx_id,
+            amount: Some(Amount::from_units(1000)),
+            asset: Asset::default(),
+        };
+
+        // more rejections than the channel holds, routing would block on a stopped worker
+        let mut shards = AccountShards::with_config(1, config);
+        shards.run().unwrap();
+        let sent = (0..1000)
+            .map(|i| shards.process(withdrawal(i)))
+            .position(|r| r.is_err());
+        assert!(sent.is_some());
+        let err = shards.join().unwrap_err();
+        assert!(err.to_string().contains("rejection writer stopped"));
+    }
+
+    #[test]
+    fn stopped_audit_writer_stops_the_shards() {
+        let (sender, receiver) = async_channel::bounded(1);
+        drop(receiver);
+        let config = ShardsConfig {
+            audit: Some(sender),
+            ..ShardsConfig::default()
+        };
+        let tx = |tx_type, tx_id| Transaction {
+            tx_type,
+            client_id: 1,
+            tx_id,
+            amount: (tx_type == TransactionType::Deposit).then(|| Amount::from_units(1000)),
+            asset: Asset::default(),
+        };
+
+        let mut shards = AccountShards::with_config(1, config);
+        shards.run().unwrap();
+        shards.process(tx(TransactionType::Deposit, 0)).unwrap();
+        let sent = (1..1000)
+            .map(|i| match i % 2 {
+                0 => shards.process(tx(TransactionType::Unlock, i)),
+                _ => shards.process(tx(TransactionType::Freeze, i)),
+            })
+            .position(|r| r.is_err());
+        assert!(sent.is_some());
+        let err = shards.join().unwrap_err();
+        assert!(err.to_string().contains("audit writer stopped"));
+    }
+
+    #[test]
+    fn reject_duplicate_ids_across_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.snap");
+        let (sender, receiver) = async_channel::unbounded();
+        let config = ShardsConfig {
+            rejections: Some(sender),
+            ..ShardsConfig::default()
+        };
+        let withdrawal = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client_id: 1,
+            tx_id: 5,
+            amount: Some(Amount::from_units(1000)),
+            asset: Asset::default(),
+        };
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            tx_id: 7,
+            ..withdrawal
+        };
+
+        // client 1 and 2 are in different shards
+        let mut shards = AccountShards::with_config(2, config.clone());
+        shards.run().unwrap();
+        shards.process(deposit).unwrap();
+        shards.process(withdrawal).unwrap();
+        shards
+            .process(Transaction {
+                client_id: 2,
+                ..deposit
+            })
+            .unwrap();
+        shards.join().unwrap();
+        shards.save_snapshot(&path).unwrap();
+
+        // withdrawal ids are kept by the snapshot
+        let mut shards = AccountShards::with_config(3, config);
+        shards.load_snapshot(&path).unwrap();
+        shards.run().unwrap();
+        shards
+            .process(Transaction {
+                client_id: 2,
+                tx_type: TransactionType::Deposit,
+                ..withdrawal
+            })
+            .unwrap();
+        shards.join().unwrap();
+
+        let reported: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|r| (r.client, r.tx, r.reason))
+            .collect();
+        assert_eq!(
+            reported,
+            vec![
+                (2, 7, "transaction_duplicate"),
+                (2, 5, "transaction_duplicate")
+            ]
+        );
+    }
+
+    #[test]
+    fn first_of_duplicate_ids_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ShardsConfig {
+            wal: Some(WalConfig {
+                dir: dir.path().to_path_buf(),
+                sync: SyncPolicy::OnJoin,
+            }),
+            ..ShardsConfig::default()
+        };
+        // client 1 and 2 are in different shards and use the same ids in turns, withdrawals
+        // fail and keep their id too
+        let tx = |tx_type, client_id: ClientId, tx_id| Transaction {
+            tx_type,
+            client_id,
+            tx_id,
+            amount: match tx_type {
+                TransactionType::Deposit => Some(Amount::from_units(1000)),
+                _ => Some(Amount::from_units(u64::MAX)),
+            },
+            asset: Asset::default(),
+        };
+        let mut rows = Vec::new();
+        for tx_id in 0..2000 {
+            let (first, second) = if tx_id % 2 == 0 { (1, 2) } else { (2, 1) };
+            let tx_type = match tx_id % 5 {
+                0 => TransactionType::Withdrawal,
+                _ => TransactionType::Deposit,
             };
-            shards.process(tx);
+            rows.push(tx(tx_type, first, tx_id));
+            rows.push(tx(TransactionType::Deposit, second, tx_id));
         }
+        rows.push(tx(TransactionType::Withdrawal, 1, 5_000));
+        rows.push(tx(TransactionType::Deposit, 2, 5_000));
+        for round in 0..3 {
+            // later rounds are recovered from the log
+            let mut shards = AccountShards::with_config(2, config.clone());
+            shards.run().unwrap();
+            if round == 0 {
+                for tx in &rows {
+                    shards.process(*tx).unwrap();
+                }
+            }
+            shards.join().unwrap();
+            let (available, _): (Vec<_>, Vec<_>) =
+                balances(&shards).into_iter().map(|b| (b.1, b.2)).unzip();
+            // 800 deposits each, the second rows are duplicates
+            assert_eq!(
+                available,
+                vec![
+                    Amount::from_units(800 * 1000),
+                    Amount::from_units(800 * 1000)
+                ],
+                "round {}",
+                round
+            );
+        }
+    }
+
+    #[test]
+    fn merge_sorted_shards() {
+        let mut shards = AccountShards::new(4);
+        shards.run().unwrap();
+        for i in 0..200u32 {
+            shards
+                .process(Transaction {
+                    tx_type: TransactionType::Deposit,
+                    client_id: (i * 37 % 101) as ClientId,
+                    tx_id: i,
+                    amount: Some(Amount::from_units(i as u64 % 13)),
+                    asset: Asset::default(),
+                })
+                .unwrap();
+        }
+        let running = shards.copy_accounts().unwrap();
+        shards.join().unwrap();
+        assert_eq!(running.sorted(AccountOrder::Client).count(), 101);
+
+        let accounts = shards.lock_accounts();
+        let clients: Vec<_> = accounts
+            .sorted(AccountOrder::Client)
+            .map(|a| a.client)
+            .collect();
+        assert_eq!(clients, (0..101).collect::<Vec<_>>());
 
-        shards.join();
+        let totals: Vec<_> = accounts
+            .sorted(AccountOrder::Total)
+            .map(|a| (a.total.amount(), a.client))
+            .collect();
+        let mut expected = totals.clone();
+        expected.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        assert_eq!(totals, expected);
     }
 }
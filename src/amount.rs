@@ -0,0 +1,372 @@
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use strum_macros::EnumString;
+
+// number of decimal places kept for every amount unless configured otherwise
+pub const DEFAULT_AMOUNT_SCALE: u32 = 3;
+// 10^19 would not fit into u64
+pub const MAX_AMOUNT_SCALE: u32 = 18;
+
+// fixed-point amount, stored as value * 10^scale (scale is set by AmountFormat)
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+#[derive(Debug, PartialEq)]
+pub enum AmountError {
+    Empty,
+    Negative,
+    InvalidDigit(char),
+    TooManyFractionDigits(usize),
+    Overflow,
+    ScaleTooLarge(u32),
+}
+
+impl std::error::Error for AmountError {}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmountError::Empty => write!(f, "amount has no digits"),
+            AmountError::Negative => write!(f, "amount cannot be negative"),
+            AmountError::InvalidDigit(c) => write!(f, "invalid character '{}' in amount", c),
+            AmountError::TooManyFractionDigits(n) => write!(
+                f,
+                "amount has {} fraction digits, more than the configured scale",
+                n
+            ),
+            AmountError::Overflow => write!(f, "amount is too large"),
+            AmountError::ScaleTooLarge(scale) => write!(
+                f,
+                "amount scale {} is too large, at most {} allowed",
+                scale, MAX_AMOUNT_SCALE
+            ),
+        }
+    }
+}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    // amount from the smallest units, ie. 1500 is 1.500 with scale 3
+    pub const fn from_units(units: u64) -> Self {
+        Amount(units)
+    }
+
+    pub const fn units(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+}
+
+// what to do with input digits beyond the configured scale
+#[derive(EnumString, Debug, Copy, Clone, PartialEq)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Rounding {
+    Reject,
+    Truncate,
+    HalfEven,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AmountFormat {
+    scale: u32,
+    rounding: Rounding,
+}
+
+impl Default for AmountFormat {
+    fn default() -> Self {
+        Self {
+            scale: DEFAULT_AMOUNT_SCALE,
+            rounding: Rounding::Reject,
+        }
+    }
+}
+
+impl AmountFormat {
+    pub fn new(scale: u32, rounding: Rounding) -> Result<Self, AmountError> {
+        if scale > MAX_AMOUNT_SCALE {
+            return Err(AmountError::ScaleTooLarge(scale));
+        }
+        Ok(Self { scale, rounding })
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    pub fn rounding(&self) -> Rounding {
+        self.rounding
+    }
+
+    // parse digit by digit, never going through floating point
+    pub fn parse(&self, s: &str) -> Result<Amount, AmountError> {
+        let s = s.trim();
+        let s = match s.strip_prefix('-') {
+            Some(_) => return Err(AmountError::Negative),
+            None => s.strip_prefix('+').unwrap_or(s),
+        };
+        let (int_part, frac_part) = match s.find('.') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => (s, ""),
+        };
+        if int_part.is_empty() && frac_part.is_empty() {
+            return Err(AmountError::Empty);
+        }
+
+        let mut units: u64 = 0;
+        for c in int_part.chars() {
+            units = push_digit(units, c)?;
+        }
+
+        // split below counts bytes, so only ascii digits may get there
+        if let Some(c) = frac_part.chars().find(|c| !c.is_ascii_digit()) {
+            return Err(AmountError::InvalidDigit(c));
+        }
+        // trailing zeros beyond the scale carry no value
+        let frac_part = frac_part.trim_end_matches('0');
+        let scale = self.scale as usize;
+        let (kept, excess) = if frac_part.len() > scale {
+            frac_part.split_at(scale)
+        } else {
+            (frac_part, "")
+        };
+        for c in kept.chars() {
+            units = push_digit(units, c)?;
+        }
+        for _ in kept.len()..scale {
+            units = units.checked_mul(10).ok_or(AmountError::Overflow)?;
+        }
+
+        if !excess.is_empty() && self.round_up(units, excess)? {
+            units = units.checked_add(1).ok_or(AmountError::Overflow)?;
+        }
+        Ok(Amount(units))
+    }
+
+    // excess holds only digits and never ends with '0'
+    fn round_up(&self, units: u64, excess: &str) -> Result<bool, AmountError> {
+        match self.rounding {
+            Rounding::Reject => Err(AmountError::TooManyFractionDigits(
+                self.scale as usize + excess.len(),
+            )),
+            Rounding::Truncate => Ok(false),
+            Rounding::HalfEven => {
+                let first = excess.as_bytes()[0];
+                Ok(match first {
+                    b'6'..=b'9' => true,
+                    b'5' if excess.len() > 1 => true,
+                    b'5' => units % 2 == 1,
+                    _ => false,
+                })
+            }
+        }
+    }
+
+    pub fn display(&self, amount: Amount) -> FormattedAmount {
+        FormattedAmount {
+            amount,
+            scale: self.scale,
+            negative: false,
+        }
+    }
+
+    // `plus - minus`, printed with a sign when negative (ie. available with a receivable)
+    pub fn display_difference(&self, plus: Amount, minus: Amount) -> FormattedAmount {
+        match plus.checked_sub(minus) {
+            Some(amount) => self.display(amount),
+            None => FormattedAmount {
+                amount: minus - plus,
+                scale: self.scale,
+                negative: true,
+            },
+        }
+    }
+}
+
+fn push_digit(units: u64, c: char) -> Result<u64, AmountError> {
+    let digit = c.to_digit(10).ok_or(AmountError::InvalidDigit(c))?;
+    units
+        .checked_mul(10)
+        .and_then(|v| v.checked_add(digit as u64))
+        .ok_or(AmountError::Overflow)
+}
+
+// amount paired with its scale, printed with exactly `scale` fraction digits
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FormattedAmount {
+    amount: Amount,
+    scale: u32,
+    negative: bool,
+}
+
+impl FormattedAmount {
+    pub fn amount(&self) -> Amount {
+        self.amount
+    }
+}
+
+impl fmt::Display for FormattedAmount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = self.amount.units();
+        if self.negative {
+            f.write_str("-")?;
+        }
+        if self.scale == 0 {
+            return write!(f, "{}", units);
+        }
+        let base = 10u64.pow(self.scale);
+        write!(
+            f,
+            "{}.{:0width$}",
+            units / base,
+            units % base,
+            width = self.scale as usize
+        )
+    }
+}
+
+impl Serialize for FormattedAmount {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, other: Amount) -> Amount {
+        Amount(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, other: Amount) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, other: Amount) -> Amount {
+        Amount(self.0 - other.0)
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, other: Amount) {
+        self.0 -= other.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn format(scale: u32, rounding: Rounding) -> AmountFormat {
+        AmountFormat::new(scale, rounding).unwrap()
+    }
+
+    #[test]
+    fn parse_exact() {
+        let f = AmountFormat::default();
+        assert_eq!(f.parse("2.999"), Ok(Amount::from_units(2999)));
+        assert_eq!(f.parse("1"), Ok(Amount::from_units(1000)));
+        assert_eq!(f.parse(".5"), Ok(Amount::from_units(500)));
+        assert_eq!(f.parse("3.1000"), Ok(Amount::from_units(3100)));
+        assert_eq!(
+            f.parse("18446744073709551.615"),
+            Ok(Amount::from_units(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let f = AmountFormat::default();
+        assert_eq!(f.parse(""), Err(AmountError::Empty));
+        assert_eq!(f.parse("."), Err(AmountError::Empty));
+        assert_eq!(f.parse("-2.0"), Err(AmountError::Negative));
+        assert_eq!(f.parse("1e3"), Err(AmountError::InvalidDigit('e')));
+        assert_eq!(
+            f.parse("2.9999"),
+            Err(AmountError::TooManyFractionDigits(4))
+        );
+        assert_eq!(f.parse("18446744073709551.616"), Err(AmountError::Overflow));
+        assert_eq!(
+            format(1, Rounding::Reject).parse("1.é"),
+            Err(AmountError::InvalidDigit('é'))
+        );
+        assert_eq!(
+            AmountFormat::new(19, Rounding::Reject),
+            Err(AmountError::ScaleTooLarge(19))
+        );
+    }
+
+    #[test]
+    fn format_roundtrip() {
+        let f = AmountFormat::default();
+        for s in &["0.000", "1.500", "2.999", "18446744073709551.615"] {
+            assert_eq!(f.display(f.parse(s).unwrap()).to_string(), *s);
+        }
+        let f = format(0, Rounding::Reject);
+        assert_eq!(f.display(f.parse("42").unwrap()).to_string(), "42");
+    }
+
+    #[test]
+    fn display_difference() {
+        let f = AmountFormat::default();
+        let (five, seven) = (Amount::from_units(5_000), Amount::from_units(7_250));
+        assert_eq!(f.display_difference(seven, five).to_string(), "2.250");
+        assert_eq!(f.display_difference(five, seven).to_string(), "-2.250");
+        assert_eq!(f.display_difference(five, five).to_string(), "0.000");
+    }
+
+    #[test]
+    fn configurable_scale() {
+        let f = format(4, Rounding::Reject);
+        assert_eq!(f.parse("2.9999"), Ok(Amount::from_units(29_999)));
+        assert_eq!(f.display(Amount::from_units(15_000)).to_string(), "1.5000");
+        assert_eq!(
+            f.parse("0.00001"),
+            Err(AmountError::TooManyFractionDigits(5))
+        );
+    }
+
+    #[test]
+    fn truncate_excess_digits() {
+        let f = format(2, Rounding::Truncate);
+        assert_eq!(f.parse("1.239"), Ok(Amount::from_units(123)));
+        assert_eq!(f.parse("0.005"), Ok(Amount::ZERO));
+        assert_eq!(f.parse("1.23x"), Err(AmountError::InvalidDigit('x')));
+        assert_eq!(f.parse("1.é"), Err(AmountError::InvalidDigit('é')));
+        assert_eq!(f.parse("1.2é"), Err(AmountError::InvalidDigit('é')));
+    }
+
+    #[test]
+    fn round_half_even_excess_digits() {
+        let f = format(2, Rounding::HalfEven);
+        assert_eq!(f.parse("1.234"), Ok(Amount::from_units(123)));
+        assert_eq!(f.parse("1.236"), Ok(Amount::from_units(124)));
+        assert_eq!(f.parse("1.225"), Ok(Amount::from_units(122)));
+        assert_eq!(f.parse("1.235"), Ok(Amount::from_units(124)));
+        assert_eq!(f.parse("1.2250001"), Ok(Amount::from_units(123)));
+        assert_eq!(f.parse("0.995"), Ok(Amount::from_units(100)));
+        assert_eq!(f.parse("0.9é5"), Err(AmountError::InvalidDigit('é')));
+        assert_eq!(
+            f.parse("184467440737095516.155"),
+            Err(AmountError::Overflow)
+        );
+    }
+}
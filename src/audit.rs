@@ -0,0 +1,120 @@
+use crate::account_service::AccountStatus;
+use crate::amount::{AmountFormat, FormattedAmount};
+use crate::output::{OutputFormat, RecordWriter};
+use crate::tx::*;
+use crate::tx_processor::StatusChange;
+
+use futures_lite::future;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::thread;
+
+// entries are buffered between shard workers and the writer thread
+const CHANNEL_CAP: usize = 1024;
+
+// applied account administration operation
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub client: ClientId,
+    pub asset: Asset,
+    pub tx: TransactionId,
+    pub reason: Reason,
+    pub from: AccountStatus,
+    pub to: AccountStatus,
+    pub payout: FormattedAmount,
+    pub shard: usize,
+}
+
+impl AuditEntry {
+    pub fn new(
+        row: &TransactionRow,
+        shard: usize,
+        change: &StatusChange,
+        format: AmountFormat,
+    ) -> Self {
+        let tx = &row.tx;
+        Self {
+            tx_type: tx.tx_type,
+            client: tx.client_id,
+            asset: tx.asset,
+            tx: tx.tx_id,
+            reason: row.reason,
+            from: change.from,
+            to: change.to,
+            payout: format.display(change.payout),
+            shard,
+        }
+    }
+}
+
+// writes the audit trail sent by shard workers on a separate thread
+pub struct AuditWriter {
+    sender: async_channel::Sender<AuditEntry>,
+    handle: thread::JoinHandle<io::Result<()>>,
+}
+
+impl AuditWriter {
+    pub fn spawn<W: Write + Send + 'static>(writer: W, format: OutputFormat) -> Self {
+        let (sender, receiver) = async_channel::bounded::<AuditEntry>(CHANNEL_CAP);
+        let handle = thread::spawn(move || {
+            let mut out = RecordWriter::new(writer, format);
+            while let Ok(entry) = future::block_on(receiver.recv()) {
+                out.write(&entry)?;
+            }
+            out.finish()
+        });
+        Self { sender, handle }
+    }
+
+    pub fn sender(&self) -> async_channel::Sender<AuditEntry> {
+        self.sender.clone()
+    }
+
+    // entries already sent are still written
+    pub fn finish(self) -> io::Result<()> {
+        self.sender.close();
+        self.handle.join().expect("Audit writer panicked")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn write_csv() {
+        let close = TransactionRow {
+            tx: Transaction {
+                tx_type: TransactionType::Close,
+                client_id: 4,
+                tx_id: 90,
+                amount: None,
+                asset: "EUR".parse().unwrap(),
+            },
+            reason: "customer_request".parse().unwrap(),
+        };
+        let change = StatusChange {
+            from: AccountStatus::Locked,
+            to: AccountStatus::Closed,
+            payout: Amount::from_units(2500),
+        };
+        let mut buf = Vec::new();
+        let mut out = RecordWriter::new(&mut buf, OutputFormat::Csv);
+        out.write(&AuditEntry::new(
+            &close,
+            0,
+            &change,
+            AmountFormat::default(),
+        ))
+        .unwrap();
+        out.finish().unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "type,client,asset,tx,reason,from,to,payout,shard\n\
+             close,4,EUR,90,customer_request,locked,closed,2.500,0\n"
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use crate::account_service_shards::AccountShards;
+use crate::codec;
+use crate::tx::InputPosition;
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const CHECKPOINT_VERSION: u32 = 1;
+// bytes hashed at the start of the input and right before the checkpoint offset
+const FINGERPRINT_WINDOW: u64 = 64 * 1024;
+
+// detects that the input was replaced or modified before the checkpoint offset
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InputFingerprint {
+    head_crc: u32,
+    tail_crc: u32,
+}
+
+impl InputFingerprint {
+    pub fn compute(path: &Path, offset: u64) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        if file.metadata()?.len() < offset {
+            return Err(codec::invalid_data(
+                "input is shorter than checkpoint offset",
+            ));
+        }
+        let head_crc = window_crc(&mut file, 0, offset.min(FINGERPRINT_WINDOW))?;
+        let tail_start = offset.saturating_sub(FINGERPRINT_WINDOW);
+        let tail_crc = window_crc(&mut file, tail_start, offset - tail_start)?;
+        Ok(Self { head_crc, tail_crc })
+    }
+}
+
+fn window_crc(file: &mut File, start: u64, len: u64) -> io::Result<u32> {
+    let mut buf = Vec::with_capacity(len as usize);
+    file.seek(SeekFrom::Start(start))?;
+    file.take(len).read_to_end(&mut buf)?;
+    Ok(crc32fast::hash(&buf))
+}
+
+// input before `position` is reflected in `snapshot`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub input: PathBuf,
+    pub position: InputPosition,
+    pub fingerprint: InputFingerprint,
+    pub snapshot: PathBuf,
+    pub seq: u64,
+}
+
+impl Checkpoint {
+    // written to a temporary file and renamed, so the checkpoint file is always complete
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut file = File::create(&tmp_path)?;
+        writeln!(file, "version={}", CHECKPOINT_VERSION)?;
+        writeln!(file, "input={}", path_str(&self.input)?)?;
+        writeln!(file, "byte={}", self.position.byte)?;
+        writeln!(file, "line={}", self.position.line)?;
+        writeln!(file, "record={}", self.position.record)?;
+        writeln!(file, "head_crc={}", self.fingerprint.head_crc)?;
+        writeln!(file, "tail_crc={}", self.fingerprint.tail_crc)?;
+        writeln!(file, "snapshot={}", path_str(&self.snapshot)?)?;
+        writeln!(file, "seq={}", self.seq)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let value = |key: &str| {
+            content
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v)
+                .ok_or_else(|| codec::invalid_data(&format!("checkpoint has no {}", key)))
+        };
+        let number = |key: &str| {
+            value(key)?
+                .parse::<u64>()
+                .map_err(|_| codec::invalid_data(&format!("checkpoint has invalid {}", key)))
+        };
+
+        if number("version")? != CHECKPOINT_VERSION as u64 {
+            return Err(codec::invalid_data("unsupported checkpoint version"));
+        }
+        Ok(Checkpoint {
+            input: PathBuf::from(value("input")?),
+            position: InputPosition {
+                byte: number("byte")?,
+                line: number("line")?,
+                record: number("record")?,
+            },
+            fingerprint: InputFingerprint {
+                head_crc: number("head_crc")? as u32,
+                tail_crc: number("tail_crc")? as u32,
+            },
+            snapshot: PathBuf::from(value("snapshot")?),
+            seq: number("seq")?,
+        })
+    }
+
+    // checkpoint can be used only for the same, unmodified input
+    pub fn verify(&self, input: &Path) -> io::Result<()> {
+        if fs::canonicalize(input)? != fs::canonicalize(&self.input)? {
+            return Err(codec::invalid_data(&format!(
+                "checkpoint was taken for input {}",
+                self.input.display()
+            )));
+        }
+        if InputFingerprint::compute(input, self.position.byte)? != self.fingerprint {
+            return Err(codec::invalid_data(
+                "input was modified since the checkpoint",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn path_str(path: &Path) -> io::Result<&str> {
+    path.to_str()
+        .ok_or_else(|| codec::invalid_data("checkpoint paths must be valid UTF-8"))
+}
+
+// periodically persists engine state together with the input position
+pub struct Checkpointer {
+    path: PathBuf,
+    input: PathBuf,
+    every: u64,
+    since_last: u64,
+    last: Option<Checkpoint>,
+}
+
+impl Checkpointer {
+    pub fn new(path: &Path, input: &Path, every: u64) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            input: input.to_path_buf(),
+            every,
+            since_last: 0,
+            last: None,
+        }
+    }
+
+    // continue the sequence of a checkpoint loaded for resume
+    pub fn resumed(path: &Path, every: u64, last: Checkpoint) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            input: last.input.clone(),
+            every,
+            since_last: 0,
+            last: Some(last),
+        }
+    }
+
+    // called after every transaction passed to shards, `position` is where reading continues
+    pub fn record_processed(
+        &mut self,
+        shards: &AccountShards,
+        position: InputPosition,
+    ) -> io::Result<()> {
+        self.since_last += 1;
+        if self.since_last >= self.every {
+            self.save(shards, position)?;
+        }
+        Ok(())
+    }
+
+    // new snapshot is written under a new name, so the previous checkpoint stays valid
+    // until the checkpoint file is replaced
+    pub fn save(&mut self, shards: &AccountShards, position: InputPosition) -> io::Result<()> {
+        let seq = self.last.as_ref().map_or(0, |c| c.seq + 1);
+        let mut snapshot = self.path.as_os_str().to_owned();
+        snapshot.push(format!(".{}.snap", seq));
+        let snapshot = PathBuf::from(snapshot);
+
+        shards.save_snapshot(&snapshot)?;
+        let checkpoint = Checkpoint {
+            input: self.input.clone(),
+            position,
+            fingerprint: InputFingerprint::compute(&self.input, position.byte)?,
+            snapshot,
+            seq,
+        };
+        checkpoint.save(&self.path)?;
+
+        if let Some(previous) = self.last.replace(checkpoint) {
+            // stale snapshot, leftover file is harmless
+            let _ = fs::remove_file(&previous.snapshot);
+        }
+        self.since_last = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::account_service::AccountOrder;
+    use crate::amount::AmountFormat;
+    use crate::tx_csv_iter::TransIterator;
+
+    fn final_balances(shards: AccountShards) -> Vec<String> {
+        shards
+            .lock_accounts()
+            .sorted(AccountOrder::Client)
+            .map(|a| format!("{} {} {}", a.client, a.available, a.held))
+            .collect()
+    }
+
+    #[test]
+    fn resume_after_kill() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.csv");
+        let mut content = String::from("type,client,tx,amount\n");
+        for i in 0..100 {
+            content.push_str(&format!("deposit,{},{},1.0\n", i % 7, i));
+            content.push_str(&format!("withdrawal,{},{},0.5\n", i % 7, 1000 + i));
+        }
+        fs::write(&input, content).unwrap();
+        let checkpoint_path = dir.path().join("run.checkpoint");
+
+        // run killed after 150 records, last checkpoint at 140
+        let mut shards = AccountShards::new(3);
+        shards.run().unwrap();
+        let mut checkpointer = Checkpointer::new(&checkpoint_path, &input, 20);
+        let mut iter = TransIterator::new(&input, AmountFormat::default()).unwrap();
+        for _ in 0..150 {
+            let row = iter.next().unwrap().unwrap();
+            shards.process(row.tx).unwrap();
+            checkpointer
+                .record_processed(&shards, iter.position())
+                .unwrap();
+        }
+        shards.join().unwrap();
+
+        let checkpoint = Checkpoint::load(&checkpoint_path).unwrap();
+        assert_eq!(checkpoint.position.record, 141);
+        checkpoint.verify(&input).unwrap();
+
+        let mut resumed = AccountShards::new(2);
+        resumed.load_snapshot(&checkpoint.snapshot).unwrap();
+        resumed.run().unwrap();
+        let iter =
+            TransIterator::resume(&input, AmountFormat::default(), checkpoint.position).unwrap();
+        iter.for_each(|row| resumed.process(row.unwrap().tx).unwrap());
+        resumed.join().unwrap();
+
+        let mut full = AccountShards::new(4);
+        full.run().unwrap();
+        TransIterator::new(&input, AmountFormat::default())
+            .unwrap()
+            .for_each(|row| full.process(row.unwrap().tx).unwrap());
+        full.join().unwrap();
+
+        assert_eq!(final_balances(resumed), final_balances(full));
+    }
+
+    #[test]
+    fn modified_input_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.csv");
+        fs::write(&input, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+
+        let checkpoint = Checkpoint {
+            input: input.clone(),
+            position: InputPosition {
+                byte: 38,
+                line: 3,
+                record: 2,
+            },
+            fingerprint: InputFingerprint::compute(&input, 38).unwrap(),
+            snapshot: dir.path().join("x.snap"),
+            seq: 3,
+        };
+        let path = dir.path().join("run.checkpoint");
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        assert_eq!(loaded, checkpoint);
+        loaded.verify(&input).unwrap();
+
+        // appended rows are fine
+        fs::write(
+            &input,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.0\n",
+        )
+        .unwrap();
+        loaded.verify(&input).unwrap();
+
+        fs::write(&input, "type,client,tx,amount\ndeposit,1,1,9.0\n").unwrap();
+        assert!(loaded.verify(&input).is_err());
+    }
+}
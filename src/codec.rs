@@ -0,0 +1,345 @@
+use crate::account_service::{Account, AccountStatus};
+use crate::tx::*;
+use crate::tx_service::{TransactionState, TransactionWithState};
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+// binary encoding shared by the write-ahead log and snapshots (little endian)
+
+pub const TRANSACTION_LEN: usize = 24;
+const LEGACY_TRANSACTION_STATE_LEN: usize = TRANSACTION_LEN + 1;
+const PARTIAL_TRANSACTION_STATE_LEN: usize = LEGACY_TRANSACTION_STATE_LEN + 16;
+pub const TRANSACTION_STATE_LEN: usize = PARTIAL_TRANSACTION_STATE_LEN + 8;
+const LEGACY_ACCOUNT_LEN: usize = 27;
+pub const ACCOUNT_LEN: usize = LEGACY_ACCOUNT_LEN + 16;
+
+fn tx_type_to_u8(tx_type: TransactionType) -> u8 {
+    match tx_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Freeze => 5,
+        TransactionType::Unlock => 6,
+        TransactionType::Close => 7,
+    }
+}
+
+fn tx_type_from_u8(v: u8) -> Option<TransactionType> {
+    Some(match v {
+        0 => TransactionType::Deposit,
+        1 => TransactionType::Withdrawal,
+        2 => TransactionType::Dispute,
+        3 => TransactionType::Resolve,
+        4 => TransactionType::Chargeback,
+        5 => TransactionType::Freeze,
+        6 => TransactionType::Unlock,
+        7 => TransactionType::Close,
+        _ => return None,
+    })
+}
+
+pub fn encode_transaction(tx: &Transaction, buf: &mut Vec<u8>) {
+    buf.push(tx_type_to_u8(tx.tx_type));
+    buf.extend_from_slice(&tx.client_id.to_le_bytes());
+    buf.extend_from_slice(&tx.tx_id.to_le_bytes());
+    encode_option_amount(tx.amount, buf);
+    buf.extend_from_slice(tx.asset.as_bytes());
+}
+
+pub fn decode_transaction(buf: &[u8]) -> Option<Transaction> {
+    if buf.len() != TRANSACTION_LEN {
+        return None;
+    }
+    Some(Transaction {
+        tx_type: tx_type_from_u8(buf[0])?,
+        client_id: ClientId::from_le_bytes(buf[1..3].try_into().ok()?),
+        tx_id: TransactionId::from_le_bytes(buf[3..7].try_into().ok()?),
+        amount: decode_option_amount(&buf[7..16])?,
+        asset: Asset::from_bytes(buf[16..24].try_into().ok()?)?,
+    })
+}
+
+pub fn encode_transaction_state(tx_state: &TransactionWithState, buf: &mut Vec<u8>) {
+    encode_transaction(&tx_state.tx, buf);
+    buf.push(match tx_state.state {
+        TransactionState::Valid => 0,
+        TransactionState::Disputed => 1,
+        TransactionState::Refunded => 2,
+        TransactionState::Resolved => 3,
+    });
+    buf.extend_from_slice(&tx_state.disputed.units().to_le_bytes());
+    buf.extend_from_slice(&tx_state.refunded.units().to_le_bytes());
+    buf.extend_from_slice(&tx_state.pending.units().to_le_bytes());
+}
+
+// snapshots before version 3 have no disputed and refunded parts, a dispute was always
+// of the whole amount; before version 7 the pending part is not known, all of the disputed
+// part may be pending (the account limits it to its pending hold)
+pub fn decode_transaction_state(buf: &[u8]) -> Option<TransactionWithState> {
+    if ![
+        TRANSACTION_STATE_LEN,
+        PARTIAL_TRANSACTION_STATE_LEN,
+        LEGACY_TRANSACTION_STATE_LEN,
+    ]
+    .contains(&buf.len())
+    {
+        return None;
+    }
+    let mut tx_state = TransactionWithState::new(decode_transaction(&buf[..TRANSACTION_LEN])?);
+    tx_state.state = match buf[TRANSACTION_LEN] {
+        0 => TransactionState::Valid,
+        1 => TransactionState::Disputed,
+        2 => TransactionState::Refunded,
+        3 => TransactionState::Resolved,
+        _ => return None,
+    };
+    if buf.len() == LEGACY_TRANSACTION_STATE_LEN {
+        let amount = tx_state.tx.amount.unwrap_or(Amount::ZERO);
+        match tx_state.state {
+            TransactionState::Disputed => tx_state.disputed = amount,
+            TransactionState::Refunded => tx_state.refunded = amount,
+            TransactionState::Valid | TransactionState::Resolved => {}
+        }
+        tx_state.pending = tx_state.disputed;
+        return Some(tx_state);
+    }
+    let amount = |i: usize| {
+        Some(Amount::from_units(u64::from_le_bytes(
+            buf[i..i + 8].try_into().ok()?,
+        )))
+    };
+    tx_state.disputed = amount(LEGACY_TRANSACTION_STATE_LEN)?;
+    tx_state.refunded = amount(LEGACY_TRANSACTION_STATE_LEN + 8)?;
+    tx_state.pending = match buf.len() {
+        TRANSACTION_STATE_LEN => amount(PARTIAL_TRANSACTION_STATE_LEN)?,
+        _ => tx_state.disputed,
+    };
+    Some(tx_state)
+}
+
+pub fn encode_account(account: &Account, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&account.client_id.to_le_bytes());
+    buf.extend_from_slice(account.asset.as_bytes());
+    buf.extend_from_slice(&account.available.units().to_le_bytes());
+    buf.extend_from_slice(&account.held.units().to_le_bytes());
+    buf.push(match account.status {
+        AccountStatus::Active => 0,
+        AccountStatus::Locked => 1,
+        AccountStatus::Frozen => 2,
+        AccountStatus::Closed => 3,
+    });
+    buf.extend_from_slice(&account.receivable.units().to_le_bytes());
+    buf.extend_from_slice(&account.hold_pending.units().to_le_bytes());
+}
+
+// snapshots before version 4 have no receivable and pending hold
+pub fn decode_account(buf: &[u8]) -> Option<Account> {
+    if buf.len() != ACCOUNT_LEN && buf.len() != LEGACY_ACCOUNT_LEN {
+        return None;
+    }
+    let amount = |i: usize| {
+        Some(Amount::from_units(u64::from_le_bytes(
+            buf[i..i + 8].try_into().ok()?,
+        )))
+    };
+    let mut account = Account::new(
+        ClientId::from_le_bytes(buf[0..2].try_into().ok()?),
+        Asset::from_bytes(buf[2..10].try_into().ok()?)?,
+        amount(10)?,
+    );
+    account.held = amount(18)?;
+    // before version 5 only active (0) and locked (1)
+    account.status = match buf[26] {
+        0 => AccountStatus::Active,
+        1 => AccountStatus::Locked,
+        2 => AccountStatus::Frozen,
+        3 => AccountStatus::Closed,
+        _ => return None,
+    };
+    if buf.len() == ACCOUNT_LEN {
+        account.receivable = amount(LEGACY_ACCOUNT_LEN)?;
+        account.hold_pending = amount(LEGACY_ACCOUNT_LEN + 8)?;
+    }
+    Some(account)
+}
+
+fn encode_option_amount(amount: Option<Amount>, buf: &mut Vec<u8>) {
+    match amount {
+        Some(amount) => {
+            buf.push(1);
+            buf.extend_from_slice(&amount.units().to_le_bytes());
+        }
+        None => buf.extend_from_slice(&[0; 9]),
+    }
+}
+
+fn decode_option_amount(buf: &[u8]) -> Option<Option<Amount>> {
+    let units = u64::from_le_bytes(buf[1..9].try_into().ok()?);
+    match buf[0] {
+        0 => Some(None),
+        1 => Some(Some(Amount::from_units(units))),
+        _ => None,
+    }
+}
+
+// record framing: payload length, payload, crc32 of payload
+pub fn write_record<W: Write>(w: &mut W, payload: &[u8]) -> io::Result<()> {
+    w.write_all(&(payload.len() as u32).to_le_bytes())?;
+    w.write_all(payload)?;
+    w.write_all(&crc32fast::hash(payload).to_le_bytes())
+}
+
+// Ok(None) on clean end of input, InvalidData error on torn or corrupted record,
+// a length above `max_len` is corrupted (it is not allocated)
+pub fn read_record<R: Read>(
+    r: &mut R,
+    payload: &mut Vec<u8>,
+    max_len: usize,
+) -> io::Result<Option<()>> {
+    let mut len = [0; 4];
+    match read_exact_or_eof(r, &mut len)? {
+        0 => return Ok(None),
+        4 => {}
+        _ => return Err(invalid_data("truncated record length")),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > max_len {
+        return Err(invalid_data("record length out of range"));
+    }
+    payload.resize(len, 0);
+    let mut crc = [0; 4];
+    if read_exact_or_eof(r, payload)? != len || read_exact_or_eof(r, &mut crc)? != 4 {
+        return Err(invalid_data("truncated record"));
+    }
+    if crc32fast::hash(payload) != u32::from_le_bytes(crc) {
+        return Err(invalid_data("record checksum mismatch"));
+    }
+    Ok(Some(()))
+}
+
+fn read_exact_or_eof<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match r.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+pub fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn transaction_roundtrip() {
+        let tx = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client_id: 65_535,
+            tx_id: 4_000_000_000,
+            amount: Some(Amount::from_units(u64::MAX)),
+            asset: "USDT".parse().unwrap(),
+        };
+        let mut buf = Vec::new();
+        encode_transaction(&tx, &mut buf);
+        assert_eq!(buf.len(), TRANSACTION_LEN);
+        let decoded = decode_transaction(&buf).unwrap();
+        assert_eq!(decoded.tx_type, tx.tx_type);
+        assert_eq!(decoded.client_id, tx.client_id);
+        assert_eq!(decoded.tx_id, tx.tx_id);
+        assert_eq!(decoded.amount, tx.amount);
+        assert_eq!(decoded.asset, tx.asset);
+    }
+
+    #[test]
+    fn transaction_state_roundtrip() {
+        let mut tx_state = TransactionWithState::new(Transaction {
+            tx_type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 2,
+            amount: Some(Amount::from_units(100)),
+            asset: Asset::default(),
+        });
+        tx_state.disputed = Amount::from_units(30);
+        tx_state.refunded = Amount::from_units(20);
+        tx_state.pending = Amount::from_units(10);
+        tx_state.update_state();
+        let mut buf = Vec::new();
+        encode_transaction_state(&tx_state, &mut buf);
+        assert_eq!(buf.len(), TRANSACTION_STATE_LEN);
+        let decoded = decode_transaction_state(&buf).unwrap();
+        assert_eq!(decoded.state, TransactionState::Disputed);
+        assert_eq!(decoded.disputed, Amount::from_units(30));
+        assert_eq!(decoded.refunded, Amount::from_units(20));
+        assert_eq!(decoded.pending, Amount::from_units(10));
+
+        // all of the disputed part may be pending in older snapshots
+        buf.truncate(PARTIAL_TRANSACTION_STATE_LEN);
+        let decoded = decode_transaction_state(&buf).unwrap();
+        assert_eq!(decoded.refunded, Amount::from_units(20));
+        assert_eq!(decoded.pending, Amount::from_units(30));
+
+        // disputed as a whole in older snapshots
+        buf.truncate(LEGACY_TRANSACTION_STATE_LEN);
+        let decoded = decode_transaction_state(&buf).unwrap();
+        assert_eq!(decoded.disputed, Amount::from_units(100));
+        assert_eq!(decoded.refunded, Amount::ZERO);
+    }
+
+    #[test]
+    fn account_roundtrip() {
+        let mut account = Account::new(3, "EUR".parse().unwrap(), Amount::from_units(15));
+        account.held = Amount::from_units(7);
+        account.status = AccountStatus::Frozen;
+        account.receivable = Amount::from_units(2);
+        account.hold_pending = Amount::from_units(4);
+        let mut buf = Vec::new();
+        encode_account(&account, &mut buf);
+        assert_eq!(buf.len(), ACCOUNT_LEN);
+        assert_eq!(decode_account(&buf), Some(account.clone()));
+
+        // nothing owed or pending in older snapshots
+        buf.truncate(LEGACY_ACCOUNT_LEN);
+        let decoded = decode_account(&buf).unwrap();
+        assert_eq!(decoded.held, account.held);
+        assert_eq!(decoded.receivable, Amount::ZERO);
+        assert_eq!(decoded.hold_pending, Amount::ZERO);
+    }
+
+    #[test]
+    fn torn_record_is_detected() {
+        let mut buf = Vec::new();
+        write_record(&mut buf, b"payload").unwrap();
+        write_record(&mut buf, b"second").unwrap();
+        buf.truncate(buf.len() - 2);
+
+        let mut r = &buf[..];
+        let mut payload = Vec::new();
+        assert!(read_record(&mut r, &mut payload, 16).unwrap().is_some());
+        assert_eq!(payload, b"payload");
+        assert!(read_record(&mut r, &mut payload, 16).is_err());
+    }
+
+    #[test]
+    fn corrupted_length_is_not_allocated() {
+        let mut buf = Vec::new();
+        write_record(&mut buf, b"payload").unwrap();
+        buf[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut payload = Vec::new();
+        let err = read_record(&mut &buf[..], &mut payload, 16).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(payload.capacity(), 0);
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+// ratio budget is checked only after this many rows, so a bad first row does not abort the run
+const MIN_ROWS_FOR_RATIO: u64 = 100;
+
+// malformed input row, reported instead of a transaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseDiagnostic {
+    // line and byte offset where the record starts
+    pub line: u64,
+    pub byte: u64,
+    // raw record fields joined with ',', empty when the record could not be read
+    pub record: String,
+    // column name, when the error can be attributed to one
+    pub field: Option<String>,
+    pub message: String,
+    // reading the input failed (i/o error), no more rows can be read from it
+    pub fatal: bool,
+}
+
+impl fmt::Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {} (byte {})", self.line, self.byte)?;
+        if let Some(field) = &self.field {
+            write!(f, " field '{}'", field)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if !self.record.is_empty() {
+            write!(f, ", record '{}'", self.record)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ErrorBudget {
+    // abort when more than N rows are malformed
+    Rows(u64),
+    // abort when more than X% of rows are malformed
+    Percent(f64),
+}
+
+impl FromStr for ErrorBudget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid error budget '{}', expected number of rows or percentage (ie. 0.5%)",
+                s
+            )
+        };
+        match s.strip_suffix('%') {
+            Some(p) => match p.parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(ErrorBudget::Percent(p)),
+                _ => Err(invalid()),
+            },
+            None => s.parse().map(ErrorBudget::Rows).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for ErrorBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorBudget::Rows(n) => write!(f, "{} rows", n),
+            ErrorBudget::Percent(p) => write!(f, "{}%", p),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BudgetExceeded {
+    pub errors: u64,
+    pub rows: u64,
+    pub budget: ErrorBudget,
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} rows are malformed, error budget is {}",
+            self.errors, self.rows, self.budget
+        )
+    }
+}
+
+// writes diagnostics and keeps count of malformed rows against the budget
+pub struct DiagnosticReporter {
+    out: Box<dyn Write>,
+    budget: Option<ErrorBudget>,
+    rows: u64,
+    errors: u64,
+}
+
+impl DiagnosticReporter {
+    pub fn new(out: Box<dyn Write>, budget: Option<ErrorBudget>) -> Self {
+        Self {
+            out,
+            budget,
+            rows: 0,
+            errors: 0,
+        }
+    }
+
+    pub fn stderr(budget: Option<ErrorBudget>) -> Self {
+        Self::new(Box::new(io::stderr()), budget)
+    }
+
+    pub fn record_valid(&mut self) {
+        self.rows += 1;
+    }
+
+    pub fn report(&mut self, diagnostic: &ParseDiagnostic) -> io::Result<()> {
+        self.rows += 1;
+        self.errors += 1;
+        writeln!(self.out, "Parse error {}", diagnostic)
+    }
+
+    // at the end of input the ratio is checked regardless of the number of rows
+    pub fn check(&self, end_of_input: bool) -> Result<(), BudgetExceeded> {
+        let exceeded = match self.budget {
+            None => false,
+            Some(ErrorBudget::Rows(n)) => self.errors > n,
+            Some(ErrorBudget::Percent(p)) => {
+                (end_of_input || self.rows >= MIN_ROWS_FOR_RATIO)
+                    && self.errors as f64 * 100.0 > p * self.rows as f64
+            }
+        };
+        match (exceeded, self.budget) {
+            (true, Some(budget)) => Err(BudgetExceeded {
+                errors: self.errors,
+                rows: self.rows,
+                budget,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn diagnostic() -> ParseDiagnostic {
+        ParseDiagnostic {
+            line: 3,
+            byte: 38,
+            record: "deposit,2,2,-2.0".to_string(),
+            field: Some("amount".to_string()),
+            message: "amount cannot be negative".to_string(),
+            fatal: false,
+        }
+    }
+
+    #[test]
+    fn display_diagnostic() {
+        assert_eq!(
+            diagnostic().to_string(),
+            "line 3 (byte 38) field 'amount': amount cannot be negative, record 'deposit,2,2,-2.0'"
+        );
+    }
+
+    #[test]
+    fn parse_budget() {
+        assert_eq!("10".parse(), Ok(ErrorBudget::Rows(10)));
+        assert_eq!("0.5%".parse(), Ok(ErrorBudget::Percent(0.5)));
+        assert!("101%".parse::<ErrorBudget>().is_err());
+        assert!("ten".parse::<ErrorBudget>().is_err());
+    }
+
+    #[test]
+    fn budget_exceeded() {
+        let mut reporter =
+            DiagnosticReporter::new(Box::new(io::sink()), Some(ErrorBudget::Rows(1)));
+        reporter.report(&diagnostic()).unwrap();
+        assert!(reporter.check(false).is_ok());
+        reporter.report(&diagnostic()).unwrap();
+        assert_eq!(reporter.check(false).unwrap_err().errors, 2);
+
+        let mut reporter =
+            DiagnosticReporter::new(Box::new(io::sink()), Some(ErrorBudget::Percent(10.0)));
+        reporter.report(&diagnostic()).unwrap();
+        for _ in 0..8 {
+            reporter.record_valid();
+        }
+        // too few rows to judge the ratio until the input ends
+        assert!(reporter.check(false).is_ok());
+        assert!(reporter.check(true).is_err());
+        reporter.record_valid();
+        assert!(reporter.check(true).is_ok());
+    }
+}
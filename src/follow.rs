@@ -0,0 +1,228 @@
+use crate::input::TIMESTAMP_HEADER;
+
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const CHUNK_LEN: usize = 8 * 1024;
+
+// reads a growing file like `tail -F`: at the end of file it waits for appended data,
+// hands out complete lines only and reopens the path when the file is rotated or truncated
+pub struct FollowReader {
+    path: PathBuf,
+    file: File,
+    id: Option<(u64, u64)>,
+    // bytes read from the current file
+    offset: u64,
+    // read but not yet returned, a partial last line stays here until its newline arrives
+    pending: Vec<u8>,
+    poll: Duration,
+    // end of input is returned once set, a pending partial line is dropped
+    stop: Arc<AtomicBool>,
+    // first line of the followed file, dropped when a rotated file repeats it (csv header)
+    header: Option<Vec<u8>>,
+    skip_header: bool,
+    at_file_start: bool,
+    // length of the timestamp line of the first file, still in `pending` and passed on
+    checked: usize,
+}
+
+impl FollowReader {
+    pub fn open(path: &Path, poll: Duration, stop: Arc<AtomicBool>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let id = file_id(&file.metadata()?);
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            id,
+            offset: 0,
+            pending: Vec::new(),
+            poll,
+            stop,
+            header: None,
+            skip_header: false,
+            at_file_start: true,
+            checked: 0,
+        })
+    }
+
+    // every file starts with the same header line, ie. csv column names
+    pub fn skip_repeated_header(mut self) -> Self {
+        self.skip_header = true;
+        self
+    }
+
+    // called with complete lines in `pending`, which belong to the current file only
+    fn check_header(&mut self) {
+        let start = self.checked;
+        let end = match self.pending[start..].iter().position(|&b| b == b'\n') {
+            Some(end) if self.at_file_start => start + end + 1,
+            _ => return,
+        };
+        if !self.skip_header {
+            self.at_file_start = false;
+            return;
+        }
+        // the header follows the timestamp line, which is read by the csv reader in the
+        // first file and would be a malformed row in a rotated one
+        if start == 0 && self.pending.starts_with(TIMESTAMP_HEADER.as_bytes()) {
+            match self.header {
+                Some(_) => {
+                    self.pending.drain(..end);
+                }
+                None => self.checked = end,
+            }
+            return self.check_header();
+        }
+        self.at_file_start = false;
+        self.checked = 0;
+        match &self.header {
+            Some(header) if header[..] == self.pending[start..end] => {
+                self.pending.drain(start..end);
+            }
+            Some(_) => {}
+            None => self.header = Some(self.pending[start..end].to_vec()),
+        }
+    }
+
+    // reads a chunk of the current file into `pending`, 0 at its end
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0; CHUNK_LEN];
+        let n = self.file.read(&mut chunk)?;
+        self.pending.extend_from_slice(&chunk[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    // true when the current file has ended for good and the path was reopened
+    fn reopen_if_replaced(&mut self) -> io::Result<bool> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // rotated away and not recreated yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let same_file = file_id(&metadata) == self.id;
+        let truncated = same_file && metadata.len() < self.offset;
+        if same_file && !truncated {
+            return Ok(false);
+        }
+        // a rotated file is complete, its last line may lack the newline,
+        // the new file is opened once the line is returned
+        if !truncated && !self.pending.is_empty() {
+            self.pending.push(b'\n');
+            return Ok(true);
+        }
+        self.pending.clear();
+        self.file = File::open(&self.path)?;
+        self.id = file_id(&self.file.metadata()?);
+        self.offset = 0;
+        self.at_file_start = true;
+        self.checked = 0;
+        Ok(true)
+    }
+}
+
+impl Read for FollowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            self.check_header();
+            if let Some(last) = self.pending.iter().rposition(|&b| b == b'\n') {
+                let n = buf.len().min(last + 1);
+                buf[..n].copy_from_slice(&self.pending[..n]);
+                self.pending.drain(..n);
+                self.checked = self.checked.saturating_sub(n);
+                return Ok(n);
+            }
+            if self.fill()? > 0 {
+                continue;
+            }
+            // rotated file is drained before switching to the new one
+            if self.reopen_if_replaced()? {
+                continue;
+            }
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok(0);
+            }
+            thread::sleep(self.poll);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+// rotation is detected only by truncation
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+
+    #[test]
+    fn follow_appends_and_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv");
+        fs::write(&path, "h\na\nb").unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = FollowReader::open(&path, Duration::from_millis(5), Arc::clone(&stop))
+            .unwrap()
+            .skip_repeated_header();
+        let mut lines = BufReader::new(reader).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "h");
+        assert_eq!(lines.next().unwrap().unwrap(), "a");
+
+        // partial line is completed by the writer
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"c\nd").unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "bc");
+
+        // rotation, last line of the old file has no newline
+        fs::rename(&path, dir.path().join("input.csv.1")).unwrap();
+        fs::write(&path, "h\ne\nf").unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "d");
+        assert_eq!(lines.next().unwrap().unwrap(), "e");
+
+        // partial line is dropped on stop
+        stop.store(true, Ordering::Relaxed);
+        assert!(lines.next().is_none());
+    }
+
+    #[test]
+    fn rotation_of_timestamped_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv");
+        fs::write(&path, "# timestamp: 2024-05-01T10:00:00Z\nh\na\n").unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let reader = FollowReader::open(&path, Duration::from_millis(5), Arc::clone(&stop))
+            .unwrap()
+            .skip_repeated_header();
+        let mut lines = BufReader::new(reader).lines();
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            "# timestamp: 2024-05-01T10:00:00Z"
+        );
+        assert_eq!(lines.next().unwrap().unwrap(), "h");
+        assert_eq!(lines.next().unwrap().unwrap(), "a");
+
+        // timestamp and header of the rotated file are dropped
+        fs::rename(&path, dir.path().join("input.csv.1")).unwrap();
+        fs::write(&path, "# timestamp: 2024-05-02T10:00:00Z\nh\nb\n").unwrap();
+        assert_eq!(lines.next().unwrap().unwrap(), "b");
+
+        stop.store(true, Ordering::Relaxed);
+        assert!(lines.next().is_none());
+    }
+}
@@ -0,0 +1,249 @@
+use crate::account_service::AccountResult;
+use crate::account_service_shards::ShardsHandle;
+use crate::amount::AmountFormat;
+use crate::tx_service::TransactionResult;
+
+use serde::Serialize;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// interval of checking the stop flag while no client connects
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+// slow or idle clients do not keep a connection thread forever
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// read-only json queries of the running engine, every query is answered by the shard
+// workers through their channels, so it sees every transaction passed to them before:
+//   GET /clients/<client>      accounts of the client (one per asset)
+//   GET /accounts/locked       locked accounts ordered by client and asset
+//   GET /transactions/<tx>     stored deposit or withdrawal with its state
+pub struct HttpServer {
+    listener: TcpListener,
+    amount_format: AmountFormat,
+    stop: Arc<AtomicBool>,
+}
+
+struct Response {
+    status: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json<T: Serialize>(value: &T) -> Self {
+        Response {
+            status: "200 OK",
+            body: serde_json::to_string(value).expect("Serialize response error"),
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Self {
+        Response {
+            status,
+            body: serde_json::json!({ "error": message }).to_string(),
+        }
+    }
+}
+
+impl HttpServer {
+    pub fn bind(
+        addr: &str,
+        amount_format: AmountFormat,
+        stop: Arc<AtomicBool>,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(HttpServer {
+            listener,
+            amount_format,
+            stop,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // returns once stop is set, shards must be running until then
+    pub fn serve(self, shards: ShardsHandle) -> io::Result<()> {
+        let mut connections = Vec::new();
+        while !self.stop.load(Ordering::Relaxed) {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    let shards = shards.clone();
+                    let amount_format = self.amount_format;
+                    connections.push(thread::spawn(move || {
+                        if let Err(e) = handle_request(stream, &shards, amount_format) {
+                            eprintln!("Http connection error: {}", e);
+                        }
+                    }));
+                }
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        eprintln!("Accept error: {}", e);
+                    }
+                    thread::sleep(ACCEPT_POLL);
+                }
+            }
+            connections.retain(|handle| !handle.is_finished());
+        }
+        for handle in connections {
+            handle.join().expect("Http connection thread panicked");
+        }
+        Ok(())
+    }
+}
+
+// one request per connection, the body of the request is ignored
+fn handle_request(
+    stream: TcpStream,
+    shards: &ShardsHandle,
+    amount_format: AmountFormat,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => route(path, shards, amount_format)?,
+        (Some(_), Some(_)) => Response::error("405 Method Not Allowed", "only GET is supported"),
+        _ => Response::error("400 Bad Request", "invalid request line"),
+    };
+    let mut out = io::BufWriter::new(stream);
+    write!(
+        out,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.body.len(),
+        response.body
+    )?;
+    out.flush()
+}
+
+fn route(path: &str, shards: &ShardsHandle, amount_format: AmountFormat) -> io::Result<Response> {
+    let path = path.split('?').next().unwrap_or_default();
+    let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+    Ok(match segments[..] {
+        ["clients", client] => match client.parse() {
+            Ok(client_id) => {
+                let accounts = shards.client_accounts(client_id)?;
+                if accounts.is_empty() {
+                    Response::error("404 Not Found", "client not found")
+                } else {
+                    let results: Vec<_> = accounts
+                        .iter()
+                        .map(|a| AccountResult::new(a, amount_format))
+                        .collect();
+                    Response::json(&results)
+                }
+            }
+            Err(_) => Response::error("400 Bad Request", "invalid client id"),
+        },
+        ["accounts", "locked"] => {
+            let results: Vec<_> = shards
+                .locked_accounts()?
+                .iter()
+                .map(|a| AccountResult::new(a, amount_format))
+                .collect();
+            Response::json(&results)
+        }
+        ["transactions", tx] => match tx.parse() {
+            Ok(tx_id) => match shards.transaction(tx_id)? {
+                Some(t) => Response::json(&TransactionResult::new(&t, amount_format)),
+                None => Response::error("404 Not Found", "transaction not found"),
+            },
+            Err(_) => Response::error("400 Bad Request", "invalid transaction id"),
+        },
+        _ => Response::error("404 Not Found", "unknown path"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::account_service_shards::AccountShards;
+    use crate::tx::*;
+    use std::io::Read;
+
+    fn get(addr: SocketAddr, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_string();
+        (status, body.to_string())
+    }
+
+    fn tx(
+        tx_type: TransactionType,
+        client_id: ClientId,
+        tx_id: TransactionId,
+        amount: u64,
+    ) -> Transaction {
+        Transaction {
+            tx_type,
+            client_id,
+            tx_id,
+            amount: (amount > 0).then(|| Amount::from_units(amount)),
+            asset: Asset::default(),
+        }
+    }
+
+    #[test]
+    fn query_running_shards() {
+        let mut shards = AccountShards::new(2);
+        shards.run().unwrap();
+        shards
+            .process(tx(TransactionType::Deposit, 1, 1, 5000))
+            .unwrap();
+        shards
+            .process(tx(TransactionType::Deposit, 2, 2, 3000))
+            .unwrap();
+        shards
+            .process(tx(TransactionType::Dispute, 2, 2, 0))
+            .unwrap();
+        shards
+            .process(tx(TransactionType::Chargeback, 2, 2, 0))
+            .unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let server =
+            HttpServer::bind("127.0.0.1:0", shards.amount_format(), Arc::clone(&stop)).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = shards.handle();
+        let serving = thread::spawn(move || server.serve(handle));
+
+        let (status, body) = get(addr, "/clients/1");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(
+            body,
+            "[{\"client\":1,\"asset\":\"\",\"available\":\"5.000\",\"held\":\"0.000\",\"total\":\"5.000\",\"locked\":false,\"status\":\"active\"}]"
+        );
+        let (_, body) = get(addr, "/accounts/locked");
+        assert!(body.starts_with("[{\"client\":2,"));
+        let (_, body) = get(addr, "/transactions/2");
+        assert_eq!(
+            body,
+            "{\"type\":\"deposit\",\"client\":2,\"tx\":2,\"amount\":\"3.000\",\"asset\":\"\",\"state\":\"refunded\",\"disputed\":\"0.000\",\"refunded\":\"3.000\"}"
+        );
+        assert_eq!(get(addr, "/clients/9").0, "HTTP/1.1 404 Not Found");
+        assert_eq!(get(addr, "/transactions/x").0, "HTTP/1.1 400 Bad Request");
+
+        stop.store(true, Ordering::Relaxed);
+        serving.join().unwrap().unwrap();
+        shards.join().unwrap();
+    }
+}
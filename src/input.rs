@@ -0,0 +1,506 @@
+use crate::diagnostics::ParseDiagnostic;
+use crate::tx::*;
+use crate::tx_csv_iter::TransIterator;
+use crate::tx_json_iter::JsonTransIterator;
+
+use crate::account_service::AccountServiceError;
+use crate::follow::FollowReader;
+use crate::tx_processor::Outcome;
+
+use serde::Serialize;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use strum_macros::EnumString;
+
+// bytes looked at when sniffing the format of a followed file
+const SNIFF_LEN: u64 = 4096;
+// optional first line of an input file, ie. "# timestamp: 2024-05-01T10:00:00Z"
+pub const TIMESTAMP_HEADER: &str = "# timestamp:";
+
+#[derive(EnumString, Debug, Copy, Clone, PartialEq, Default)]
+#[strum(serialize_all = "snake_case")]
+pub enum InputFormat {
+    // json when the first non-whitespace character is '{' or '[', otherwise csv
+    #[default]
+    Auto,
+    Csv,
+    // ndjson or json array of transaction objects
+    Json,
+}
+
+impl InputFormat {
+    // `head` is the start of the input
+    pub fn detect(self, head: &[u8]) -> InputFormat {
+        if self != InputFormat::Auto {
+            return self;
+        }
+        let mut head = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+        loop {
+            match head.iter().position(|b| !b.is_ascii_whitespace()) {
+                // '#' comment line, ie. timestamp header
+                Some(i) if head[i] == b'#' => match head[i..].iter().position(|&b| b == b'\n') {
+                    Some(end) => head = &head[i + end..],
+                    None => return InputFormat::Csv,
+                },
+                Some(i) if matches!(head[i], b'{' | b'[') => return InputFormat::Json,
+                _ => return InputFormat::Csv,
+            }
+        }
+    }
+}
+
+// input file, or stdin for path "-"
+pub enum InputSource {
+    File(File),
+    Stdin(io::Stdin),
+    // growing file
+    Follow(FollowReader),
+}
+
+impl InputSource {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if path == Path::new("-") {
+            Ok(InputSource::Stdin(io::stdin()))
+        } else {
+            File::open(path).map(InputSource::File)
+        }
+    }
+}
+
+impl Read for InputSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            InputSource::File(f) => f.read(buf),
+            InputSource::Stdin(s) => s.read(buf),
+            InputSource::Follow(f) => f.read(buf),
+        }
+    }
+}
+
+// only plain files can be resumed
+impl Seek for InputSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            InputSource::File(f) => f.seek(pos),
+            InputSource::Stdin(_) | InputSource::Follow(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "stream cannot be seeked",
+            )),
+        }
+    }
+}
+
+// transaction reader of any supported input format
+pub enum TransactionReader<R: BufRead> {
+    Csv(TransIterator<R>),
+    Json(JsonTransIterator<R>),
+}
+
+impl TransactionReader<BufReader<InputSource>> {
+    pub fn open(
+        path: &Path,
+        format: InputFormat,
+        amount_format: AmountFormat,
+    ) -> Result<Self, Box<dyn Error>> {
+        let reader = BufReader::new(InputSource::open(path)?);
+        TransactionReader::from_reader(reader, format, amount_format)
+    }
+
+    // keeps waiting for appended rows until `stop` is set, see FollowReader
+    pub fn follow(
+        path: &Path,
+        format: InputFormat,
+        amount_format: AmountFormat,
+        poll: Duration,
+        stop: Arc<AtomicBool>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut head = Vec::new();
+        File::open(path)?.take(SNIFF_LEN).read_to_end(&mut head)?;
+        let format = format.detect(&head);
+        let mut follow = FollowReader::open(path, poll, stop)?;
+        if format == InputFormat::Csv {
+            // rotated csv files start with the header again
+            follow = follow.skip_repeated_header();
+        }
+        let reader = BufReader::new(InputSource::Follow(follow));
+        TransactionReader::from_reader(reader, format, amount_format)
+    }
+
+    // continue reading after the record which ended at `position`
+    pub fn resume(
+        path: &Path,
+        format: InputFormat,
+        amount_format: AmountFormat,
+        position: InputPosition,
+    ) -> Result<Self, Box<dyn Error>> {
+        let reader = BufReader::new(InputSource::open(path)?);
+        TransactionReader::from_reader_at(reader, format, amount_format, position)
+    }
+}
+
+impl<R: BufRead> TransactionReader<R> {
+    // format is detected from the buffered start of `reader`, nothing is consumed
+    pub fn from_reader(
+        mut reader: R,
+        format: InputFormat,
+        amount_format: AmountFormat,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(match format.detect(reader.fill_buf()?) {
+            InputFormat::Json => {
+                TransactionReader::Json(JsonTransIterator::from_reader(reader, amount_format)?)
+            }
+            _ => TransactionReader::Csv(TransIterator::from_reader(reader, amount_format)?),
+        })
+    }
+
+    pub fn position(&self) -> InputPosition {
+        match self {
+            TransactionReader::Csv(iter) => iter.position(),
+            TransactionReader::Json(iter) => iter.position(),
+        }
+    }
+}
+
+impl<R: BufRead + Seek> TransactionReader<R> {
+    pub fn from_reader_at(
+        mut reader: R,
+        format: InputFormat,
+        amount_format: AmountFormat,
+        position: InputPosition,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(match format.detect(reader.fill_buf()?) {
+            InputFormat::Json => TransactionReader::Json(JsonTransIterator::from_reader_at(
+                reader,
+                amount_format,
+                position,
+            )?),
+            _ => TransactionReader::Csv(TransIterator::from_reader_at(
+                reader,
+                amount_format,
+                position,
+            )?),
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for TransactionReader<R> {
+    type Item = Result<TransactionRow, ParseDiagnostic>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            TransactionReader::Csv(iter) => iter.next(),
+            TransactionReader::Json(iter) => iter.next(),
+        }
+    }
+}
+
+#[derive(EnumString, Debug, Copy, Clone, PartialEq, Default)]
+#[strum(serialize_all = "snake_case")]
+pub enum InputOrder {
+    // as given, files of a directory or a pattern by file name, then by full path
+    #[default]
+    Name,
+    // by the RFC 3339 timestamp header of every file, files with the same one as given
+    Timestamp,
+}
+
+// expands directories (their files) and glob patterns, ie. "in/*.csv", a file listed
+// more than once (ie. a directory and a file in it) is read only the first time
+pub fn expand_inputs(paths: &[PathBuf], order: InputOrder) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut seen = HashSet::new();
+    for path in paths {
+        let pattern = path.to_string_lossy();
+        let mut expanded = Vec::new();
+        if path.is_dir() {
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                if entry.file_type()?.is_file() && !hidden {
+                    expanded.push(entry.path());
+                }
+            }
+        } else if pattern.contains(['*', '?', '[']) {
+            let matches = glob::glob(&pattern)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            for entry in matches {
+                expanded.push(entry.map_err(io::Error::from)?);
+            }
+        } else {
+            expanded.push(path.clone());
+        }
+        expanded.sort_by(|a, b| (a.file_name(), a).cmp(&(b.file_name(), b)));
+        for file in expanded {
+            // missing files are kept as given, opening them fails later
+            if seen.insert(fs::canonicalize(&file).unwrap_or_else(|_| file.clone())) {
+                files.push(file);
+            }
+        }
+    }
+    if files.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no input files"));
+    }
+
+    if order == InputOrder::Timestamp {
+        let mut stamped = files
+            .into_iter()
+            .map(|path| Ok((header_timestamp(&path)?, path)))
+            .collect::<io::Result<Vec<_>>>()?;
+        // stable, files with the same timestamp stay in the given order
+        stamped.sort_by_key(|(timestamp, _)| *timestamp);
+        files = stamped.into_iter().map(|(_, path)| path).collect();
+    }
+    Ok(files)
+}
+
+fn header_timestamp(path: &Path) -> io::Result<(i64, u32)> {
+    let mut line = String::new();
+    BufReader::new(File::open(path)?).read_line(&mut line)?;
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let timestamp = line
+        .trim_start_matches('\u{feff}')
+        .strip_prefix(TIMESTAMP_HEADER)
+        .map(str::trim)
+        .ok_or_else(|| invalid(format!("{} has no timestamp header", path.display())))?;
+    parse_rfc3339(timestamp).ok_or_else(|| {
+        invalid(format!(
+            "{} has an invalid RFC 3339 timestamp '{}'",
+            path.display(),
+            timestamp
+        ))
+    })
+}
+
+// seconds and nanoseconds since the unix epoch of an RFC 3339 date and time,
+// ie. "2024-05-01T10:00:00.25+02:00", None when it is not valid
+fn parse_rfc3339(s: &str) -> Option<(i64, u32)> {
+    let b = s.as_bytes();
+    if b.len() < 20
+        || !s.is_ascii()
+        || (b[4], b[7], b[13], b[16]) != (b'-', b'-', b':', b':')
+        || !matches!(b[10], b'T' | b't' | b' ')
+    {
+        return None;
+    }
+    let (year, month, day) = (digits(&s[0..4])?, digits(&s[5..7])?, digits(&s[8..10])?);
+    let (hour, minute, second) = (
+        digits(&s[11..13])?,
+        digits(&s[14..16])?,
+        digits(&s[17..19])?,
+    );
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    // second 60 is a leap second
+    if !(1..=month_days).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    let mut nanos = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        // digits below a nanosecond are dropped
+        let kept = len.min(9);
+        nanos = fraction[..kept].parse::<u32>().ok()? * 10u32.pow(9 - kept as u32);
+        rest = &fraction[len..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && &rest[3..4] == ":" => {
+            let (hours, minutes) = (digits(&rest[1..3])?, digits(&rest[4..6])?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            match &rest[..1] {
+                "+" => hours * 3600 + minutes * 60,
+                "-" => -(hours * 3600 + minutes * 60),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+
+    // days since the epoch of the proleptic gregorian calendar, years start in march
+    let y = if month <= 2 { year - 1 } else { year };
+    let (era, year_of_era) = (y.div_euclid(400), y.rem_euclid(400));
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Some((
+        days * 86_400 + hour * 3600 + minute * 60 + second - offset,
+        nanos,
+    ))
+}
+
+fn digits(s: &str) -> Option<i64> {
+    match s.bytes().all(|b| b.is_ascii_digit()) {
+        true => s.parse().ok(),
+        false => None,
+    }
+}
+
+// counts of rows read from one input, updated by shard workers
+#[derive(Debug, Default)]
+pub struct InputStats {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    ignored: AtomicU64,
+    malformed: AtomicU64,
+}
+
+impl InputStats {
+    pub fn record(&self, outcome: &Result<Outcome, AccountServiceError>) {
+        let counter = match outcome {
+            Ok(Outcome::Applied) | Ok(Outcome::Administered(_)) => &self.accepted,
+            Ok(Outcome::Ignored(_)) => &self.ignored,
+            Err(_) => &self.rejected,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_malformed(&self) {
+        self.malformed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // complete once the shards are joined
+    pub fn report(&self, path: &Path) -> InputReport {
+        InputReport {
+            file: path.display().to_string(),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            ignored: self.ignored.load(Ordering::Relaxed),
+            malformed: self.malformed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct InputReport {
+    pub file: String,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub ignored: u64,
+    pub malformed: u64,
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn sniff_input_format() {
+        assert_eq!(
+            InputFormat::Auto.detect(b"\n  {\"type\""),
+            InputFormat::Json
+        );
+        assert_eq!(
+            InputFormat::Auto.detect(b"\xef\xbb\xbf["),
+            InputFormat::Json
+        );
+        assert_eq!(InputFormat::Auto.detect(b"type,client"), InputFormat::Csv);
+        assert_eq!(InputFormat::Csv.detect(b"{"), InputFormat::Csv);
+        assert_eq!(
+            InputFormat::Auto.detect(b"# timestamp: 1\n{"),
+            InputFormat::Json
+        );
+    }
+
+    #[test]
+    fn read_any_stream() {
+        let json: &[u8] = b"{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1}\n";
+        let reader =
+            TransactionReader::from_reader(json, InputFormat::Auto, AmountFormat::default());
+        let v: Vec<_> = reader.unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(v[0].tx.amount, Some(Amount::from_units(1000)));
+
+        let csv: &[u8] = b"type,client,tx,amount\ndeposit,1,1,2.5\n";
+        let reader =
+            TransactionReader::from_reader(csv, InputFormat::Auto, AmountFormat::default());
+        let v: Vec<_> = reader.unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(v[0].tx.amount, Some(Amount::from_units(2500)));
+    }
+
+    #[test]
+    fn expand_and_order_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, ts: &str| {
+            let content = format!("{} {}\ntype,client,tx,amount\n", TIMESTAMP_HEADER, ts);
+            fs::write(dir.path().join(name), content).unwrap();
+        };
+        write("b.csv", "2024-05-01T09:00:00Z");
+        write("a.csv", "2024-05-01T10:00:00Z");
+        write("c.txt", "2024-05-01T08:00:00Z");
+        let names = |paths: Vec<PathBuf>| -> Vec<String> {
+            paths
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+
+        let all = expand_inputs(&[dir.path().to_path_buf()], InputOrder::Name).unwrap();
+        assert_eq!(names(all), vec!["a.csv", "b.csv", "c.txt"]);
+        let csv = expand_inputs(&[dir.path().join("*.csv")], InputOrder::Timestamp).unwrap();
+        assert_eq!(names(csv), vec!["b.csv", "a.csv"]);
+
+        // given order is kept, files listed twice are read once
+        let given = [
+            dir.path().join("c.txt"),
+            dir.path().to_path_buf(),
+            dir.path().join("./b.csv"),
+        ];
+        let given = expand_inputs(&given, InputOrder::Name).unwrap();
+        assert_eq!(names(given), vec!["c.txt", "a.csv", "b.csv"]);
+
+        // offsets and fractions are compared as instants
+        write("a.csv", "2024-05-01T10:29:59+02:00");
+        write("b.csv", "2024-05-01T08:30:00.5Z");
+        write("c.txt", "2024-05-01T08:30:00Z");
+        let all = expand_inputs(&[dir.path().to_path_buf()], InputOrder::Timestamp).unwrap();
+        assert_eq!(names(all), vec!["a.csv", "c.txt", "b.csv"]);
+
+        write("c.txt", "2024-02-30T08:30:00Z");
+        assert!(expand_inputs(&[dir.path().to_path_buf()], InputOrder::Timestamp).is_err());
+        fs::write(dir.path().join("d.csv"), "type,client,tx,amount\n").unwrap();
+        assert!(expand_inputs(&[dir.path().join("*.csv")], InputOrder::Timestamp).is_err());
+    }
+
+    #[test]
+    fn parse_timestamps() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some((0, 0)));
+        assert_eq!(
+            parse_rfc3339("2024-02-29t23:59:60.123456789123-01:30"),
+            Some((1_709_256_600, 123_456_789))
+        );
+        assert_eq!(
+            parse_rfc3339("1969-12-31 23:59:59.5z"),
+            Some((-1, 500_000_000))
+        );
+        for invalid in [
+            "2023-02-29T00:00:00Z",
+            "2024-05-01T10:00:00",
+            "2024-05-01T24:00:00Z",
+            "2024-05-01T10:00:00.Z",
+            "2024-05-01T10:00:00+0200",
+            "2024-5-01T10:00:00Z",
+            "2024-05-01T10:00:+0Z",
+        ] {
+            assert_eq!(parse_rfc3339(invalid), None, "{}", invalid);
+        }
+    }
+}
@@ -1,6 +1,24 @@
 pub mod account_service;
 pub mod account_service_shards;
+pub mod amount;
+pub mod audit;
+pub mod checkpoint;
+mod codec;
+pub mod diagnostics;
+pub mod follow;
+pub mod http;
+pub mod input;
+pub mod output;
+pub mod policy;
+pub mod rejections;
+pub mod server;
+pub mod snapshot;
 pub mod tx;
 pub mod tx_csv_iter;
+pub mod tx_dense_store;
+pub mod tx_disk_store;
+pub mod tx_ids;
+pub mod tx_json_iter;
 pub mod tx_processor;
 pub mod tx_service;
+pub mod wal;
@@ -1,39 +1,470 @@
-use tx::account_service_shards;
-use tx::tx_csv_iter;
-use std::io;
-use std::path::PathBuf;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
+use tx::account_service::AccountOrder;
+use tx::account_service_shards::{self, ShardsConfig};
+use tx::amount::{AmountFormat, Rounding};
+use tx::audit::AuditWriter;
+use tx::checkpoint::{Checkpoint, Checkpointer};
+use tx::diagnostics::{DiagnosticReporter, ErrorBudget, ParseDiagnostic};
+use tx::http::HttpServer;
+use tx::input::{self, InputFormat, InputOrder, InputStats, TransactionReader};
+use tx::output::{self, OutputFormat};
+use tx::policy::{DisputeShortfall, ProcessingPolicy, WithdrawalDisputes};
+use tx::rejections::RejectionWriter;
+use tx::server::{ListenAddr, Server};
+use tx::tx::{InputPosition, TransactionRow};
+use tx::tx_disk_store::DiskStoreConfig;
+use tx::tx_processor::TransactionProcessor;
+use tx::wal::{SyncPolicy, WalConfig};
 
 extern crate num_cpus;
 
 // questions / todo:
-// move to hold max available amount when available < disputed amount
-// or add hold_target to be increased by dispute when available < disputed amount and decreased when new deposit arrive?
 // store rejected transactions (better errors when dispute arrive)
-// allow to dispute withdrawals?
-// tests documenting expected behaviour in cases above
 
 #[derive(Debug, StructOpt)]
 struct Opt {
-    /// Input file
-    #[structopt(parse(from_os_str), help = "transactions.csv")]
-    input: PathBuf,
+    /// Input files, directories or glob patterns
+    #[structopt(
+        parse(from_os_str),
+        required_unless = "serve",
+        help = "transactions.csv, - for stdin, a directory or a pattern like 'in/*.csv'"
+    )]
+    input: Vec<PathBuf>,
+
+    /// Order of input files: name (as given) or timestamp (from the '# timestamp:' first line)
+    #[structopt(long, default_value = "name")]
+    input_order: InputOrder,
+
+    /// Write per-file counts of accepted, rejected, ignored and malformed rows (in --format)
+    #[structopt(long, parse(from_os_str))]
+    input_report: Option<PathBuf>,
+
+    /// Input format: csv, json (ndjson or array) or auto to detect it from the content
+    #[structopt(long, default_value = "auto")]
+    input_format: InputFormat,
+
+    /// Number of decimal places of amounts
+    #[structopt(long, default_value = "3")]
+    scale: u32,
+
+    /// Handling of amounts with more decimal places than scale: reject, truncate or half-even
+    #[structopt(long, default_value = "reject")]
+    rounding: Rounding,
+
+    /// Business rules (toml), ie. `locked-accounts = "allow-disputes"`, see the readme
+    #[structopt(long, parse(from_os_str))]
+    policy: Option<PathBuf>,
+
+    /// Disputes of withdrawals: reject, or reverse (held until resolve, returned by chargeback),
+    /// overrides the policy file
+    #[structopt(long)]
+    withdrawal_disputes: Option<WithdrawalDisputes>,
+
+    /// Disputes of more than available funds: reject, hold-target (held from later deposits)
+    /// or negative (available goes below zero), overrides the policy file
+    #[structopt(long)]
+    dispute_shortfall: Option<DisputeShortfall>,
+
+    /// Number of shards (worker threads), defaults to number of cpus
+    #[structopt(long)]
+    shards: Option<usize>,
+
+    /// Directory of the write-ahead log, shard state is recovered from it on start
+    #[structopt(long, parse(from_os_str))]
+    wal: Option<PathBuf>,
+
+    /// Write-ahead log fsync policy: always, on-join or number of records
+    #[structopt(long, default_value = "1000")]
+    wal_sync: SyncPolicy,
+
+    /// Directory to keep transaction history on disk instead of in memory (kept when the
+    /// write-ahead log has records, cleared on start otherwise)
+    #[structopt(long, parse(from_os_str))]
+    tx_store: Option<PathBuf>,
+
+    /// Keep transactions packed in a paged array indexed by id (16 bytes per id, for dense ids)
+    #[structopt(long, conflicts_with = "tx-store")]
+    tx_dense: bool,
+
+    /// Number of transactions per shard cached in memory by --tx-store
+    #[structopt(long, default_value = "1000000")]
+    tx_cache: usize,
+
+    /// Snapshot of engine state to start from (ie. yesterday's state)
+    #[structopt(long, parse(from_os_str))]
+    load_snapshot: Option<PathBuf>,
+
+    /// Write snapshot of engine state after processing input
+    #[structopt(long, parse(from_os_str))]
+    save_snapshot: Option<PathBuf>,
+
+    /// Checkpoint file, input position and engine snapshot are persisted periodically
+    #[structopt(long, parse(from_os_str), conflicts_with = "wal")]
+    checkpoint: Option<PathBuf>,
+
+    /// Number of input records between checkpoints
+    #[structopt(long, default_value = "1000000")]
+    checkpoint_every: u64,
+
+    /// Continue from the last checkpoint instead of the first row of input
+    #[structopt(long, requires = "checkpoint", conflicts_with = "load-snapshot")]
+    resume: bool,
+
+    /// Write rejected and ignored transactions with reason codes to this file
+    #[structopt(long, parse(from_os_str))]
+    rejections: Option<PathBuf>,
+
+    /// Format of the rejections file: csv, json or ndjson
+    #[structopt(long, default_value = "csv")]
+    rejections_format: OutputFormat,
+
+    /// Write the audit trail of administration operations (freeze, unlock, close) to this file
+    #[structopt(long, parse(from_os_str))]
+    audit: Option<PathBuf>,
+
+    /// Format of the audit file: csv, json or ndjson
+    #[structopt(long, default_value = "csv")]
+    audit_format: OutputFormat,
+
+    /// Write parse errors of malformed rows to this file instead of stderr
+    #[structopt(long, parse(from_os_str))]
+    parse_errors: Option<PathBuf>,
+
+    /// Abort with non-zero exit code when more than N rows (or X%) are malformed
+    #[structopt(long)]
+    error_budget: Option<ErrorBudget>,
+
+    /// Order of output accounts: client, total (largest first) or locked (locked first)
+    #[structopt(long, default_value = "client")]
+    order: AccountOrder,
+
+    /// Format of the account output: csv, json or ndjson
+    #[structopt(long, default_value = "csv")]
+    format: OutputFormat,
+
+    /// Keep reading rows appended to the input file (like tail -F) until interrupted
+    #[structopt(long, conflicts_with = "checkpoint")]
+    follow: bool,
+
+    /// Interval of checking the followed file for new rows, in milliseconds
+    #[structopt(long, default_value = "200")]
+    follow_poll_ms: u64,
+
+    /// Print current balances every N seconds while processing
+    #[structopt(long)]
+    output_every: Option<u64>,
+
+    /// Accept csv or ndjson transactions from clients on host:port or unix:/path until interrupted
+    #[structopt(long, conflicts_with_all = &["input", "follow", "checkpoint"])]
+    serve: Option<ListenAddr>,
+
+    /// Answer json queries of balances and transaction state on host:port while running
+    #[structopt(long)]
+    http: Option<String>,
+}
+
+enum Event {
+    // input row and the position after it
+    Row(Result<TransactionRow, ParseDiagnostic>, InputPosition),
+    // no row arrived for a while
+    Idle,
+}
+
+// rows of a followed file are read on a separate thread, so the main thread can print
+// balances while the reader waits for the file to grow
+fn follow_events<R>(mut reader: TransactionReader<R>, tick: Duration) -> impl Iterator<Item = Event>
+where
+    R: BufRead + Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel(1024);
+    thread::spawn(move || {
+        while let Some(item) = reader.next() {
+            if sender.send(Event::Row(item, reader.position())).is_err() {
+                break;
+            }
+        }
+    });
+    std::iter::from_fn(move || match receiver.recv_timeout(tick) {
+        Ok(event) => Some(event),
+        Err(mpsc::RecvTimeoutError::Timeout) => Some(Event::Idle),
+        Err(mpsc::RecvTimeoutError::Disconnected) => None,
+    })
 }
 
 fn main() {
     let opt = Opt::from_args();
 
-    let mut shards = account_service_shards::AccountShards::new(num_cpus::get());
-    shards.run();
-    let iter = tx_csv_iter::TransIterator::new(&opt.input).expect("Cannot open input file");
-    iter.for_each(|tx| shards.process(tx));
-    shards.join();
+    // served transactions are the only input
+    let inputs = match opt.serve {
+        Some(_) => Vec::new(),
+        None => input::expand_inputs(&opt.input, opt.input_order).expect("Cannot list input files"),
+    };
+    if opt.checkpoint.is_some() && (inputs.len() != 1 || inputs[0] == Path::new("-")) {
+        eprintln!("Checkpoints need a single input file, stdin cannot be resumed");
+        std::process::exit(1);
+    }
+    if opt.follow && (inputs.len() != 1 || inputs[0] == Path::new("-")) {
+        eprintln!("Follow mode needs a single input file");
+        std::process::exit(1);
+    }
+    // followed input and the server end on SIGINT or SIGTERM, then the run finishes as usual
+    let stop = Arc::new(AtomicBool::new(false));
+    if opt.follow || opt.serve.is_some() {
+        for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+            signal_hook::flag::register(signal, Arc::clone(&stop))
+                .expect("Cannot register signal handler");
+        }
+    }
+
+    let amount_format = AmountFormat::new(opt.scale, opt.rounding).expect("Invalid amount scale");
+    let wal = opt.wal.as_ref().map(|dir| {
+        std::fs::create_dir_all(dir).expect("Cannot create write-ahead log directory");
+        WalConfig {
+            dir: dir.clone(),
+            sync: opt.wal_sync,
+        }
+    });
+    let mut policy = opt
+        .policy
+        .as_ref()
+        .map_or_else(ProcessingPolicy::default, |path| {
+            ProcessingPolicy::load(path).expect("Cannot read policy file")
+        });
+    if let Some(withdrawal_disputes) = opt.withdrawal_disputes {
+        policy.withdrawal_disputes = withdrawal_disputes;
+    }
+    if let Some(dispute_shortfall) = opt.dispute_shortfall {
+        policy.dispute_shortfall = dispute_shortfall;
+    }
+    let rejection_writer = opt.rejections.as_ref().map(|path| {
+        let file = std::fs::File::create(path).expect("Cannot create rejections file");
+        RejectionWriter::spawn(file, opt.rejections_format)
+    });
+    let audit_writer = opt.audit.as_ref().map(|path| {
+        let file = std::fs::File::create(path).expect("Cannot create audit file");
+        AuditWriter::spawn(file, opt.audit_format)
+    });
+    let config = ShardsConfig {
+        amount_format,
+        wal,
+        rejections: rejection_writer.as_ref().map(RejectionWriter::sender),
+        audit: audit_writer.as_ref().map(AuditWriter::sender),
+        processor: TransactionProcessor { policy },
+        disk_store: opt.tx_store.as_ref().map(|dir| DiskStoreConfig {
+            dir: dir.clone(),
+            cache: opt.tx_cache,
+        }),
+        dense_store: opt.tx_dense,
+    };
+
+    let server = opt.serve.as_ref().map(|addr| {
+        Server::bind(addr, amount_format, Arc::clone(&stop)).expect("Cannot listen on address")
+    });
+
+    let http = opt.http.as_ref().map(|addr| {
+        HttpServer::bind(addr, amount_format, Arc::clone(&stop)).expect("Cannot listen on address")
+    });
 
-    let mut writer = csv::Writer::from_writer(io::stdout());
-    for account_service in shards.account_services {
-        for account in account_service.lock().unwrap().iter() {
-            writer.serialize(account).expect("Account serialize error");
+    let shard_count = opt.shards.unwrap_or_else(num_cpus::get);
+    let mut shards = account_service_shards::AccountShards::with_config(shard_count, config);
+    if let Some(path) = &opt.load_snapshot {
+        shards.load_snapshot(path).expect("Cannot load snapshot");
+    }
+
+    let (mut checkpointer, mut resume_at) = match (&opt.checkpoint, opt.resume) {
+        (Some(path), true) => {
+            let checkpoint = Checkpoint::load(path).expect("Cannot load checkpoint");
+            checkpoint
+                .verify(&inputs[0])
+                .expect("Checkpoint does not match input");
+            shards
+                .load_snapshot(&checkpoint.snapshot)
+                .expect("Cannot load checkpoint snapshot");
+            let position = checkpoint.position;
+            let checkpointer = Checkpointer::resumed(path, opt.checkpoint_every, checkpoint);
+            (Some(checkpointer), Some(position))
+        }
+        (path, _) => {
+            let checkpointer = path
+                .as_ref()
+                .map(|path| Checkpointer::new(path, &inputs[0], opt.checkpoint_every));
+            (checkpointer, None)
         }
+    };
+
+    let mut diagnostics = match &opt.parse_errors {
+        Some(path) => {
+            let file = std::fs::File::create(path).expect("Cannot create parse errors file");
+            DiagnosticReporter::new(Box::new(io::BufWriter::new(file)), opt.error_budget)
+        }
+        None => DiagnosticReporter::stderr(opt.error_budget),
+    };
+
+    if let Err(e) = shards.run() {
+        let _ = shards.join();
+        eprintln!("Aborted: {}", e);
+        std::process::exit(4);
+    }
+    let http = http.map(|http| {
+        let handle = shards.handle();
+        thread::spawn(move || http.serve(handle))
+    });
+    if let Some(server) = server {
+        server.serve(shards.handle()).expect("Server error");
     }
-    writer.flush().expect("Print output error");
+    let mut budget = Ok(());
+    // an input which cannot be read stops the run, it is not a malformed row
+    let mut read_error = None;
+    // a shard which cannot log or report a transaction stops the run
+    let mut shard_error = None;
+    let mut input_stats = Vec::with_capacity(inputs.len());
+    let mut end = resume_at.unwrap_or_default();
+    let output_every = opt.output_every.map(Duration::from_secs);
+    let mut next_output = output_every.map(|interval| Instant::now() + interval);
+    for path in &inputs {
+        let stats = Arc::new(InputStats::default());
+        input_stats.push((path, Arc::clone(&stats)));
+        let events: Box<dyn Iterator<Item = Event>> = if opt.follow {
+            let poll = Duration::from_millis(opt.follow_poll_ms);
+            let reader = TransactionReader::follow(
+                path,
+                opt.input_format,
+                shards.amount_format(),
+                poll,
+                Arc::clone(&stop),
+            )
+            .expect("Cannot open input file");
+            Box::new(follow_events(reader, output_every.unwrap_or(poll)))
+        } else {
+            let iter = match resume_at.take() {
+                Some(position) => TransactionReader::resume(
+                    path,
+                    opt.input_format,
+                    shards.amount_format(),
+                    position,
+                ),
+                None => TransactionReader::open(path, opt.input_format, shards.amount_format()),
+            };
+            let mut iter = iter.expect("Cannot open input file");
+            Box::new(std::iter::from_fn(move || {
+                let item = iter.next()?;
+                Some(Event::Row(item, iter.position()))
+            }))
+        };
+
+        for event in events {
+            if let Event::Row(item, position) = event {
+                match item {
+                    Ok(row) => {
+                        diagnostics.record_valid();
+                        if let Err(e) = shards.process_counted(row, &stats) {
+                            shard_error = Some(e);
+                            break;
+                        }
+                    }
+                    Err(diagnostic) if diagnostic.fatal => {
+                        read_error = Some((path, diagnostic));
+                        break;
+                    }
+                    Err(diagnostic) => {
+                        stats.record_malformed();
+                        diagnostics
+                            .report(&diagnostic)
+                            .expect("Write parse errors error")
+                    }
+                }
+                budget = diagnostics.check(false);
+                if budget.is_err() {
+                    break;
+                }
+                if let Some(checkpointer) = checkpointer.as_mut() {
+                    checkpointer
+                        .record_processed(&shards, position)
+                        .expect("Cannot write checkpoint");
+                }
+                end = position;
+            }
+            if let (Some(at), Some(interval)) = (next_output, output_every) {
+                if Instant::now() >= at {
+                    let accounts = shards.copy_accounts().expect("Cannot copy accounts");
+                    output::write_all(io::stdout(), opt.format, accounts.sorted(opt.order))
+                        .expect("Print output error");
+                    next_output = Some(Instant::now() + interval);
+                }
+            }
+        }
+        if budget.is_err() || read_error.is_some() || shard_error.is_some() {
+            break;
+        }
+    }
+    let budget = budget.and_then(|_| diagnostics.check(true));
+    // whole input is processed, resume would continue at its end
+    if let (Some(checkpointer), Ok(_), None, None) =
+        (checkpointer.as_mut(), &budget, &read_error, &shard_error)
+    {
+        checkpointer
+            .save(&shards, end)
+            .expect("Cannot write checkpoint");
+    }
+    // queries are answered by the workers, so the http server stops before them
+    if let Some(http) = http {
+        stop.store(true, Ordering::Relaxed);
+        http.join()
+            .expect("Http server panicked")
+            .expect("Http server error");
+    }
+    if let Err(e) = shards.join() {
+        shard_error.get_or_insert(e);
+    }
+    // a writer which failed stopped the shards, its error tells why
+    if let Some(writer) = rejection_writer {
+        if let Err(e) = writer.finish() {
+            shard_error = Some(io::Error::new(
+                e.kind(),
+                format!("cannot write rejections: {}", e),
+            ));
+        }
+    }
+    if let Some(writer) = audit_writer {
+        if let Err(e) = writer.finish() {
+            shard_error = Some(io::Error::new(
+                e.kind(),
+                format!("cannot write audit: {}", e),
+            ));
+        }
+    }
+    diagnostics.flush().expect("Write parse errors error");
+    if let Some(path) = &opt.input_report {
+        let file = std::fs::File::create(path).expect("Cannot create input report");
+        let reports = input_stats.iter().map(|(path, stats)| stats.report(path));
+        output::write_all(file, opt.format, reports).expect("Write input report error");
+    }
+    if let Some(e) = shard_error {
+        eprintln!("Aborted: {}", e);
+        std::process::exit(4);
+    }
+    if let Some((path, diagnostic)) = read_error {
+        eprintln!("Aborted: cannot read {}: {}", path.display(), diagnostic);
+        std::process::exit(3);
+    }
+    if let Err(exceeded) = budget {
+        eprintln!("Aborted: {}", exceeded);
+        std::process::exit(2);
+    }
+    if let Some(path) = &opt.save_snapshot {
+        shards.save_snapshot(path).expect("Cannot save snapshot");
+    }
+
+    output::write_all(
+        io::stdout(),
+        opt.format,
+        shards.lock_accounts().sorted(opt.order),
+    )
+    .expect("Print output error");
 }
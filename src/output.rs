@@ -0,0 +1,129 @@
+use serde::Serialize;
+use std::io::{self, Write};
+use strum_macros::EnumString;
+
+#[derive(EnumString, Debug, Copy, Clone, PartialEq, Default)]
+#[strum(serialize_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    // single array, one record per line
+    Json,
+    // one object per line
+    Ndjson,
+}
+
+// streams serializable records (accounts, rejections) in the chosen format,
+// amounts are serialized as exact decimal strings by FormattedAmount
+pub enum RecordWriter<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Json { out: io::BufWriter<W>, first: bool },
+    Ndjson(io::BufWriter<W>),
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(writer: W, format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Csv => RecordWriter::Csv(Box::new(csv::Writer::from_writer(writer))),
+            OutputFormat::Json => RecordWriter::Json {
+                out: io::BufWriter::new(writer),
+                first: true,
+            },
+            OutputFormat::Ndjson => RecordWriter::Ndjson(io::BufWriter::new(writer)),
+        }
+    }
+
+    pub fn write<T: Serialize>(&mut self, record: &T) -> io::Result<()> {
+        match self {
+            RecordWriter::Csv(w) => w.serialize(record).map_err(io::Error::from),
+            RecordWriter::Json { out, first } => {
+                out.write_all(if *first { b"[\n" } else { b",\n" })?;
+                *first = false;
+                serde_json::to_writer(&mut *out, record)?;
+                Ok(())
+            }
+            RecordWriter::Ndjson(w) => {
+                serde_json::to_writer(&mut *w, record)?;
+                w.write_all(b"\n")
+            }
+        }
+    }
+
+    // closes the json array (empty array when nothing was written) and flushes
+    pub fn finish(mut self) -> io::Result<()> {
+        match &mut self {
+            RecordWriter::Csv(w) => w.flush(),
+            RecordWriter::Json { out, first } => {
+                out.write_all(if *first { b"[]\n" } else { b"\n]\n" })?;
+                out.flush()
+            }
+            RecordWriter::Ndjson(w) => w.flush(),
+        }
+    }
+}
+
+// writes all records, ie. shards.lock_accounts().sorted(order)
+pub fn write_all<W, T, I>(writer: W, format: OutputFormat, records: I) -> io::Result<()>
+where
+    W: Write,
+    T: Serialize,
+    I: IntoIterator<Item = T>,
+{
+    let mut writer = RecordWriter::new(writer, format);
+    for record in records {
+        writer.write(&record)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::account_service::{Account, AccountResult};
+    use crate::amount::AmountFormat;
+    use crate::tx::*;
+
+    fn accounts() -> Vec<AccountResult> {
+        let format = AmountFormat::default();
+        vec![
+            AccountResult::new(&Account::new(1, Asset::default(), Amount::ZERO), format),
+            AccountResult::new(
+                &Account::new(2, "EUR".parse().unwrap(), Amount::from_units(1500)),
+                format,
+            ),
+        ]
+    }
+
+    fn output(format: OutputFormat, records: Vec<AccountResult>) -> String {
+        let mut buf = Vec::new();
+        write_all(&mut buf, format, records).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn write_json() {
+        assert_eq!(
+            output(OutputFormat::Json, accounts()),
+            "[\n\
+             {\"client\":1,\"asset\":\"\",\"available\":\"0.000\",\"held\":\"0.000\",\"total\":\"0.000\",\"locked\":false,\"status\":\"active\"},\n\
+             {\"client\":2,\"asset\":\"EUR\",\"available\":\"1.500\",\"held\":\"0.000\",\"total\":\"1.500\",\"locked\":false,\"status\":\"active\"}\n\
+             ]\n"
+        );
+        assert_eq!(output(OutputFormat::Json, Vec::new()), "[]\n");
+    }
+
+    #[test]
+    fn write_ndjson_and_csv() {
+        let ndjson = output(OutputFormat::Ndjson, accounts());
+        assert_eq!(ndjson.lines().count(), 2);
+        assert!(ndjson.ends_with("\"total\":\"1.500\",\"locked\":false,\"status\":\"active\"}\n"));
+
+        assert_eq!(
+            output(OutputFormat::Csv, accounts()),
+            "client,asset,available,held,total,locked,status\n\
+             1,,0.000,0.000,0.000,false,active\n\
+             2,EUR,1.500,0.000,1.500,false,active\n"
+        );
+    }
+}
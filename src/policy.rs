@@ -0,0 +1,159 @@
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::Path;
+use strum_macros::EnumString;
+
+// handling of disputes which refer to a withdrawal
+#[derive(EnumString, Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum WithdrawalDisputes {
+    // dispute fails with dispute_wrong_transaction_type
+    #[default]
+    Reject,
+    // withdrawn amount is held until resolve (withdrawal stands)
+    // or chargeback (withdrawal is reversed into available funds)
+    Reverse,
+}
+
+// dispute of more than the available funds, ie. the deposit was already withdrawn
+#[derive(EnumString, Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum DisputeShortfall {
+    // dispute fails with insufficient_balance
+    #[default]
+    Reject,
+    // available funds are held, the rest is held from later deposits
+    HoldTarget,
+    // whole amount is held, available goes below zero until deposits pay it back
+    Negative,
+}
+
+// dispute without amount of an already disputed transaction
+#[derive(EnumString, Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum RepeatDisputes {
+    // skipped as already_disputed
+    #[default]
+    Ignore,
+    // fails with already_disputed
+    Reject,
+}
+
+// transactions of an account locked by a chargeback
+#[derive(EnumString, Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum LockedAccounts {
+    // everything fails with account_locked until unlock
+    #[default]
+    RejectAll,
+    // disputes, resolves and chargebacks are still processed, like on a frozen account
+    AllowDisputes,
+}
+
+// dispute of a transaction whose earlier dispute was resolved (or partially charged back)
+#[derive(EnumString, Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum ResolvedDisputes {
+    #[default]
+    Allow,
+    // fails with already_resolved
+    Reject,
+}
+
+// business rules of TransactionProcessor, the defaults are the rules listed in the readme,
+// a toml file can set any of them (kebab-case keys and values):
+//   withdrawal-disputes = "reverse"
+//   locked-accounts = "allow-disputes"
+#[derive(Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ProcessingPolicy {
+    pub withdrawal_disputes: WithdrawalDisputes,
+    pub dispute_shortfall: DisputeShortfall,
+    pub repeat_disputes: RepeatDisputes,
+    pub locked_accounts: LockedAccounts,
+    pub resolved_disputes: ResolvedDisputes,
+}
+
+impl ProcessingPolicy {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        ProcessingPolicy::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(config: &str) -> io::Result<Self> {
+        toml::from_str(config).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // every rule in 4 bits, stored with the write-ahead log
+    pub fn fingerprint(&self) -> u32 {
+        self.withdrawal_disputes as u32
+            | (self.dispute_shortfall as u32) << 4
+            | (self.repeat_disputes as u32) << 8
+            | (self.locked_accounts as u32) << 12
+            | (self.resolved_disputes as u32) << 16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn parse_policy() {
+        let policy = ProcessingPolicy::parse(
+            "withdrawal-disputes = \"reverse\"\n\
+             locked-accounts = \"allow-disputes\"\n",
+        )
+        .unwrap();
+        assert_eq!(policy.withdrawal_disputes, WithdrawalDisputes::Reverse);
+        assert_eq!(policy.locked_accounts, LockedAccounts::AllowDisputes);
+        assert_eq!(policy.dispute_shortfall, DisputeShortfall::Reject);
+        assert_eq!(ProcessingPolicy::parse("").unwrap(), Default::default());
+    }
+
+    #[test]
+    fn fingerprint_of_every_rule() {
+        assert_eq!(ProcessingPolicy::default().fingerprint(), 0);
+        let mut policies = Vec::new();
+        for withdrawal_disputes in [WithdrawalDisputes::Reject, WithdrawalDisputes::Reverse] {
+            for dispute_shortfall in [
+                DisputeShortfall::Reject,
+                DisputeShortfall::HoldTarget,
+                DisputeShortfall::Negative,
+            ] {
+                for repeat_disputes in [RepeatDisputes::Ignore, RepeatDisputes::Reject] {
+                    for locked_accounts in
+                        [LockedAccounts::RejectAll, LockedAccounts::AllowDisputes]
+                    {
+                        for resolved_disputes in [ResolvedDisputes::Allow, ResolvedDisputes::Reject]
+                        {
+                            policies.push(ProcessingPolicy {
+                                withdrawal_disputes,
+                                dispute_shortfall,
+                                repeat_disputes,
+                                locked_accounts,
+                                resolved_disputes,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        // every combination of the rules has its own fingerprint
+        let fingerprints: HashSet<_> = policies.iter().map(|p| p.fingerprint()).collect();
+        assert_eq!(fingerprints.len(), 2 * 3 * 2 * 2 * 2);
+    }
+
+    #[test]
+    fn unknown_rules_are_refused() {
+        assert!(ProcessingPolicy::parse("repeat-dispute = \"reject\"").is_err());
+        assert!(ProcessingPolicy::parse("repeat-disputes = \"skip\"").is_err());
+    }
+}
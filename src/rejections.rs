@@ -0,0 +1,182 @@
+use crate::account_service::AccountServiceError;
+use crate::amount::{AmountFormat, FormattedAmount};
+use crate::output::{OutputFormat, RecordWriter};
+use crate::tx::*;
+
+use futures_lite::future;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::thread;
+
+// rejections are buffered between shard workers and the writer thread
+const CHANNEL_CAP: usize = 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionKind {
+    // transaction failed
+    Error,
+    // transaction was deliberately skipped (ie. dispute of disputed transaction)
+    Ignored,
+}
+
+// rejected or ignored transaction with its original fields
+#[derive(Debug, Serialize)]
+pub struct Rejection {
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub amount: Option<FormattedAmount>,
+    pub asset: Asset,
+    pub shard: usize,
+    pub reason: &'static str,
+    pub kind: RejectionKind,
+}
+
+impl Rejection {
+    pub fn new(
+        tx: &Transaction,
+        shard: usize,
+        reason: &AccountServiceError,
+        kind: RejectionKind,
+        format: AmountFormat,
+    ) -> Self {
+        Self {
+            tx_type: tx.tx_type,
+            client: tx.client_id,
+            tx: tx.tx_id,
+            amount: tx.amount.map(|a| format.display(a)),
+            asset: tx.asset,
+            shard,
+            reason: reason.code(),
+            kind,
+        }
+    }
+}
+
+// writes rejections sent by shard workers on a separate thread
+pub struct RejectionWriter {
+    sender: async_channel::Sender<Rejection>,
+    handle: thread::JoinHandle<io::Result<()>>,
+}
+
+impl RejectionWriter {
+    pub fn spawn<W: Write + Send + 'static>(writer: W, format: OutputFormat) -> Self {
+        let (sender, receiver) = async_channel::bounded::<Rejection>(CHANNEL_CAP);
+        let handle = thread::spawn(move || {
+            let mut out = RecordWriter::new(writer, format);
+            while let Ok(rejection) = future::block_on(receiver.recv()) {
+                out.write(&rejection)?;
+            }
+            out.finish()
+        });
+        Self { sender, handle }
+    }
+
+    pub fn sender(&self) -> async_channel::Sender<Rejection> {
+        self.sender.clone()
+    }
+
+    // rejections already sent are still written
+    pub fn finish(self) -> io::Result<()> {
+        self.sender.close();
+        self.handle.join().expect("Rejection writer panicked")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // Write handle to a buffer shared with the test
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn content(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn rejections() -> Vec<Rejection> {
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client_id: 2,
+            tx_id: 7,
+            amount: Some(Amount::from_units(1500)),
+            asset: "EUR".parse().unwrap(),
+        };
+        let resolve = Transaction {
+            tx_type: TransactionType::Resolve,
+            amount: None,
+            asset: Asset::default(),
+            ..deposit
+        };
+        let format = AmountFormat::default();
+        vec![
+            Rejection::new(
+                &deposit,
+                1,
+                &AccountServiceError::TransactionDuplicate,
+                RejectionKind::Error,
+                format,
+            ),
+            Rejection::new(
+                &resolve,
+                1,
+                &AccountServiceError::NotDisputed,
+                RejectionKind::Ignored,
+                format,
+            ),
+        ]
+    }
+
+    #[test]
+    fn write_csv() {
+        let buf = SharedBuf::default();
+        let writer = RejectionWriter::spawn(buf.clone(), OutputFormat::Csv);
+        for r in rejections() {
+            future::block_on(writer.sender().send(r)).unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(
+            buf.content(),
+            "type,client,tx,amount,asset,shard,reason,kind\n\
+             deposit,2,7,1.500,EUR,1,transaction_duplicate,error\n\
+             resolve,2,7,,,1,not_disputed,ignored\n"
+        );
+    }
+
+    #[test]
+    fn write_ndjson() {
+        let buf = SharedBuf::default();
+        let writer = RejectionWriter::spawn(buf.clone(), OutputFormat::Ndjson);
+        for r in rejections() {
+            future::block_on(writer.sender().send(r)).unwrap();
+        }
+        writer.finish().unwrap();
+        let content = buf.content();
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"{"type":"deposit","client":2,"tx":7,"amount":"1.500","asset":"EUR","shard":1,"reason":"transaction_duplicate","kind":"error"}"#
+        );
+        assert_eq!(
+            lines[1],
+            r#"{"type":"resolve","client":2,"tx":7,"amount":null,"asset":"","shard":1,"reason":"not_disputed","kind":"ignored"}"#
+        );
+    }
+}
@@ -0,0 +1,370 @@
+use crate::account_service::AccountServiceError;
+use crate::account_service_shards::ShardsHandle;
+use crate::amount::AmountFormat;
+use crate::input::{InputFormat, TransactionReader};
+use crate::tx_processor::Outcome;
+
+use std::error::Error;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+// interval of checking the stop flag while no client connects
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+// rows of a connection waiting for their ack, the reader blocks when there are more
+const PENDING_ACKS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    // host:port
+    Tcp(String),
+    // unix:/path/to/socket
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            #[cfg(not(unix))]
+            Some(_) => Err("unix sockets are not supported on this platform".to_string()),
+            None => Ok(ListenAddr::Tcp(s.to_string())),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    // non-blocking, so the accept loop can check the stop flag
+    fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        let listener = match addr {
+            ListenAddr::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr.as_str())?),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => Listener::Unix(UnixListener::bind(path)?, path.clone()),
+        };
+        match &listener {
+            Listener::Tcp(l) => l.set_nonblocking(true)?,
+            #[cfg(unix)]
+            Listener::Unix(l, _) => l.set_nonblocking(true)?,
+        }
+        Ok(listener)
+    }
+
+    fn accept(&self) -> io::Result<Stream> {
+        let stream = match self {
+            Listener::Tcp(l) => Stream::Tcp(l.accept()?.0),
+            #[cfg(unix)]
+            Listener::Unix(l, _) => Stream::Unix(l.accept()?.0),
+        };
+        // accepted socket may inherit non-blocking mode of the listener
+        match &stream {
+            Stream::Tcp(s) => s.set_nonblocking(false)?,
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_nonblocking(false)?,
+        }
+        Ok(stream)
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Stream::Tcp(s) => Stream::Tcp(s.try_clone()?),
+            #[cfg(unix)]
+            Stream::Unix(s) => Stream::Unix(s.try_clone()?),
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+// clients stream csv (with a header line) or ndjson transactions, every row is acknowledged
+// with its outcome, transactions go to the running shards like rows of an input file
+pub struct Server {
+    listener: Listener,
+    amount_format: AmountFormat,
+    // no new connections are accepted once set, open connections stop reading
+    stop: Arc<AtomicBool>,
+}
+
+impl Server {
+    pub fn bind(
+        addr: &ListenAddr,
+        amount_format: AmountFormat,
+        stop: Arc<AtomicBool>,
+    ) -> io::Result<Self> {
+        Ok(Server {
+            listener: Listener::bind(addr)?,
+            amount_format,
+            stop,
+        })
+    }
+
+    // bound address of a tcp listener, ie. the port chosen for port 0
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(l) => l.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+        }
+    }
+
+    // returns after stop is set and every received row is passed to the shards and acked,
+    // shards.join() then drains the shard channels
+    pub fn serve(self, shards: ShardsHandle) -> io::Result<()> {
+        let mut connections: Vec<(Stream, thread::JoinHandle<()>)> = Vec::new();
+        while !self.stop.load(Ordering::Relaxed) {
+            match self.listener.accept() {
+                Ok(stream) => {
+                    let control = stream.try_clone()?;
+                    let shards = shards.clone();
+                    let amount_format = self.amount_format;
+                    let handle = thread::spawn(move || {
+                        if let Err(e) = serve_connection(stream, &shards, amount_format) {
+                            eprintln!("Connection error: {}", e);
+                        }
+                    });
+                    connections.push((control, handle));
+                }
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        eprintln!("Accept error: {}", e);
+                    }
+                    thread::sleep(ACCEPT_POLL);
+                }
+            }
+            connections.retain(|(_, handle)| !handle.is_finished());
+        }
+
+        // rows already received are still processed and acked
+        for (control, _) in &connections {
+            let _ = control.shutdown(Shutdown::Read);
+        }
+        for (_, handle) in connections {
+            handle.join().expect("Connection thread panicked");
+        }
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = &self.listener {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+// rows are acked in the order they were received, one line per row (header excluded):
+// `<row> applied`, `<row> ignored <code>`, `<row> rejected <code>` or `<row> malformed <error>`
+fn serve_connection(
+    stream: Stream,
+    shards: &ShardsHandle,
+    amount_format: AmountFormat,
+) -> Result<(), Box<dyn Error>> {
+    let (sender, receiver) = mpsc::sync_channel(PENDING_ACKS);
+    let writer = {
+        let stream = stream.try_clone()?;
+        thread::spawn(move || write_acks(stream, receiver))
+    };
+    let reader =
+        TransactionReader::from_reader(BufReader::new(stream), InputFormat::Auto, amount_format)?;
+    for (row, item) in (1..).zip(reader) {
+        // slot of the row's ack, sending into it never blocks a shard
+        let (slot, pending) = mpsc::sync_channel(1);
+        match item {
+            Ok(row) => {
+                shards.process_acked(row, move |outcome| {
+                    // client is gone, the transaction is processed anyway
+                    let _ = slot.send(ack(outcome));
+                })?;
+            }
+            Err(diagnostic) => {
+                let _ = slot.send(format!("malformed {}", diagnostic));
+            }
+        }
+        // blocks while the client does not read its acks, a failed writer ends reading
+        // and its error is returned below
+        if sender.send((row, pending)).is_err() {
+            break;
+        }
+    }
+    // writer ends once the last pending ack is sent
+    drop(sender);
+    writer.join().expect("Ack writer panicked")?;
+    Ok(())
+}
+
+fn ack(outcome: &Result<Outcome, AccountServiceError>) -> String {
+    match outcome {
+        Ok(Outcome::Applied) | Ok(Outcome::Administered(_)) => "applied".to_string(),
+        Ok(Outcome::Ignored(reason)) => format!("ignored {}", reason.code()),
+        Err(err) => format!("rejected {}", err.code()),
+    }
+}
+
+// acks come from several shards in processing order, they are written in row order
+fn write_acks(
+    stream: Stream,
+    rows: mpsc::Receiver<(u64, mpsc::Receiver<String>)>,
+) -> io::Result<()> {
+    let mut out = io::BufWriter::new(stream);
+    while let Some((row, pending)) = recv_flushed(&mut out, &rows)? {
+        // shards stopped before the row was processed
+        if let Some(ack) = recv_flushed(&mut out, &pending)? {
+            writeln!(out, "{} {}", row, ack)?;
+        }
+    }
+    out.flush()
+}
+
+// written acks are flushed before waiting, so the client gets the ones which are ready
+fn recv_flushed<T>(out: &mut impl Write, receiver: &mpsc::Receiver<T>) -> io::Result<Option<T>> {
+    match receiver.try_recv() {
+        Ok(item) => Ok(Some(item)),
+        Err(_) => {
+            out.flush()?;
+            Ok(receiver.recv().ok())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::account_service::AccountOrder;
+    use crate::account_service_shards::AccountShards;
+    use std::io::BufRead;
+
+    fn send_rows(addr: SocketAddr, rows: &str) -> Vec<String> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(rows.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        BufReader::new(stream).lines().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn serve_csv_and_ndjson_clients() {
+        let mut shards = AccountShards::new(2);
+        let stop = Arc::new(AtomicBool::new(false));
+        let addr = ListenAddr::Tcp("127.0.0.1:0".to_string());
+        let server = Server::bind(&addr, shards.amount_format(), Arc::clone(&stop)).unwrap();
+        let addr = server.tcp_addr().unwrap();
+        shards.run().unwrap();
+        let handle = shards.handle();
+        let serving = thread::spawn(move || server.serve(handle));
+
+        let acks = send_rows(
+            addr,
+            "type,client,tx,amount\n\
+             deposit,1,1,5.0\n\
+             deposit,2,2,1.0\n\
+             withdrawal,2,3,3.0\n\
+             deposit,1,oops,1.0\n\
+             dispute,1,1,\n\
+             dispute,1,1,\n",
+        );
+        assert_eq!(acks.len(), 6);
+        assert_eq!(acks[0], "1 applied");
+        assert_eq!(acks[1], "2 applied");
+        assert_eq!(acks[2], "3 rejected insufficient_balance");
+        assert!(acks[3].starts_with("4 malformed line 5"));
+        assert_eq!(acks[4], "5 applied");
+        assert_eq!(acks[5], "6 ignored already_disputed");
+
+        // client 1 continues on another connection
+        let acks = send_rows(
+            addr,
+            "{\"type\":\"resolve\",\"client\":1,\"tx\":1}\n\
+             {\"type\":\"withdrawal\",\"client\":1,\"tx\":4,\"amount\":\"2.0\"}\n",
+        );
+        assert_eq!(acks, vec!["1 applied", "2 applied"]);
+
+        // more rows than pending acks, the reader waits for the client to read them
+        let rows = 3 * PENDING_ACKS as u32;
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut input = stream.try_clone().unwrap();
+        let sending = thread::spawn(move || {
+            writeln!(input, "type,client,tx,amount").unwrap();
+            for tx in 100..100 + rows {
+                writeln!(input, "deposit,3,{},1.0", tx).unwrap();
+            }
+            input.shutdown(Shutdown::Write).unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+        let acks: Vec<_> = BufReader::new(stream).lines().map(Result::unwrap).collect();
+        sending.join().unwrap();
+        assert_eq!(acks.len(), rows as usize);
+        assert_eq!(acks.last().unwrap(), &format!("{} applied", rows));
+
+        stop.store(true, Ordering::Relaxed);
+        serving.join().unwrap().unwrap();
+        shards.join().unwrap();
+        let totals: Vec<_> = shards
+            .lock_accounts()
+            .sorted(AccountOrder::Client)
+            .map(|a| (a.client, a.available.to_string()))
+            .collect();
+        assert_eq!(
+            totals,
+            vec![
+                (1, "3.000".to_string()),
+                (2, "1.000".to_string()),
+                (3, format!("{}.000", rows))
+            ]
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use crate::account_service::{Account, AccountStore};
+use crate::codec;
+use crate::tx_ids::{TxIdSet, PAGE_WORDS};
+use crate::tx_service::{TransactionStore, TransactionWithState};
+
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+// snapshot layout: header, framed records (accounts and transactions of all shards,
+// pages of used transaction ids), end record
+const MAGIC: &[u8; 8] = b"TXSNAP\0\0";
+pub const SNAPSHOT_VERSION: u32 = 7;
+// version 1 has no transaction id pages, ids of stored transactions are enough to read it,
+// versions before 3 have no partial disputes (see codec::decode_transaction_state),
+// versions before 4 have no receivables and pending holds (see codec::decode_account),
+// versions before 5 have no frozen and closed accounts, before 6 no resolved transactions,
+// before 7 no pending part of disputes
+const MIN_SNAPSHOT_VERSION: u32 = 1;
+const HEADER_LEN: usize = 16;
+
+const RECORD_ACCOUNT: u8 = 1;
+const RECORD_TRANSACTION: u8 = 2;
+const RECORD_TX_IDS: u8 = 3;
+const RECORD_END: u8 = 0xff;
+// largest record payload, a page of transaction ids
+const MAX_RECORD_LEN: usize = 1 + 4 + PAGE_WORDS * 8;
+// bytes of records in a part of a shard encoded by a running worker
+const PART_LEN: usize = 64 * 1024;
+
+// writes to a temporary file renamed into place by finish(), readers never see partial snapshot
+pub struct SnapshotWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    file: BufWriter<File>,
+    accounts: u64,
+    transactions: u64,
+    buf: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn create(path: &Path, scale: u32) -> io::Result<Self> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        file.write_all(&scale.to_le_bytes())?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            tmp_path,
+            file,
+            accounts: 0,
+            transactions: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn write_shard(
+        &mut self,
+        accounts: &impl AccountStore,
+        transactions: &impl TransactionStore,
+    ) -> io::Result<()> {
+        let (a, t) = write_shard_records(&mut self.file, &mut self.buf, accounts, transactions)?;
+        self.accounts += a;
+        self.transactions += t;
+        Ok(())
+    }
+
+    // parts of a shard are written in the order they were encoded
+    pub fn write_encoded_shard(&mut self, part: &EncodedShard) -> io::Result<()> {
+        self.file.write_all(&part.records)?;
+        self.accounts += part.accounts;
+        self.transactions += part.transactions;
+        Ok(())
+    }
+
+    // used ids of all shards, written once per snapshot
+    pub fn write_tx_ids(&mut self, ids: &TxIdSet) -> io::Result<()> {
+        for (index, words) in ids.pages() {
+            self.buf.clear();
+            self.buf.push(RECORD_TX_IDS);
+            self.buf.extend_from_slice(&index.to_le_bytes());
+            for word in words {
+                self.buf.extend_from_slice(&word.to_le_bytes());
+            }
+            codec::write_record(&mut self.file, &self.buf)?;
+        }
+        Ok(())
+    }
+
+    // end record carries counts, so a truncated snapshot is never loaded
+    pub fn finish(mut self) -> io::Result<()> {
+        self.buf.clear();
+        self.buf.push(RECORD_END);
+        self.buf.extend_from_slice(&self.accounts.to_le_bytes());
+        self.buf.extend_from_slice(&self.transactions.to_le_bytes());
+        codec::write_record(&mut self.file, &self.buf)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)
+    }
+}
+
+// part of the shard state encoded by a running worker, written to the snapshot by
+// SnapshotWriter; the last part carries the record counts of the shard
+pub struct EncodedShard {
+    records: Vec<u8>,
+    accounts: u64,
+    transactions: u64,
+    last: bool,
+}
+
+impl EncodedShard {
+    // the state is passed to `send` in parts of about PART_LEN bytes, so a shard is never
+    // held in memory at once; `send` returns false when the parts are not wanted any more
+    pub fn encode(
+        accounts: &impl AccountStore,
+        transactions: &impl TransactionStore,
+        mut send: impl FnMut(EncodedShard) -> bool,
+    ) -> io::Result<()> {
+        let mut parts = Parts {
+            records: Vec::with_capacity(PART_LEN),
+            send: &mut send,
+        };
+        let (accounts, transactions) =
+            write_shard_records(&mut parts, &mut Vec::new(), accounts, transactions)?;
+        let records = parts.records;
+        if !send(EncodedShard {
+            records,
+            accounts,
+            transactions,
+            last: true,
+        }) {
+            return Err(parts_not_wanted());
+        }
+        Ok(())
+    }
+
+    pub fn is_last(&self) -> bool {
+        self.last
+    }
+}
+
+// writer of the records of a shard, which sends every full part
+struct Parts<'a, F> {
+    records: Vec<u8>,
+    send: &'a mut F,
+}
+
+impl<F: FnMut(EncodedShard) -> bool> Write for Parts<'_, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.records.extend_from_slice(buf);
+        if self.records.len() >= PART_LEN {
+            let records = std::mem::replace(&mut self.records, Vec::with_capacity(PART_LEN));
+            if !(self.send)(EncodedShard {
+                records,
+                accounts: 0,
+                transactions: 0,
+                last: false,
+            }) {
+                return Err(parts_not_wanted());
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn parts_not_wanted() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "snapshot parts are not received")
+}
+
+fn write_shard_records<W: Write>(
+    w: &mut W,
+    buf: &mut Vec<u8>,
+    accounts: &impl AccountStore,
+    transactions: &impl TransactionStore,
+) -> io::Result<(u64, u64)> {
+    let (mut account_count, mut tx_count) = (0, 0);
+    for account in accounts.accounts() {
+        buf.clear();
+        buf.push(RECORD_ACCOUNT);
+        codec::encode_account(account, buf);
+        codec::write_record(w, buf)?;
+        account_count += 1;
+    }
+    for tx_state in transactions.iter() {
+        let tx_state = tx_state?;
+        buf.clear();
+        buf.push(RECORD_TRANSACTION);
+        codec::encode_transaction_state(&tx_state, buf);
+        codec::write_record(w, buf)?;
+        tx_count += 1;
+    }
+    Ok((account_count, tx_count))
+}
+
+pub enum SnapshotRecord {
+    Account(Account),
+    Transaction(TransactionWithState),
+    // page index and its bitmap words, see TxIdSet::insert_page()
+    TxIds(u32, Vec<u64>),
+}
+
+// reads a complete snapshot, calling `apply` for every record, an error of it ends the read
+pub fn read<F>(path: &Path, scale: u32, mut apply: F) -> io::Result<()>
+where
+    F: FnMut(SnapshotRecord) -> io::Result<()>,
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let field = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    if &header[..8] != MAGIC {
+        return Err(codec::invalid_data("not a snapshot file"));
+    }
+    if !(MIN_SNAPSHOT_VERSION..=SNAPSHOT_VERSION).contains(&field(8)) {
+        return Err(codec::invalid_data(&format!(
+            "unsupported snapshot version {}",
+            field(8)
+        )));
+    }
+    if field(12) != scale {
+        return Err(codec::invalid_data(&format!(
+            "snapshot written with amount scale {}, engine runs with {}",
+            field(12),
+            scale
+        )));
+    }
+
+    let (mut accounts, mut transactions) = (0u64, 0u64);
+    let mut payload = Vec::new();
+    loop {
+        if codec::read_record(&mut reader, &mut payload, MAX_RECORD_LEN)?.is_none() {
+            return Err(codec::invalid_data("snapshot has no end record"));
+        }
+        let (kind, body) = match payload.split_first() {
+            Some(v) => v,
+            None => return Err(codec::invalid_data("empty snapshot record")),
+        };
+        match *kind {
+            RECORD_ACCOUNT => {
+                let account = codec::decode_account(body)
+                    .ok_or_else(|| codec::invalid_data("invalid account record"))?;
+                apply(SnapshotRecord::Account(account))?;
+                accounts += 1;
+            }
+            RECORD_TRANSACTION => {
+                let tx_state = codec::decode_transaction_state(body)
+                    .ok_or_else(|| codec::invalid_data("invalid transaction record"))?;
+                apply(SnapshotRecord::Transaction(tx_state))?;
+                transactions += 1;
+            }
+            RECORD_TX_IDS if body.len() == 4 + PAGE_WORDS * 8 => {
+                let index = u32::from_le_bytes(body[..4].try_into().unwrap());
+                let words = body[4..]
+                    .chunks_exact(8)
+                    .map(|w| u64::from_le_bytes(w.try_into().unwrap()))
+                    .collect();
+                apply(SnapshotRecord::TxIds(index, words))?;
+            }
+            RECORD_END if body.len() == 16 => {
+                let count = |i: usize| u64::from_le_bytes(body[i..i + 8].try_into().unwrap());
+                if count(0) != accounts || count(8) != transactions {
+                    return Err(codec::invalid_data("snapshot record count mismatch"));
+                }
+                return Ok(());
+            }
+            _ => return Err(codec::invalid_data("unknown snapshot record")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::account_service::AccountService;
+    use crate::tx::*;
+    use crate::tx_service::{TransactionService, TransactionState};
+
+    #[test]
+    fn write_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.snap");
+
+        let mut accounts = AccountService::new();
+        accounts
+            .ensure_account(1, Asset::default())
+            .deposit(Amount::from_units(10))
+            .unwrap();
+        let mut transactions = TransactionService::new();
+        transactions
+            .insert(TransactionWithState {
+                tx: Transaction {
+                    tx_type: TransactionType::Deposit,
+                    client_id: 1,
+                    tx_id: 9,
+                    amount: Some(Amount::from_units(10)),
+                    asset: Asset::default(),
+                },
+                state: TransactionState::Disputed,
+                disputed: Amount::from_units(10),
+                refunded: Amount::ZERO,
+                pending: Amount::ZERO,
+            })
+            .unwrap();
+
+        let mut writer = SnapshotWriter::create(&path, 3).unwrap();
+        writer.write_shard(&accounts, &transactions).unwrap();
+        writer.finish().unwrap();
+
+        let mut records = Vec::new();
+        read(&path, 3, |r| {
+            records.push(r);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(records.len(), 2);
+        match &records[1] {
+            SnapshotRecord::Transaction(t) => {
+                assert_eq!(t.tx.tx_id, 9);
+                assert_eq!(t.state, TransactionState::Disputed);
+            }
+            _ => panic!("transaction record expected"),
+        }
+
+        assert!(read(&path, 4, |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn truncated_snapshot_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.snap");
+
+        let mut accounts = AccountService::new();
+        accounts.ensure_account(1, Asset::default());
+        let mut writer = SnapshotWriter::create(&path, 3).unwrap();
+        writer
+            .write_shard(&accounts, &TransactionService::new())
+            .unwrap();
+        writer.finish().unwrap();
+
+        let len = fs::metadata(&path).unwrap().len();
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 25).unwrap();
+        assert!(read(&path, 3, |_| Ok(())).is_err());
+    }
+}
@@ -1,14 +1,126 @@
-use serde::Deserialize;
+pub use crate::amount::{Amount, AmountError, AmountFormat};
+
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
 use strum_macros::EnumString;
 
 pub type ClientId = u16;
 pub type TransactionId = u32;
 
-// store coins as value * base
-pub type AmountDecimal = u64;
-pub const AMOUNT_BASE: u16 = 1000;
+pub const ASSET_MAX_LEN: usize = 8;
+
+// asset/currency code stored inline (keeps Transaction Copy), empty code is the default asset
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Asset([u8; ASSET_MAX_LEN]);
+
+impl Asset {
+    pub fn is_default(&self) -> bool {
+        *self == Asset::default()
+    }
+
+    pub fn as_bytes(&self) -> &[u8; ASSET_MAX_LEN] {
+        &self.0
+    }
+
+    // inverse of as_bytes, None when bytes are not a valid code
+    pub fn from_bytes(bytes: [u8; ASSET_MAX_LEN]) -> Option<Self> {
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(ASSET_MAX_LEN);
+        let code = std::str::from_utf8(&bytes[..len])
+            .ok()?
+            .parse::<Asset>()
+            .ok()?;
+        if code.0 != bytes {
+            return None;
+        }
+        Some(code)
+    }
+
+    pub fn as_str(&self) -> &str {
+        let len = self.0.iter().position(|b| *b == 0).unwrap_or(ASSET_MAX_LEN);
+        // only ascii is accepted by from_str
+        std::str::from_utf8(&self.0[..len]).unwrap_or_default()
+    }
+}
+
+impl FromStr for Asset {
+    type Err = RecordError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() > ASSET_MAX_LEN || !s.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(RecordError::InvalidAsset(s.to_string()));
+        }
+        let mut code = [0; ASSET_MAX_LEN];
+        code[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Asset(code))
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Asset {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+pub const REASON_MAX_LEN: usize = 16;
+
+// reason code of an administration operation (ie. `fraud_cleared`), stored inline like Asset
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Reason([u8; REASON_MAX_LEN]);
+
+impl Reason {
+    pub fn as_str(&self) -> &str {
+        let len = self
+            .0
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(REASON_MAX_LEN);
+        // only ascii is accepted by from_str
+        std::str::from_utf8(&self.0[..len]).unwrap_or_default()
+    }
+}
+
+impl FromStr for Reason {
+    type Err = RecordError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let valid = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'-';
+        if s.len() > REASON_MAX_LEN || !s.bytes().all(valid) {
+            return Err(RecordError::InvalidReason(s.to_string()));
+        }
+        let mut code = [0; REASON_MAX_LEN];
+        code[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Reason(code))
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Reason {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
 
-#[derive(EnumString, Debug, Copy, Clone, PartialEq, Deserialize)]
+#[derive(EnumString, Debug, Copy, Clone, PartialEq, Deserialize, Serialize)]
 #[strum(serialize_all = "snake_case")]
 #[serde(rename_all = "snake_case")]
 pub enum TransactionType {
@@ -17,10 +129,50 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    // account administration, tx is a reference of the operation (not a stored transaction)
+    Freeze,
+    Unlock,
+    Close,
+}
+
+impl TransactionType {
+    pub fn is_admin(self) -> bool {
+        matches!(
+            self,
+            TransactionType::Freeze | TransactionType::Unlock | TransactionType::Close
+        )
+    }
 }
 
-#[derive(Debug, Deserialize, Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct Transaction {
+    pub tx_type: TransactionType,
+    pub client_id: ClientId,
+    pub tx_id: TransactionId,
+    pub amount: Option<Amount>,
+    pub asset: Asset,
+}
+
+// row read from input, the reason code of an administration operation only goes to the
+// audit trail, so it is not kept with the stored transactions
+#[derive(Debug, Copy, Clone)]
+pub struct TransactionRow {
+    pub tx: Transaction,
+    pub reason: Reason,
+}
+
+impl From<Transaction> for TransactionRow {
+    fn from(tx: Transaction) -> Self {
+        TransactionRow {
+            tx,
+            reason: Reason::default(),
+        }
+    }
+}
+
+// transaction as read from input, amount is parsed later with the engine AmountFormat
+#[derive(Debug, Deserialize)]
+pub struct TransactionRecord {
     #[serde(rename = "type")]
     pub tx_type: TransactionType,
 
@@ -30,31 +182,82 @@ pub struct Transaction {
     #[serde(rename = "tx")]
     pub tx_id: TransactionId,
 
-    #[serde(with = "amount_decimal")]
-    pub amount: Option<AmountDecimal>,
+    #[serde(default)]
+    pub amount: String,
+
+    // optional column, files without it use the default asset
+    #[serde(default, alias = "currency")]
+    pub asset: String,
+
+    // optional column, reason code of administration operations
+    #[serde(default)]
+    pub reason: String,
 }
 
-mod amount_decimal {
-    use super::*;
-    use serde::{self, Deserialize, Deserializer};
+impl TransactionRecord {
+    pub fn parse(self, format: &AmountFormat) -> Result<TransactionRow, RecordError> {
+        let amount = if self.amount.is_empty() {
+            None
+        } else {
+            Some(format.parse(&self.amount).map_err(RecordError::Amount)?)
+        };
+        let tx = Transaction {
+            tx_type: self.tx_type,
+            client_id: self.client_id,
+            tx_id: self.tx_id,
+            amount,
+            asset: self.asset.parse()?,
+        };
+        Ok(TransactionRow {
+            tx,
+            reason: self.reason.parse()?,
+        })
+    }
+}
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<AmountDecimal>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let s = String::deserialize(deserializer)?;
-        if s.is_empty() {
-            return Ok(None);
+// location in the input, byte offset can be used to seek back to the record
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct InputPosition {
+    pub byte: u64,
+    pub line: u64,
+    pub record: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RecordError {
+    Amount(AmountError),
+    InvalidAsset(String),
+    InvalidReason(String),
+}
+
+impl RecordError {
+    // input column the error was found in
+    pub fn field(&self) -> &'static str {
+        match self {
+            RecordError::Amount(_) => "amount",
+            RecordError::InvalidAsset(_) => "asset",
+            RecordError::InvalidReason(_) => "reason",
         }
+    }
+}
+
+impl std::error::Error for RecordError {}
 
-        let f: f64 = s.parse().map_err(serde::de::Error::custom)?;
-        if f < 0.0 {
-            return Err(serde::de::Error::custom(&format!(
-                "Unexpected amount value: {}",
-                f
-            )));
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordError::Amount(e) => e.fmt(f),
+            RecordError::InvalidAsset(s) => write!(
+                f,
+                "invalid asset '{}', expected up to {} letters or digits",
+                s, ASSET_MAX_LEN
+            ),
+            RecordError::InvalidReason(s) => write!(
+                f,
+                "invalid reason '{}', expected up to {} letters, digits, '_' or '-'",
+                s, REASON_MAX_LEN
+            ),
         }
-        Ok(Some((f * (AMOUNT_BASE as f64)) as AmountDecimal))
     }
 }
 
@@ -62,7 +265,6 @@ mod amount_decimal {
 mod tests {
 
     use super::*;
-    use std::str::FromStr;
 
     #[test]
     fn type_is_comparable() {
@@ -75,4 +277,47 @@ mod tests {
             TransactionType::Resolve
         );
     }
+
+    #[test]
+    fn record_amount_uses_format() {
+        let record = || TransactionRecord {
+            tx_type: TransactionType::Deposit,
+            client_id: 1,
+            tx_id: 2,
+            amount: "1.2345".to_string(),
+            asset: String::new(),
+            reason: String::new(),
+        };
+        let format = AmountFormat::default();
+        assert_eq!(
+            record().parse(&format).unwrap_err(),
+            RecordError::Amount(AmountError::TooManyFractionDigits(4))
+        );
+        let format = AmountFormat::new(4, crate::amount::Rounding::Reject).unwrap();
+        let tx = record().parse(&format).unwrap().tx;
+        assert_eq!(tx.amount, Some(Amount::from_units(12_345)));
+        assert!(tx.asset.is_default());
+    }
+
+    #[test]
+    fn asset_code() {
+        let asset: Asset = "USDT".parse().unwrap();
+        assert_eq!(asset.as_str(), "USDT");
+        assert!(!asset.is_default());
+        assert!("".parse::<Asset>().unwrap().is_default());
+        assert_eq!(
+            "TOOLONGCODE".parse::<Asset>(),
+            Err(RecordError::InvalidAsset("TOOLONGCODE".to_string()))
+        );
+        assert!("U$D".parse::<Asset>().is_err());
+    }
+
+    #[test]
+    fn reason_code() {
+        let reason: Reason = "fraud_cleared".parse().unwrap();
+        assert_eq!(reason.to_string(), "fraud_cleared");
+        assert_eq!(Reason::default().as_str(), "");
+        assert!("kyc review".parse::<Reason>().is_err());
+        assert_eq!(RecordError::InvalidReason(String::new()).field(), "reason");
+    }
 }
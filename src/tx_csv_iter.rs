@@ -1,44 +1,168 @@
+use crate::diagnostics::ParseDiagnostic;
+use crate::input::TIMESTAMP_HEADER;
 use crate::tx::*;
 
 use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{Read, Seek};
 use std::path::PathBuf;
 
-pub struct TransIterator {
-    inner: csv::DeserializeRecordsIntoIter<BufReader<File>, Transaction>,
+// reads transactions from any csv source, ie. a file or stdin
+pub struct TransIterator<R: Read> {
+    reader: csv::Reader<R>,
+    headers: csv::StringRecord,
+    record: csv::StringRecord,
+    format: AmountFormat,
+    // set after an i/o error, no more records can be read
+    failed: bool,
 }
 
-impl TransIterator {
-    pub fn new(path: &PathBuf) -> Result<Self, Box<dyn Error>> {
-        let f = File::open(path)?;
-        let br = std::io::BufReader::new(f);
+impl TransIterator<File> {
+    pub fn new(path: &PathBuf, format: AmountFormat) -> Result<Self, Box<dyn Error>> {
+        TransIterator::from_reader(File::open(path)?, format)
+    }
+
+    // continue reading after the record which ended at `position`
+    pub fn resume(
+        path: &PathBuf,
+        format: AmountFormat,
+        position: InputPosition,
+    ) -> Result<Self, Box<dyn Error>> {
+        TransIterator::from_reader_at(File::open(path)?, format, position)
+    }
+}
+
+impl<R: Read + Seek> TransIterator<R> {
+    // headers are read from the start of `reader`, then it seeks to `position`
+    pub fn from_reader_at(
+        reader: R,
+        format: AmountFormat,
+        position: InputPosition,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut iter = TransIterator::from_reader(reader, format)?;
+        // nothing was read yet, headers are already consumed
+        if position == InputPosition::default() {
+            return Ok(iter);
+        }
+        let mut pos = csv::Position::new();
+        pos.set_byte(position.byte)
+            .set_line(position.line)
+            .set_record(position.record);
+        iter.reader.seek(pos)?;
+        Ok(iter)
+    }
+}
+
+impl<R: Read> TransIterator<R> {
+    // csv reader is buffered, no need to wrap `reader` in BufReader
+    pub fn from_reader(reader: R, format: AmountFormat) -> Result<Self, Box<dyn Error>> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(reader);
+        // only the first line can be the timestamp header, other rows starting with '#'
+        // are malformed
+        let mut headers = reader.headers()?.clone();
+        if headers
+            .get(0)
+            .is_some_and(|h| h.starts_with(TIMESTAMP_HEADER))
+        {
+            reader.read_record(&mut headers)?;
+            reader.set_headers(headers.clone());
+        }
         Ok(TransIterator {
-            inner: csv::ReaderBuilder::new()
-                .trim(csv::Trim::All)
-                .flexible(true)
-                .from_reader(br)
-                .into_deserialize(),
+            reader,
+            headers,
+            record: csv::StringRecord::new(),
+            format,
+            failed: false,
         })
     }
+
+    // position right after the last returned (or skipped) record, where reading resumes
+    pub fn position(&self) -> InputPosition {
+        let pos = self.reader.position();
+        InputPosition {
+            byte: pos.byte(),
+            line: pos.line(),
+            record: pos.record(),
+        }
+    }
 }
 
-impl Iterator for TransIterator {
-    type Item = Transaction;
-
-    // inner iter, on error skip
-    fn next(&mut self) -> Option<Transaction> {
-        loop {
-            match self.inner.next() {
-                Some(v) => match v {
-                    Ok(t) => return Some(t),
-                    Err(e) => {
-                        println!("Parse error {}", e);
-                    } // on error skip
-                },
-                None => return None,
-            }
+impl<R: Read> TransIterator<R> {
+    fn diagnostic(&self, field: Option<String>, message: String) -> ParseDiagnostic {
+        let (line, byte) = self
+            .record
+            .position()
+            .map_or((0, 0), |pos| (pos.line(), pos.byte()));
+        ParseDiagnostic {
+            line,
+            byte,
+            record: self.record.iter().collect::<Vec<_>>().join(","),
+            field,
+            message,
+            fatal: false,
+        }
+    }
+
+    fn header_name(&self, index: Option<u64>) -> Option<String> {
+        index
+            .and_then(|i| self.headers.get(i as usize))
+            .map(str::to_string)
+    }
+
+    fn read_error(&mut self, e: csv::Error) -> ParseDiagnostic {
+        let field = match e.kind() {
+            csv::ErrorKind::Utf8 { err, .. } => self.header_name(Some(err.field() as u64)),
+            _ => None,
+        };
+        let (line, byte) = e.position().map_or((0, 0), |pos| (pos.line(), pos.byte()));
+        // reader cannot continue after i/o error
+        self.failed = matches!(e.kind(), csv::ErrorKind::Io(_));
+        ParseDiagnostic {
+            line,
+            byte,
+            record: String::new(),
+            field,
+            message: e.to_string(),
+            fatal: self.failed,
+        }
+    }
+}
+
+impl<R: Read> Iterator for TransIterator<R> {
+    type Item = Result<TransactionRow, ParseDiagnostic>;
+
+    // malformed rows are returned as diagnostics, reading continues with the next row
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.reader.read_record(&mut self.record) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(self.read_error(e))),
         }
+        let record: TransactionRecord = match self.record.deserialize(Some(&self.headers)) {
+            Ok(record) => record,
+            Err(e) => {
+                let field = match e.kind() {
+                    csv::ErrorKind::Deserialize { err, .. } => self.header_name(err.field()),
+                    _ => None,
+                };
+                let message = match e.kind() {
+                    csv::ErrorKind::Deserialize { err, .. } => err.kind().to_string(),
+                    _ => e.to_string(),
+                };
+                return Some(Err(self.diagnostic(field, message)));
+            }
+        };
+        Some(
+            record
+                .parse(&self.format)
+                .map_err(|e| self.diagnostic(Some(e.field().to_string()), e.to_string())),
+        )
     }
 }
 
@@ -51,16 +175,92 @@ mod tests {
     #[test]
     fn read_csv() {
         let path = PathBuf::from("./data/transactions.csv");
-        let iter = TransIterator::new(&path).expect("Cannot open input file");
-        let v: Vec<_> = iter.collect();
+        let iter =
+            TransIterator::new(&path, AmountFormat::default()).expect("Cannot open input file");
+        let v: Vec<_> = iter.collect::<Result<_, _>>().unwrap();
         assert_eq!(v.len(), 5);
     }
 
     #[test]
     fn read_csv_with_error() {
         let path = PathBuf::from("./data/transactions_wrong.csv");
-        let iter = TransIterator::new(&path).expect("Cannot open input file");
-        let v: Vec<_> = iter.collect();
+        let iter =
+            TransIterator::new(&path, AmountFormat::default()).expect("Cannot open input file");
+        let (v, errors): (Vec<_>, Vec<_>) = iter.partition(Result::is_ok);
+        assert_eq!(v.len(), 5);
+
+        let errors: Vec<_> = errors.into_iter().map(Result::unwrap_err).collect();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].line, 3);
+        assert_eq!(errors[0].byte, 38);
+        assert_eq!(errors[0].field.as_deref(), Some("amount"));
+        assert_eq!(errors[0].record, "deposit,2,2,-2.0");
+        assert_eq!(errors[1].line, 6);
+        assert_eq!(errors[1].record, "withaaaawl,1,4,1.5");
+    }
+
+    #[test]
+    fn read_csv_with_currency() {
+        let path = PathBuf::from("./data/transactions_assets.csv");
+        let iter =
+            TransIterator::new(&path, AmountFormat::default()).expect("Cannot open input file");
+        let v: Vec<_> = iter.collect::<Result<_, _>>().unwrap();
         assert_eq!(v.len(), 5);
+        assert_eq!(v[0].tx.asset.as_str(), "BTC");
+        assert_eq!(v[1].tx.asset.as_str(), "EUR");
+        assert!(v[2].tx.asset.is_default());
+        assert!(v[4].tx.asset.is_default());
+    }
+
+    #[test]
+    fn read_error_is_fatal() {
+        // fails after the header and the first row
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\n".as_bytes();
+        let failing = std::io::Read::chain(input, FailingReader);
+        let v: Vec<_> = TransIterator::from_reader(failing, AmountFormat::default())
+            .unwrap()
+            .collect();
+        assert_eq!(v.len(), 2);
+        assert_eq!(v[0].as_ref().unwrap().tx.tx_id, 1);
+        assert!(v[1].as_ref().unwrap_err().fatal);
+    }
+
+    #[test]
+    fn only_timestamp_header_is_skipped() {
+        let input = "\u{feff}# timestamp: 2024-05-01T10:00:00Z\n\
+                     type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     #deposit,1,2,1.0\n\
+                     deposit,1,3,1.0\n";
+        let v: Vec<_> = TransIterator::from_reader(input.as_bytes(), AmountFormat::default())
+            .unwrap()
+            .collect();
+        assert_eq!(v.len(), 3);
+        assert_eq!(v[0].as_ref().unwrap().tx.tx_id, 1);
+        assert_eq!(v[1].as_ref().unwrap_err().line, 4);
+        assert_eq!(v[2].as_ref().unwrap().tx.tx_id, 3);
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk failure"))
+        }
+    }
+
+    #[test]
+    fn resume_from_position() {
+        let path = PathBuf::from("./data/transactions.csv");
+        let mut iter = TransIterator::new(&path, AmountFormat::default()).unwrap();
+        let first: Vec<_> = iter.by_ref().take(2).map(|t| t.unwrap().tx.tx_id).collect();
+        assert_eq!(first, vec![1, 2]);
+        let position = iter.position();
+        assert_eq!(position.record, 3);
+        assert_eq!(position.line, 4);
+
+        let iter = TransIterator::resume(&path, AmountFormat::default(), position).unwrap();
+        let rest: Vec<_> = iter.map(|t| t.unwrap().tx.tx_id).collect();
+        assert_eq!(rest, vec![3, 4, 5]);
     }
 }
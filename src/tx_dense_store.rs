@@ -0,0 +1,305 @@
+use crate::tx::*;
+use crate::tx_ids::TxIdSet;
+use crate::tx_service::{TransactionState, TransactionStore, TransactionWithState};
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+// slots of a page, 1 MiB
+const PAGE_BITS: u32 = 16;
+const PAGE_SLOTS: usize = 1 << PAGE_BITS;
+const PAGES: usize = 1 << (TransactionId::BITS - PAGE_BITS);
+
+// bits of the slot meta word
+const PRESENT: u64 = 1;
+const WITHDRAWAL: u64 = 1 << 1;
+const STATE_SHIFT: u32 = 2;
+const CLIENT_SHIFT: u32 = 16;
+const ASSET_SHIFT: u32 = 32;
+const SHARD_SHIFT: u32 = 48;
+
+// amount and meta word (client, asset index, type and state bits, owning shard)
+type Slot = [AtomicU64; 2];
+
+// transactions indexed by id in pages of 65536 slots of 16 bytes, a page is allocated when
+// the first id of it is stored, shared by the shards of an engine like TxIdSet: a slot is
+// written only by the shard the id was claimed for, so no locks are needed
+pub struct DenseTransactions {
+    pages: Box<[OnceLock<Box<[Slot]>>]>,
+}
+
+impl Default for DenseTransactions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DenseTransactions {
+    pub fn new() -> Self {
+        Self {
+            pages: (0..PAGES).map(|_| OnceLock::new()).collect(),
+        }
+    }
+
+    fn slot(&self, tx_id: TransactionId) -> Option<&Slot> {
+        let page = self.pages[(tx_id >> PAGE_BITS) as usize].get()?;
+        Some(&page[tx_id as usize & (PAGE_SLOTS - 1)])
+    }
+
+    fn slot_mut(&self, tx_id: TransactionId) -> &Slot {
+        let page = self.pages[(tx_id >> PAGE_BITS) as usize].get_or_init(|| {
+            (0..PAGE_SLOTS)
+                .map(|_| [AtomicU64::new(0), AtomicU64::new(0)])
+                .collect()
+        });
+        &page[tx_id as usize & (PAGE_SLOTS - 1)]
+    }
+
+    // allocated bytes, for reports
+    pub fn allocated(&self) -> usize {
+        let pages = self
+            .pages
+            .iter()
+            .filter(|page| page.get().is_some())
+            .count();
+        pages * PAGE_SLOTS * std::mem::size_of::<Slot>()
+            + self.pages.len() * std::mem::size_of::<OnceLock<Box<[Slot]>>>()
+    }
+}
+
+// transaction store of a shard over the shared dense slots, deposits and withdrawals with
+// nothing disputed or refunded and one of the first 65535 assets of the shard take a slot,
+// the rest (ie. open disputes) is kept in a map
+pub struct DenseTransactionStore {
+    slots: Arc<DenseTransactions>,
+    shard: u16,
+    // not packable into a slot
+    others: HashMap<TransactionId, TransactionWithState>,
+    // asset of an index stored in slots of this shard
+    assets: Vec<Asset>,
+    asset_index: HashMap<Asset, u16>,
+    ids: Arc<TxIdSet>,
+}
+
+impl DenseTransactionStore {
+    pub fn new(slots: Arc<DenseTransactions>, shard: u16, ids: Arc<TxIdSet>) -> Self {
+        Self {
+            slots,
+            shard,
+            others: HashMap::new(),
+            assets: vec![Asset::default()],
+            asset_index: HashMap::from([(Asset::default(), 0)]),
+            ids,
+        }
+    }
+
+    // meta word of the transaction, None when it does not fit into a slot
+    fn pack(&mut self, tx_state: &TransactionWithState) -> Option<u64> {
+        let tx = &tx_state.tx;
+        let kind = match tx.tx_type {
+            TransactionType::Deposit => 0,
+            TransactionType::Withdrawal => WITHDRAWAL,
+            _ => return None,
+        };
+        let state = match tx_state.state {
+            TransactionState::Valid => 0,
+            TransactionState::Resolved => 1,
+            TransactionState::Disputed | TransactionState::Refunded => return None,
+        };
+        if tx.amount.is_none()
+            || tx_state.disputed != Amount::ZERO
+            || tx_state.refunded != Amount::ZERO
+        {
+            return None;
+        }
+        let asset = match self.asset_index.get(&tx.asset) {
+            Some(&index) => index,
+            None if self.assets.len() <= u16::MAX as usize => {
+                let index = self.assets.len() as u16;
+                self.assets.push(tx.asset);
+                self.asset_index.insert(tx.asset, index);
+                index
+            }
+            None => return None,
+        };
+        Some(
+            PRESENT
+                | kind
+                | state << STATE_SHIFT
+                | (tx.client_id as u64) << CLIENT_SHIFT
+                | (asset as u64) << ASSET_SHIFT
+                | (self.shard as u64) << SHARD_SHIFT,
+        )
+    }
+
+    fn unpack(&self, tx_id: TransactionId, slot: &Slot) -> Option<TransactionWithState> {
+        let meta = slot[1].load(Ordering::Relaxed);
+        if meta & PRESENT == 0 || (meta >> SHARD_SHIFT) as u16 != self.shard {
+            return None;
+        }
+        let mut tx_state = TransactionWithState::new(Transaction {
+            tx_type: match meta & WITHDRAWAL {
+                0 => TransactionType::Deposit,
+                _ => TransactionType::Withdrawal,
+            },
+            client_id: (meta >> CLIENT_SHIFT) as ClientId,
+            tx_id,
+            amount: Some(Amount::from_units(slot[0].load(Ordering::Relaxed))),
+            asset: self.assets[(meta >> ASSET_SHIFT) as u16 as usize],
+        });
+        if (meta >> STATE_SHIFT) & 1 == 1 {
+            tx_state.state = TransactionState::Resolved;
+        }
+        Some(tx_state)
+    }
+}
+
+impl TransactionStore for DenseTransactionStore {
+    fn ids(&self) -> &TxIdSet {
+        &self.ids
+    }
+
+    fn get(&self, transaction_id: TransactionId) -> io::Result<Option<TransactionWithState>> {
+        Ok(match self.slots.slot(transaction_id) {
+            Some(slot) => self.unpack(transaction_id, slot),
+            None => None,
+        }
+        .or_else(|| self.others.get(&transaction_id).copied()))
+    }
+
+    fn put(&mut self, tx_state: TransactionWithState) -> io::Result<()> {
+        let tx_id = tx_state.tx.tx_id;
+        match self.pack(&tx_state) {
+            Some(meta) => {
+                let slot = self.slots.slot_mut(tx_id);
+                slot[0].store(tx_state.tx.amount.unwrap().units(), Ordering::Relaxed);
+                slot[1].store(meta, Ordering::Relaxed);
+                self.others.remove(&tx_id);
+            }
+            None => {
+                if let Some(slot) = self.slots.slot(tx_id) {
+                    if self.unpack(tx_id, slot).is_some() {
+                        slot[1].store(0, Ordering::Relaxed);
+                    }
+                }
+                self.others.insert(tx_id, tx_state);
+            }
+        }
+        Ok(())
+    }
+
+    // slots in id order, then the others
+    fn iter(&self) -> Box<dyn Iterator<Item = io::Result<TransactionWithState>> + '_> {
+        let slots = self
+            .slots
+            .pages
+            .iter()
+            .enumerate()
+            .filter_map(|(index, page)| Some((index, page.get()?)))
+            .flat_map(move |(index, page)| {
+                page.iter().enumerate().filter_map(move |(i, slot)| {
+                    self.unpack(((index << PAGE_BITS) + i) as TransactionId, slot)
+                })
+            });
+        Box::new(slots.chain(self.others.values().copied()).map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tx_service::TransactionService;
+
+    fn tx_state(tx_type: TransactionType, tx_id: TransactionId) -> TransactionWithState {
+        TransactionWithState::new(Transaction {
+            tx_type,
+            client_id: (tx_id % 7) as ClientId,
+            tx_id,
+            amount: Some(Amount::from_units(tx_id as u64 * 10)),
+            asset: ["", "EUR", "BTC"][tx_id as usize % 3].parse().unwrap(),
+        })
+    }
+
+    #[test]
+    fn same_content_as_memory_store() {
+        let mut dense = DenseTransactionStore::new(Default::default(), 0, Default::default());
+        let mut memory = TransactionService::new();
+        for tx_id in (0..300_000).step_by(3).chain([u32::MAX]) {
+            let tx_type = match tx_id % 4 {
+                0 => TransactionType::Withdrawal,
+                _ => TransactionType::Deposit,
+            };
+            dense.insert(tx_state(tx_type, tx_id)).unwrap();
+            memory.insert(tx_state(tx_type, tx_id)).unwrap();
+        }
+        // disputed, resolved and charged back ones
+        for tx_id in (0..300_000).step_by(33) {
+            let mut tx_state = memory.get(tx_id).unwrap().unwrap();
+            tx_state.disputed = Amount::from_units(tx_id as u64 % 5);
+            tx_state.refunded = Amount::from_units(tx_id as u64 % 2);
+            tx_state.update_state();
+            dense.put(tx_state).unwrap();
+            memory.put(tx_state).unwrap();
+        }
+        assert!(dense.others.len() < 10_000);
+        let fields = |t: TransactionWithState| {
+            (
+                t.tx.tx_type,
+                t.tx.client_id,
+                t.tx.amount,
+                t.tx.asset,
+                t.state,
+                t.disputed,
+                t.refunded,
+            )
+        };
+        for tx_id in (0..300_001).chain([u32::MAX]) {
+            assert_eq!(
+                dense.get(tx_id).unwrap().map(fields),
+                memory.get(tx_id).unwrap().map(fields)
+            );
+        }
+        let mut stored: Vec<_> = dense
+            .iter()
+            .map(Result::unwrap)
+            .map(|t| (t.tx.tx_id, fields(t)))
+            .collect();
+        let mut expected: Vec<_> = memory
+            .iter()
+            .map(Result::unwrap)
+            .map(|t| (t.tx.tx_id, fields(t)))
+            .collect();
+        stored.sort_by_key(|(tx_id, _)| *tx_id);
+        expected.sort_by_key(|(tx_id, _)| *tx_id);
+        assert_eq!(stored, expected);
+    }
+
+    #[test]
+    fn shards_share_slots() {
+        let slots = Arc::new(DenseTransactions::new());
+        let ids = Arc::new(TxIdSet::new());
+        let mut first = DenseTransactionStore::new(Arc::clone(&slots), 0, Arc::clone(&ids));
+        let mut second = DenseTransactionStore::new(Arc::clone(&slots), 1, Arc::clone(&ids));
+        first.insert(tx_state(TransactionType::Deposit, 1)).unwrap();
+        second
+            .insert(tx_state(TransactionType::Deposit, 2))
+            .unwrap();
+        assert!(first.get(2).unwrap().is_none() && second.get(1).unwrap().is_none());
+        let ids = |store: &DenseTransactionStore| {
+            store
+                .iter()
+                .map(|t| t.unwrap().tx.tx_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&first), [1]);
+        assert_eq!(ids(&second), [2]);
+        // one page for both
+        assert_eq!(
+            slots.allocated(),
+            DenseTransactions::new().allocated() + (1 << 20)
+        );
+    }
+}
@@ -0,0 +1,610 @@
+use crate::codec::{self, TRANSACTION_STATE_LEN};
+use crate::tx::TransactionId;
+use crate::tx_ids::TxIdSet;
+use crate::tx_service::{TransactionStore, TransactionWithState};
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// records of a run block, the block is read by a lookup (~3.1 KiB)
+const BLOCK_RECORDS: usize = 64;
+const RUN_EXTENSION: &str = "run";
+
+// manifest of a shard directory: header, one framed record with the next run number and
+// the number and length of every run, oldest first
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_MAGIC: &[u8; 8] = b"TXRUNS\0\0";
+// version 2 has records with the pending part of disputes
+const MANIFEST_VERSION: u32 = 2;
+const MANIFEST_HEADER_LEN: usize = 12;
+const MAX_MANIFEST_LEN: usize = 8 + 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct DiskStoreConfig {
+    // directory with a subdirectory of runs per shard
+    pub dir: PathBuf,
+    // transactions kept in memory, both written ones not yet in a run and recently read ones
+    pub cache: usize,
+}
+
+impl DiskStoreConfig {
+    pub fn shard_path(&self, shard: usize) -> PathBuf {
+        self.dir.join(format!("shard-{}", shard))
+    }
+}
+
+// sorted file of fixed-length transaction records
+struct Run {
+    number: u64,
+    path: PathBuf,
+    file: File,
+    len: usize,
+    // id of the first record of every block
+    index: Vec<TransactionId>,
+    last: TransactionId,
+}
+
+impl Run {
+    // `records` must be sorted by id, a partly written file is removed
+    fn write(
+        dir: &Path,
+        number: u64,
+        records: impl Iterator<Item = io::Result<TransactionWithState>>,
+    ) -> io::Result<Run> {
+        let path = run_path(dir, number);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut run = Run {
+            number,
+            path,
+            file,
+            len: 0,
+            index: Vec::new(),
+            last: 0,
+        };
+        match run.write_records(records) {
+            Ok(()) => Ok(run),
+            Err(e) => {
+                let _ = fs::remove_file(&run.path);
+                Err(e)
+            }
+        }
+    }
+
+    fn write_records(
+        &mut self,
+        records: impl Iterator<Item = io::Result<TransactionWithState>>,
+    ) -> io::Result<()> {
+        let mut out = BufWriter::new(&self.file);
+        let mut buf = Vec::with_capacity(TRANSACTION_STATE_LEN);
+        for tx_state in records {
+            let tx_state = tx_state?;
+            if self.len.is_multiple_of(BLOCK_RECORDS) {
+                self.index.push(tx_state.tx.tx_id);
+            }
+            buf.clear();
+            codec::encode_transaction_state(&tx_state, &mut buf);
+            out.write_all(&buf)?;
+            self.len += 1;
+            self.last = tx_state.tx.tx_id;
+        }
+        out.flush()?;
+        drop(out);
+        // on disk before the manifest lists the run
+        self.file.sync_all()
+    }
+
+    // run listed in the manifest, the index is read back from the first record of every block
+    fn open(dir: &Path, number: u64, len: usize) -> io::Result<Run> {
+        let path = run_path(dir, number);
+        let file = File::open(&path)?;
+        if file.metadata()?.len() != (len * TRANSACTION_STATE_LEN) as u64 {
+            return Err(codec::invalid_data("transaction store run length mismatch"));
+        }
+        let mut run = Run {
+            number,
+            path,
+            file,
+            len,
+            index: Vec::new(),
+            last: 0,
+        };
+        for i in (0..len).step_by(BLOCK_RECORDS) {
+            let first = run.read_record(i)?.tx.tx_id;
+            run.index.push(first);
+        }
+        if len > 0 {
+            run.last = run.read_record(len - 1)?.tx.tx_id;
+        }
+        Ok(run)
+    }
+
+    fn read_record(&self, i: usize) -> io::Result<TransactionWithState> {
+        let mut buf = [0; TRANSACTION_STATE_LEN];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start((i * TRANSACTION_STATE_LEN) as u64))?;
+        file.read_exact(&mut buf)?;
+        decode(&buf)
+    }
+
+    // reads only the block which can hold the id
+    fn get(&self, tx_id: TransactionId) -> io::Result<Option<TransactionWithState>> {
+        if self.index.first().is_none_or(|&first| tx_id < first) || tx_id > self.last {
+            return Ok(None);
+        }
+        let block = self.index.partition_point(|&first| first <= tx_id) - 1;
+        let start = block * BLOCK_RECORDS;
+        let count = BLOCK_RECORDS.min(self.len - start);
+        let mut buf = vec![0; count * TRANSACTION_STATE_LEN];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start((start * TRANSACTION_STATE_LEN) as u64))?;
+        file.read_exact(&mut buf)?;
+
+        let records: Vec<_> = buf.chunks_exact(TRANSACTION_STATE_LEN).collect();
+        let mut found = None;
+        let search = records.binary_search_by(|record| match decode(record) {
+            Ok(tx_state) => {
+                let ordering = tx_state.tx.tx_id.cmp(&tx_id);
+                if ordering.is_eq() {
+                    found = Some(tx_state);
+                }
+                ordering
+            }
+            // stop at the first broken record
+            Err(_) => std::cmp::Ordering::Equal,
+        });
+        match (search, found) {
+            (Ok(i), None) => decode(records[i]).map(Some),
+            (_, found) => Ok(found),
+        }
+    }
+
+    // own file handle, reads do not move the cursor used by get(), a run shorter than
+    // written is an error
+    fn records(&self) -> io::Result<impl Iterator<Item = io::Result<TransactionWithState>>> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut buf = [0; TRANSACTION_STATE_LEN];
+        Ok((0..self.len).map(move |_| {
+            reader.read_exact(&mut buf)?;
+            decode(&buf)
+        }))
+    }
+}
+
+fn decode(record: &[u8]) -> io::Result<TransactionWithState> {
+    codec::decode_transaction_state(record)
+        .ok_or_else(|| codec::invalid_data("corrupted transaction store record"))
+}
+
+type Source<'a> = Box<dyn Iterator<Item = io::Result<TransactionWithState>> + 'a>;
+
+// merge of sorted sources, of equal ids the one from the first source is kept,
+// the merge ends with the first error of a source
+struct Merged<'a> {
+    sources: Vec<Peekable<Source<'a>>>,
+    failed: bool,
+}
+
+impl Iterator for Merged<'_> {
+    type Item = io::Result<TransactionWithState>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if let Some(i) = self
+            .sources
+            .iter_mut()
+            .position(|source| matches!(source.peek(), Some(Err(_))))
+        {
+            self.failed = true;
+            return self.sources[i].next();
+        }
+        let tx_id = self
+            .sources
+            .iter_mut()
+            .filter_map(|source| match source.peek() {
+                Some(Ok(tx_state)) => Some(tx_state.tx.tx_id),
+                _ => None,
+            })
+            .min()?;
+        let mut first = None;
+        for source in self.sources.iter_mut() {
+            if let Some(tx_state) = source.next_if(|tx_state| {
+                tx_state
+                    .as_ref()
+                    .is_ok_and(|tx_state| tx_state.tx.tx_id == tx_id)
+            }) {
+                first.get_or_insert(tx_state);
+            }
+        }
+        first
+    }
+}
+
+// recently read transactions, entries not read during two generations are dropped
+#[derive(Default)]
+struct ReadCache {
+    hot: HashMap<TransactionId, TransactionWithState>,
+    cold: HashMap<TransactionId, TransactionWithState>,
+    generation: usize,
+}
+
+impl ReadCache {
+    fn get(&mut self, tx_id: TransactionId) -> Option<TransactionWithState> {
+        if let Some(tx_state) = self.hot.get(&tx_id) {
+            return Some(*tx_state);
+        }
+        let tx_state = self.cold.remove(&tx_id)?;
+        self.insert(tx_state);
+        Some(tx_state)
+    }
+
+    fn insert(&mut self, tx_state: TransactionWithState) {
+        if self.generation == 0 {
+            return;
+        }
+        if self.hot.len() >= self.generation {
+            self.cold = std::mem::take(&mut self.hot);
+        }
+        self.hot.insert(tx_state.tx.tx_id, tx_state);
+    }
+
+    fn remove(&mut self, tx_id: TransactionId) {
+        self.hot.remove(&tx_id);
+        self.cold.remove(&tx_id);
+    }
+}
+
+// log-structured transaction store: puts go to a sorted in-memory table, which is written
+// as a sorted run file when full; runs of similar size are merged, so a shard has about
+// log2(transactions / cache) runs and a lookup reads one block of a few of them; the
+// manifest lists the runs, so they are kept across restarts
+pub struct DiskTransactionStore {
+    dir: PathBuf,
+    // written transactions not yet in a run
+    table: BTreeMap<TransactionId, TransactionWithState>,
+    table_cap: usize,
+    // newest last
+    runs: Vec<Run>,
+    next_run: u64,
+    cache: RefCell<ReadCache>,
+    ids: Arc<TxIdSet>,
+}
+
+impl DiskTransactionStore {
+    // reopens the runs listed in the manifest of `dir`, other run files are left by a flush
+    // or merge which did not finish and are removed; ids of the stored transactions are not
+    // claimed, the recovery (ie. replay of the write-ahead log) puts them again
+    pub fn open(dir: &Path, cache: usize, ids: Arc<TxIdSet>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let (next_run, listed) = read_manifest(dir)?;
+        let runs = listed
+            .into_iter()
+            .map(|(number, len)| Run::open(dir, number, len))
+            .collect::<io::Result<Vec<_>>>()?;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == RUN_EXTENSION)
+                && !runs.iter().any(|run| run.path == path)
+            {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            table: BTreeMap::new(),
+            table_cap: (cache / 2).max(1),
+            runs,
+            next_run,
+            cache: RefCell::new(ReadCache {
+                generation: cache / 4,
+                ..Default::default()
+            }),
+            ids,
+        })
+    }
+
+    // number of run files, for tests and benchmarks
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    // removes every stored transaction, ie. when the state is not recovered from a log
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.table.clear();
+        let cache = self.cache.get_mut();
+        cache.hot.clear();
+        cache.cold.clear();
+        let runs = std::mem::take(&mut self.runs);
+        self.write_manifest()?;
+        for run in runs {
+            fs::remove_file(&run.path)?;
+        }
+        Ok(())
+    }
+
+    // the table is kept when writing the run fails; merged runs are removed after the
+    // manifest without them is written, so it never lists a missing run
+    fn flush_table(&mut self) -> io::Result<()> {
+        self.next_run += 1;
+        let run = Run::write(
+            &self.dir,
+            self.next_run,
+            self.table.values().copied().map(Ok),
+        )?;
+        self.table.clear();
+        self.runs.push(run);
+        let mut merged_away = Vec::new();
+        // merge the newest runs while the older one is not much larger
+        while let [.., older, newer] = &self.runs[..] {
+            if older.len > 2 * newer.len {
+                break;
+            }
+            let sources = vec![newer.records()?, older.records()?];
+            self.next_run += 1;
+            let merged = Run::write(&self.dir, self.next_run, merge(sources))?;
+            merged_away.extend(self.runs.drain(self.runs.len() - 2..));
+            self.runs.push(merged);
+        }
+        self.write_manifest()?;
+        for run in merged_away {
+            fs::remove_file(&run.path)?;
+        }
+        Ok(())
+    }
+
+    // written to a temporary file renamed into place, readers never see a partial manifest
+    fn write_manifest(&self) -> io::Result<()> {
+        let mut payload = Vec::with_capacity(8 + 16 * self.runs.len());
+        payload.extend_from_slice(&self.next_run.to_le_bytes());
+        for run in &self.runs {
+            payload.extend_from_slice(&run.number.to_le_bytes());
+            payload.extend_from_slice(&(run.len as u64).to_le_bytes());
+        }
+        let tmp_path = self.dir.join(format!("{}.tmp", MANIFEST));
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        file.write_all(MANIFEST_MAGIC)?;
+        file.write_all(&MANIFEST_VERSION.to_le_bytes())?;
+        codec::write_record(&mut file, &payload)?;
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(MANIFEST))
+    }
+
+    fn read(&self, tx_id: TransactionId) -> io::Result<Option<TransactionWithState>> {
+        for run in self.runs.iter().rev() {
+            if let Some(tx_state) = run.get(tx_id)? {
+                return Ok(Some(tx_state));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// written transactions of the table are kept for the next open
+impl Drop for DiskTransactionStore {
+    fn drop(&mut self) {
+        if !self.table.is_empty() {
+            let _ = self.flush_table();
+        }
+    }
+}
+
+fn run_path(dir: &Path, run: u64) -> PathBuf {
+    dir.join(format!("{:010}.{}", run, RUN_EXTENSION))
+}
+
+// next run number and the number and length of every run, nothing without a manifest
+fn read_manifest(dir: &Path) -> io::Result<(u64, Vec<(u64, usize)>)> {
+    let mut reader = match File::open(dir.join(MANIFEST)) {
+        Ok(file) => BufReader::new(file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, Vec::new())),
+        Err(e) => return Err(e),
+    };
+    let mut header = [0; MANIFEST_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    if &header[..8] != MANIFEST_MAGIC || header[8..] != MANIFEST_VERSION.to_le_bytes() {
+        return Err(codec::invalid_data("not a transaction store manifest"));
+    }
+    let mut payload = Vec::new();
+    if codec::read_record(&mut reader, &mut payload, MAX_MANIFEST_LEN)?.is_none()
+        || payload.len() % 16 != 8
+    {
+        return Err(codec::invalid_data("invalid transaction store manifest"));
+    }
+    let field = |i: usize| u64::from_le_bytes(payload[i..i + 8].try_into().unwrap());
+    let runs = (8..payload.len())
+        .step_by(16)
+        .map(|i| (field(i), field(i + 8) as usize))
+        .collect();
+    Ok((field(0), runs))
+}
+
+fn merge<'a, I>(sources: Vec<I>) -> Merged<'a>
+where
+    I: Iterator<Item = io::Result<TransactionWithState>> + 'a,
+{
+    Merged {
+        sources: sources
+            .into_iter()
+            .map(|source| (Box::new(source) as Source).peekable())
+            .collect(),
+        failed: false,
+    }
+}
+
+impl TransactionStore for DiskTransactionStore {
+    fn ids(&self) -> &TxIdSet {
+        &self.ids
+    }
+
+    fn get(&self, transaction_id: TransactionId) -> io::Result<Option<TransactionWithState>> {
+        if let Some(tx_state) = self.table.get(&transaction_id) {
+            return Ok(Some(*tx_state));
+        }
+        let mut cache = self.cache.borrow_mut();
+        if let Some(tx_state) = cache.get(transaction_id) {
+            return Ok(Some(tx_state));
+        }
+        let found = self.read(transaction_id)?;
+        if let Some(tx_state) = found {
+            cache.insert(tx_state);
+        }
+        Ok(found)
+    }
+
+    // a full table is flushed first, so nothing is stored when the flush fails
+    fn put(&mut self, tx_state: TransactionWithState) -> io::Result<()> {
+        let tx_id = tx_state.tx.tx_id;
+        if self.table.len() >= self.table_cap && !self.table.contains_key(&tx_id) {
+            self.flush_table()?;
+        }
+        self.cache.get_mut().remove(tx_id);
+        self.table.insert(tx_id, tx_state);
+        Ok(())
+    }
+
+    // sorted by id
+    fn iter(&self) -> Box<dyn Iterator<Item = io::Result<TransactionWithState>> + '_> {
+        let mut sources: Vec<Source> = vec![Box::new(self.table.values().copied().map(Ok))];
+        for run in self.runs.iter().rev() {
+            sources.push(match run.records() {
+                Ok(records) => Box::new(records),
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            });
+        }
+        Box::new(merge(sources))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tx::*;
+    use crate::tx_service::{TransactionService, TransactionState};
+
+    fn deposit(tx_id: TransactionId) -> TransactionWithState {
+        TransactionWithState::new(Transaction {
+            tx_type: TransactionType::Deposit,
+            client_id: (tx_id % 7) as ClientId,
+            tx_id,
+            amount: Some(Amount::from_units(tx_id as u64 * 10)),
+            asset: Asset::default(),
+        })
+    }
+
+    #[test]
+    fn same_content_as_memory_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = DiskTransactionStore::open(dir.path(), 64, Default::default()).unwrap();
+        let mut memory = TransactionService::new();
+        // ids out of order, so runs overlap
+        for i in 0..5_000u32 {
+            let tx_id = i.wrapping_mul(2_654_435_761) % 10_000;
+            disk.insert(deposit(tx_id)).unwrap();
+            memory.insert(deposit(tx_id)).unwrap();
+        }
+        // later versions shadow the ones in older runs
+        for tx_id in (0..10_000).step_by(3) {
+            if let Some(mut tx_state) = memory.get(tx_id).unwrap() {
+                tx_state.disputed = tx_state.tx.amount.unwrap();
+                tx_state.update_state();
+                disk.put(tx_state).unwrap();
+                memory.put(tx_state).unwrap();
+            }
+        }
+        assert!(disk.runs() > 1 && disk.runs() < 12, "{} runs", disk.runs());
+        for tx_id in 0..10_001 {
+            let (d, m) = (disk.get(tx_id).unwrap(), memory.get(tx_id).unwrap());
+            let fields =
+                |t: TransactionWithState| (t.tx.client_id, t.tx.amount, t.state, t.disputed);
+            assert_eq!(d.map(fields), m.map(fields));
+        }
+        let mut expected: Vec<_> = memory
+            .iter()
+            .map(Result::unwrap)
+            .map(|t| (t.tx.tx_id, t.state))
+            .collect();
+        expected.sort_by_key(|(tx_id, _)| *tx_id);
+        let stored: Vec<_> = disk
+            .iter()
+            .map(Result::unwrap)
+            .map(|t| (t.tx.tx_id, t.state))
+            .collect();
+        assert_eq!(stored, expected);
+        assert!(stored.contains(&(3_003, TransactionState::Disputed)));
+    }
+
+    #[test]
+    fn read_errors_are_returned() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = DiskTransactionStore::open(dir.path(), 8, Default::default()).unwrap();
+        for tx_id in 0..20 {
+            store.insert(deposit(tx_id)).unwrap();
+        }
+        // cut in the middle of the last record of the oldest run
+        let run = &store.runs[0];
+        run.file
+            .set_len((run.len * TRANSACTION_STATE_LEN - 1) as u64)
+            .unwrap();
+        assert!(store.get(run.last).is_err());
+        let stored: Vec<_> = store.iter().collect();
+        assert!(stored.last().unwrap().is_err());
+        assert!(stored[..stored.len() - 1].iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn runs_are_kept_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || DiskTransactionStore::open(dir.path(), 8, Default::default()).unwrap();
+        let run_files = || {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .filter(|entry| {
+                    let path = entry.as_ref().unwrap().path();
+                    path.extension().is_some_and(|ext| ext == RUN_EXTENSION)
+                })
+                .count()
+        };
+        let mut store = open();
+        for tx_id in 0..100 {
+            store.insert(deposit(tx_id)).unwrap();
+        }
+        // still in the table when dropped
+        let mut tx_state = store.get(3).unwrap().unwrap();
+        tx_state.disputed = tx_state.tx.amount.unwrap();
+        tx_state.update_state();
+        store.put(tx_state).unwrap();
+        drop(store);
+
+        // left by a merge which did not finish
+        File::create(dir.path().join("9999999999.run")).unwrap();
+        let mut store = open();
+        assert_eq!(store.runs(), run_files());
+        assert_eq!(
+            TransactionState::Disputed,
+            store.get(3).unwrap().unwrap().state
+        );
+        for tx_id in 100..150 {
+            store.insert(deposit(tx_id)).unwrap();
+        }
+        let stored: Vec<_> = store.iter().map(|t| t.unwrap().tx.tx_id).collect();
+        assert_eq!(stored, (0..150).collect::<Vec<_>>());
+
+        store.clear().unwrap();
+        assert_eq!(0, run_files());
+        drop(store);
+        assert!(open().get(1).unwrap().is_none());
+    }
+}
@@ -0,0 +1,123 @@
+use crate::tx::TransactionId;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+// ids of a page, 8 KiB of bits
+const PAGE_BITS: u32 = 16;
+pub const PAGE_WORDS: usize = (1 << PAGE_BITS) / 64;
+const PAGES: usize = 1 << (TransactionId::BITS - PAGE_BITS);
+
+// used transaction ids shared by all shards, a bitmap allocated in pages of 65536 ids
+// when the first id of a page is used (all 2^32 ids take 512 MiB, 100M dense ids ~12 MiB),
+// inserts are lock-free, so shards never wait for each other
+pub struct TxIdSet {
+    pages: Box<[OnceLock<Box<[AtomicU64]>>]>,
+}
+
+impl Default for TxIdSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn locate(tx_id: TransactionId) -> (usize, usize, u64) {
+    let page = (tx_id >> PAGE_BITS) as usize;
+    let bit = tx_id as usize & ((1 << PAGE_BITS) - 1);
+    (page, bit / 64, 1 << (bit % 64))
+}
+
+impl TxIdSet {
+    pub fn new() -> Self {
+        Self {
+            pages: (0..PAGES).map(|_| OnceLock::new()).collect(),
+        }
+    }
+
+    fn page(&self, index: usize) -> &[AtomicU64] {
+        self.pages[index].get_or_init(|| (0..PAGE_WORDS).map(|_| AtomicU64::new(0)).collect())
+    }
+
+    // true when the id was not used, of concurrent inserts of an id only one gets true
+    pub fn insert(&self, tx_id: TransactionId) -> bool {
+        let (page, word, bit) = locate(tx_id);
+        self.page(page)[word].fetch_or(bit, Ordering::AcqRel) & bit == 0
+    }
+
+    pub fn remove(&self, tx_id: TransactionId) {
+        let (page, word, bit) = locate(tx_id);
+        if let Some(page) = self.pages[page].get() {
+            page[word].fetch_and(!bit, Ordering::AcqRel);
+        }
+    }
+
+    pub fn contains(&self, tx_id: TransactionId) -> bool {
+        let (page, word, bit) = locate(tx_id);
+        self.pages[page]
+            .get()
+            .is_some_and(|page| page[word].load(Ordering::Acquire) & bit != 0)
+    }
+
+    // allocated pages with their index, for snapshots
+    pub fn pages(&self) -> impl Iterator<Item = (u32, Vec<u64>)> + '_ {
+        self.pages.iter().enumerate().filter_map(|(i, page)| {
+            let words = page
+                .get()?
+                .iter()
+                .map(|w| w.load(Ordering::Acquire))
+                .collect();
+            Some((i as u32, words))
+        })
+    }
+
+    // adds the ids of a page written by pages()
+    pub fn insert_page(&self, index: u32, words: &[u64]) {
+        let page = self.page(index as usize);
+        for (word, bits) in page.iter().zip(words) {
+            word.fetch_or(*bits, Ordering::AcqRel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn insert_remove_and_pages() {
+        let ids = TxIdSet::new();
+        assert!(ids.insert(7));
+        assert!(!ids.insert(7));
+        assert!(ids.insert(u32::MAX));
+        assert!(ids.contains(u32::MAX));
+        ids.remove(7);
+        assert!(!ids.contains(7));
+        assert!(!ids.contains(1 << 20));
+
+        let copy = TxIdSet::new();
+        for (index, words) in ids.pages() {
+            copy.insert_page(index, &words);
+        }
+        assert_eq!(copy.pages().count(), 2);
+        assert!(copy.contains(u32::MAX));
+        assert!(!copy.contains(7));
+    }
+
+    #[test]
+    fn concurrent_inserts_claim_once() {
+        let ids = Arc::new(TxIdSet::new());
+        let claimed: u32 = (0..4)
+            .map(|_| {
+                let ids = Arc::clone(&ids);
+                thread::spawn(move || (0..100_000).filter(|&id| ids.insert(id)).count() as u32)
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .sum();
+        assert_eq!(claimed, 100_000);
+    }
+}
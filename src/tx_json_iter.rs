@@ -0,0 +1,377 @@
+use crate::diagnostics::ParseDiagnostic;
+use crate::input::TIMESTAMP_HEADER;
+use crate::tx::*;
+
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::PathBuf;
+
+// transaction object as read from json, amount can be a number or a string
+#[derive(Debug, Deserialize)]
+struct JsonRecord<'a> {
+    #[serde(rename = "type")]
+    tx_type: TransactionType,
+
+    #[serde(rename = "client")]
+    client_id: ClientId,
+
+    #[serde(rename = "tx")]
+    tx_id: TransactionId,
+
+    // kept raw, so number digits are parsed exactly by AmountFormat
+    #[serde(default, borrow)]
+    amount: Option<&'a RawValue>,
+
+    #[serde(default, alias = "currency")]
+    asset: Option<String>,
+
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+impl JsonRecord<'_> {
+    fn into_record(self) -> Result<TransactionRecord, serde_json::Error> {
+        let amount = match self.amount {
+            Some(raw) if raw.get().starts_with('"') => serde_json::from_str(raw.get())?,
+            Some(raw) => raw.get().to_string(),
+            None => String::new(),
+        };
+        Ok(TransactionRecord {
+            tx_type: self.tx_type,
+            client_id: self.client_id,
+            tx_id: self.tx_id,
+            amount,
+            asset: self.asset.unwrap_or_default(),
+            reason: self.reason.unwrap_or_default(),
+        })
+    }
+}
+
+// reads transaction objects from ndjson (one object per line) or a json array, ndjson
+// lines are parsed one by one and objects of an array are split by the reader, so
+// a malformed object does not stop reading
+pub struct JsonTransIterator<R: BufRead> {
+    reader: R,
+    format: AmountFormat,
+    // input is a json array, otherwise ndjson
+    array: bool,
+    // position after the last consumed byte
+    position: InputPosition,
+    buf: Vec<u8>,
+    // set after an i/o error, no more records can be read
+    failed: bool,
+}
+
+impl JsonTransIterator<BufReader<File>> {
+    pub fn new(path: &PathBuf, format: AmountFormat) -> Result<Self, Box<dyn Error>> {
+        JsonTransIterator::from_reader(BufReader::new(File::open(path)?), format)
+    }
+
+    // continue reading after the record which ended at `position`
+    pub fn resume(
+        path: &PathBuf,
+        format: AmountFormat,
+        position: InputPosition,
+    ) -> Result<Self, Box<dyn Error>> {
+        JsonTransIterator::from_reader_at(BufReader::new(File::open(path)?), format, position)
+    }
+}
+
+impl<R: BufRead + Seek> JsonTransIterator<R> {
+    // the form of input is detected from its start, then it seeks to `position`
+    pub fn from_reader_at(
+        reader: R,
+        format: AmountFormat,
+        position: InputPosition,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut iter = JsonTransIterator::from_reader(reader, format)?;
+        iter.reader.seek(SeekFrom::Start(position.byte))?;
+        iter.position = position;
+        Ok(iter)
+    }
+}
+
+impl<R: BufRead> JsonTransIterator<R> {
+    pub fn from_reader(reader: R, format: AmountFormat) -> Result<Self, Box<dyn Error>> {
+        let start = InputPosition {
+            byte: 0,
+            line: 1,
+            record: 0,
+        };
+        let mut iter = JsonTransIterator::with_position(reader, format, start);
+        // utf-8 byte order mark
+        if iter.reader.fill_buf()?.starts_with(b"\xef\xbb\xbf") {
+            iter.reader.consume(3);
+            iter.position.byte = 3;
+        }
+        // only the first line can be the timestamp header, other lines starting with '#'
+        // are malformed
+        if iter
+            .reader
+            .fill_buf()?
+            .starts_with(TIMESTAMP_HEADER.as_bytes())
+        {
+            iter.read_line()?;
+            iter.buf.clear();
+        }
+        iter.array = iter.skip_separators()? == Some(b'[');
+        Ok(iter)
+    }
+
+    fn with_position(reader: R, format: AmountFormat, position: InputPosition) -> Self {
+        JsonTransIterator {
+            reader,
+            format,
+            array: false,
+            position,
+            buf: Vec::new(),
+            failed: false,
+        }
+    }
+
+    // position right after the last returned (or skipped) record, where reading resumes
+    pub fn position(&self) -> InputPosition {
+        self.position
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.reader.fill_buf()?.first().copied())
+    }
+
+    fn bump(&mut self, b: u8) {
+        self.reader.consume(1);
+        self.position.byte += 1;
+        if b == b'\n' {
+            self.position.line += 1;
+        }
+    }
+
+    // reads the object starting at the current byte into `buf`, false on end of input
+    fn read_object(&mut self) -> io::Result<bool> {
+        let (mut depth, mut in_string, mut escaped) = (0u32, false, false);
+        while let Some(b) = self.peek()? {
+            self.bump(b);
+            self.buf.push(b);
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' if in_string => escaped = true,
+                b'"' => in_string = !in_string,
+                b'{' | b'[' if !in_string => depth += 1,
+                b'}' | b']' if !in_string => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(true);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+
+    // skips the rest of the line after unexpected content
+    fn read_line(&mut self) -> io::Result<()> {
+        while let Some(b) = self.peek()? {
+            self.bump(b);
+            if b == b'\n' {
+                break;
+            }
+            self.buf.push(b);
+        }
+        Ok(())
+    }
+
+    // skips whitespace and array punctuation (of an array) between objects, returns the
+    // next byte
+    fn skip_separators(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            match self.peek()? {
+                Some(b) if b.is_ascii_whitespace() => self.bump(b),
+                Some(b) if self.array && matches!(b, b'[' | b']' | b',') => self.bump(b),
+                next => return Ok(next),
+            }
+        }
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<Result<TransactionRow, ParseDiagnostic>>> {
+        let b = match self.skip_separators()? {
+            None => return Ok(None),
+            Some(b) => b,
+        };
+        let (line, byte) = (self.position.line, self.position.byte);
+        self.position.record += 1;
+        self.buf.clear();
+
+        let (field, message) = if !self.array {
+            self.read_line()?;
+            match self.parse() {
+                Ok(tx) => return Ok(Some(Ok(tx))),
+                Err(e) => e,
+            }
+        } else if b != b'{' {
+            self.read_line()?;
+            (None, "expected json object".to_string())
+        } else if !self.read_object()? {
+            (
+                None,
+                "unexpected end of input inside json object".to_string(),
+            )
+        } else {
+            match self.parse() {
+                Ok(tx) => return Ok(Some(Ok(tx))),
+                Err(e) => e,
+            }
+        };
+        Ok(Some(Err(ParseDiagnostic {
+            line,
+            byte,
+            record: String::from_utf8_lossy(&self.buf).into_owned(),
+            field,
+            message,
+            fatal: false,
+        })))
+    }
+
+    // error is the failing field (if known) and message
+    fn parse(&self) -> Result<TransactionRow, (Option<String>, String)> {
+        let record = serde_json::from_slice::<JsonRecord>(&self.buf)
+            .and_then(JsonRecord::into_record)
+            .map_err(|e| (None, e.to_string()))?;
+        record
+            .parse(&self.format)
+            .map_err(|e| (Some(e.field().to_string()), e.to_string()))
+    }
+}
+
+impl<R: BufRead> Iterator for JsonTransIterator<R> {
+    type Item = Result<TransactionRow, ParseDiagnostic>;
+
+    // malformed objects are returned as diagnostics, reading continues with the next object
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.next_record() {
+            Ok(item) => item,
+            Err(e) => {
+                // reader cannot continue after i/o error
+                self.failed = true;
+                Some(Err(ParseDiagnostic {
+                    line: self.position.line,
+                    byte: self.position.byte,
+                    record: String::new(),
+                    field: None,
+                    message: e.to_string(),
+                    fatal: true,
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::fs;
+
+    fn write_input(content: &str) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.json");
+        fs::write(&path, content).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn read_ndjson_with_error() {
+        let (_dir, path) = write_input(
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.5,\"currency\":\"EUR\"}\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":\"-2.0\"}\n\
+             not json\n\
+             {\"type\":\"dispute\",\"client\":1,\"tx\":1}\n",
+        );
+        let v: Vec<_> = JsonTransIterator::new(&path, AmountFormat::default())
+            .unwrap()
+            .collect();
+        assert_eq!(v.len(), 4);
+        let tx = v[0].as_ref().unwrap();
+        assert_eq!(tx.tx.amount, Some(Amount::from_units(1500)));
+        assert_eq!(tx.tx.asset.as_str(), "EUR");
+
+        let err = v[1].as_ref().unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.field.as_deref(), Some("amount"));
+        assert_eq!(v[2].as_ref().unwrap_err().line, 3);
+        assert_eq!(v[3].as_ref().unwrap().tx.amount, None);
+    }
+
+    #[test]
+    fn broken_ndjson_line_does_not_swallow_next_ones() {
+        let (_dir, path) = write_input(
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.0}\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":\"1.0}\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":3,\"amount\":1.0}\r\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":4,\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":5,\"amount\":1.0}\n",
+        );
+        let mut iter = JsonTransIterator::new(&path, AmountFormat::default()).unwrap();
+        let v: Vec<_> = iter.by_ref().collect();
+        let lines: Vec<_> = v
+            .iter()
+            .map(|t| t.as_ref().map(|tx| tx.tx.tx_id).map_err(|e| e.line))
+            .collect();
+        assert_eq!(lines, vec![Ok(1), Err(2), Ok(3), Err(4), Ok(5)]);
+        assert_eq!(iter.position().line, 6);
+    }
+
+    #[test]
+    fn only_timestamp_header_is_skipped() {
+        let (_dir, path) = write_input(
+            "# timestamp: 2024-05-01T10:00:00Z\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.0}\n\
+             # {\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":1.0}\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":3,\"amount\":1.0}\n",
+        );
+        let lines: Vec<_> = JsonTransIterator::new(&path, AmountFormat::default())
+            .unwrap()
+            .map(|t| t.map(|tx| tx.tx.tx_id).map_err(|e| e.line))
+            .collect();
+        assert_eq!(lines, vec![Ok(1), Err(3), Ok(3)]);
+
+        let (_dir, path) = write_input(
+            "# timestamp: 2024-05-01T10:00:00Z\n[\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.0},\n\
+             # comment\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":3,\"amount\":1.0}\n]\n",
+        );
+        let lines: Vec<_> = JsonTransIterator::new(&path, AmountFormat::default())
+            .unwrap()
+            .map(|t| t.map(|tx| tx.tx.tx_id).map_err(|e| e.line))
+            .collect();
+        assert_eq!(lines, vec![Ok(1), Err(4), Ok(3)]);
+    }
+
+    #[test]
+    fn read_json_array_and_resume() {
+        let (_dir, path) = write_input(
+            "[\n  {\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"1.0\"},\n  \
+             {\"type\": \"deposit\", \"client\": 2, \"tx\": 2,\n   \"amount\": 2.25},\n  \
+             {\"type\": \"withdrawal\", \"client\": 2, \"tx\": 3, \"amount\": 0.5}\n]\n",
+        );
+        let mut iter = JsonTransIterator::new(&path, AmountFormat::default()).unwrap();
+        assert_eq!(iter.next().unwrap().unwrap().tx.tx_id, 1);
+        assert_eq!(iter.next().unwrap().unwrap().tx.tx_id, 2);
+        let position = iter.position();
+        assert_eq!(position.record, 2);
+        assert_eq!(position.line, 4);
+
+        let rest: Vec<_> = JsonTransIterator::resume(&path, AmountFormat::default(), position)
+            .unwrap()
+            .map(|t| t.unwrap().tx.tx_id)
+            .collect();
+        assert_eq!(rest, vec![3]);
+    }
+}
@@ -1,189 +1,320 @@
-use crate::account_service::{Account, AccountService, AccountServiceError};
+use crate::account_service::{Account, AccountServiceError, AccountStatus, AccountStore};
+use crate::policy::{ProcessingPolicy, RepeatDisputes, ResolvedDisputes, WithdrawalDisputes};
 use crate::tx::*;
-use crate::tx_service::{TransactionService, TransactionState, TransactionWithState};
+use crate::tx_service::{TransactionState, TransactionStore, TransactionWithState};
 
-use std::collections::hash_map::Entry;
+// deliberate no-op carries the reason the transaction was skipped
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Applied,
+    Ignored(AccountServiceError),
+    // administration operation was applied, the change goes to the audit trail
+    Administered(StatusChange),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StatusChange {
+    pub from: AccountStatus,
+    pub to: AccountStatus,
+    // available balance paid out by close
+    pub payout: Amount,
+}
 
-pub struct TransactionProcessor {}
+// write-ahead log is replayed with the processor of the engine, so the policy must not change
+// between runs sharing the log
+#[derive(Debug, Copy, Clone, Default)]
+pub struct TransactionProcessor {
+    pub policy: ProcessingPolicy,
+}
+
+// deposits and withdrawals claim their id, it stays claimed also when they fail, so of
+// the transactions with the same id the first one always wins
+pub fn claims_id(tx: &Transaction) -> bool {
+    matches!(
+        tx.tx_type,
+        TransactionType::Deposit | TransactionType::Withdrawal
+    ) && tx.amount.is_some()
+}
 
 // business logic for transaction processing
 impl TransactionProcessor {
     pub fn process(
-        account_service: &mut AccountService,
-        tx_service: &mut TransactionService,
+        &self,
+        account_service: &mut impl AccountStore,
+        tx_service: &mut impl TransactionStore,
         tx: Transaction,
-    ) -> Result<(), AccountServiceError> {
-        let account = account_service.ensure_account(tx.client_id);
-        if account.locked {
-            return Err(AccountServiceError::AccountLocked);
+    ) -> Result<Outcome, AccountServiceError> {
+        if claims_id(&tx) && !tx_service.claim(tx.tx_id) {
+            return Err(AccountServiceError::TransactionDuplicate);
         }
-        match tx.tx_type {
+        self.apply(account_service, tx_service, tx)
+    }
+
+    // id of a deposit or withdrawal is already claimed, ie. by the thread routing
+    // transactions to shards in input order
+    pub fn apply(
+        &self,
+        account_service: &mut impl AccountStore,
+        tx_service: &mut impl TransactionStore,
+        tx: Transaction,
+    ) -> Result<Outcome, AccountServiceError> {
+        // disputes act on the asset of the original transaction
+        let asset = match tx.tx_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => tx.asset,
+            tx_type if tx_type.is_admin() => tx.asset,
+            _ => tx_service
+                .get(tx.tx_id)?
+                .map_or(tx.asset, |prev_tx_state| prev_tx_state.tx.asset),
+        };
+        // operations do not create accounts
+        let account = if tx.tx_type.is_admin() {
+            account_service
+                .account(tx.client_id, asset)
+                .ok_or(AccountServiceError::AccountNotFound)?
+        } else {
+            let account = account_service.ensure_account(tx.client_id, asset);
+            account.check_status(tx.tx_type, self.policy.locked_accounts)?;
+            account
+        };
+        // a failed store leaves the account as it was
+        let before = account.clone();
+        let result = match tx.tx_type {
             TransactionType::Deposit => TransactionProcessor::deposit(account, tx_service, tx),
             TransactionType::Withdrawal => {
                 TransactionProcessor::withdrawal(account, tx_service, tx)
             }
-            TransactionType::Dispute => TransactionProcessor::dispute(account, tx_service, tx),
+            TransactionType::Dispute => self.dispute(account, tx_service, tx),
             TransactionType::Resolve => TransactionProcessor::resolve(account, tx_service, tx),
             TransactionType::Chargeback => {
                 TransactionProcessor::chargeback(account, tx_service, tx)
             }
+            TransactionType::Freeze | TransactionType::Unlock | TransactionType::Close => {
+                TransactionProcessor::administer(account, tx)
+            }
+        };
+        if let Err(AccountServiceError::StorageFailed(_)) = result {
+            *account = before;
         }
+        result
+    }
+
+    // freeze, unlock (of a frozen or locked account) and close with a payout of available funds
+    fn administer(account: &mut Account, tx: Transaction) -> Result<Outcome, AccountServiceError> {
+        let from = account.status;
+        let mut payout = Amount::ZERO;
+        account.status = match (tx.tx_type, from) {
+            (_, AccountStatus::Closed) => return Err(AccountServiceError::AccountClosed),
+            (TransactionType::Freeze, AccountStatus::Active) => AccountStatus::Frozen,
+            (TransactionType::Freeze, AccountStatus::Frozen) => {
+                return Ok(Outcome::Ignored(AccountServiceError::AlreadyFrozen))
+            }
+            (TransactionType::Freeze, _) => return Err(AccountServiceError::AccountLocked),
+            (TransactionType::Unlock, AccountStatus::Active) => {
+                return Ok(Outcome::Ignored(AccountServiceError::NotLocked))
+            }
+            (TransactionType::Unlock, _) => AccountStatus::Active,
+            // close
+            _ => {
+                // open disputes and debts have to be settled first
+                if account.held > Amount::ZERO
+                    || account.hold_pending > Amount::ZERO
+                    || account.receivable > Amount::ZERO
+                {
+                    return Err(AccountServiceError::AccountNotSettled);
+                }
+                if tx.amount.is_some_and(|amount| amount != account.available) {
+                    return Err(AccountServiceError::PayoutMismatch);
+                }
+                payout = account.available;
+                account.available = Amount::ZERO;
+                AccountStatus::Closed
+            }
+        };
+        Ok(Outcome::Administered(StatusChange {
+            from,
+            to: account.status,
+            payout,
+        }))
     }
 
     fn deposit(
         account: &mut Account,
-        tx_service: &mut TransactionService,
+        tx_service: &mut impl TransactionStore,
         tx: Transaction,
-    ) -> Result<(), AccountServiceError> {
+    ) -> Result<Outcome, AccountServiceError> {
         let amount = match tx.amount {
             Some(v) => v,
             None => return Err(AccountServiceError::EmptyTransactionAmount),
         };
 
-        let trans_entry = tx_service.trans.entry(tx.tx_id);
-        let vacant_entry = match trans_entry {
-            Entry::Occupied(_) => return Err(AccountServiceError::TransactionDuplicate),
-            Entry::Vacant(entry) => entry,
-        };
-
         account.deposit(amount)?;
 
         // only valid transactions are stored
-        vacant_entry.insert(TransactionWithState {
-            tx,
-            state: TransactionState::Valid,
-        });
-        Ok(())
+        tx_service.put(TransactionWithState::new(tx))?;
+        Ok(Outcome::Applied)
     }
 
     fn withdrawal(
         account: &mut Account,
-        tx_service: &mut TransactionService,
+        tx_service: &mut impl TransactionStore,
         tx: Transaction,
-    ) -> Result<(), AccountServiceError> {
+    ) -> Result<Outcome, AccountServiceError> {
         let amount = match tx.amount {
             Some(v) => v,
             None => return Err(AccountServiceError::EmptyTransactionAmount),
         };
 
-        let trans_entry = tx_service.trans.entry(tx.tx_id);
-        let _vacant_entry = match trans_entry {
-            Entry::Occupied(_) => return Err(AccountServiceError::TransactionDuplicate),
-            Entry::Vacant(entry) => entry,
-        };
-
         if account.available < amount {
             return Err(AccountServiceError::InsufficientBalance);
         }
         account.available -= amount;
 
-        // skip storing withdrawal as they are not disputable in this implementation
-        // vacant_entry.insert(TransactionWithState{ tx, state: TransactionState::Valid });
-        Ok(())
+        // stored even when disputes of withdrawals are rejected, so they are reported properly
+        tx_service.put(TransactionWithState::new(tx))?;
+        Ok(Outcome::Applied)
     }
 
+    // amount can be omitted to dispute the whole transaction, several partial disputes
+    // can be open at once up to the transaction amount
     fn dispute(
+        &self,
         account: &mut Account,
-        tx_service: &mut TransactionService,
+        tx_service: &mut impl TransactionStore,
         tx: Transaction,
-    ) -> Result<(), AccountServiceError> {
-        if tx.amount.is_some() {
-            return Err(AccountServiceError::TransactionAmountShouldBeEmpty);
-        };
-
-        let prev_tx_state = tx_service.get_mut(tx.tx_id)?;
-        let prev_tx = &prev_tx_state.tx;
+    ) -> Result<Outcome, AccountServiceError> {
+        let mut prev_tx_state = stored(tx_service, tx.tx_id)?;
+        let prev_tx = prev_tx_state.tx;
 
-        check_client(prev_tx, &tx)?;
-        match prev_tx_state.state {
-            TransactionState::Disputed => return Ok(()), // skip already disputed (duplicated transaction?)
-            TransactionState::Refunded => Err(AccountServiceError::AlreadyRefunded),
-            TransactionState::Valid => Ok(()),
+        check_client(&prev_tx, &tx)?;
+        check_asset(&prev_tx, &tx)?;
+        match (prev_tx_state.state, self.policy.resolved_disputes) {
+            // skip already disputed (duplicated transaction?)
+            (TransactionState::Disputed, _) if tx.amount.is_none() => {
+                return match self.policy.repeat_disputes {
+                    RepeatDisputes::Ignore => {
+                        Ok(Outcome::Ignored(AccountServiceError::AlreadyDisputed))
+                    }
+                    RepeatDisputes::Reject => Err(AccountServiceError::AlreadyDisputed),
+                };
+            }
+            (TransactionState::Refunded, _) => Err(AccountServiceError::AlreadyRefunded),
+            (TransactionState::Resolved, ResolvedDisputes::Reject) => {
+                Err(AccountServiceError::AlreadyResolved)
+            }
+            _ => Ok(()),
         }?;
 
-        if prev_tx.tx_type != TransactionType::Deposit {
-            return Err(AccountServiceError::DisputeWrongTransactionType(
-                prev_tx.tx_type,
-            ));
+        if prev_tx.amount.is_none() {
+            return Err(AccountServiceError::EmptyTransactionAmount);
+        }
+        let undisputed = prev_tx_state.undisputed();
+        let amount = tx.amount.unwrap_or(undisputed);
+        if amount > undisputed {
+            return Err(AccountServiceError::DisputeAmountExceeded);
         }
 
-        let amount = match prev_tx.amount {
-            Some(v) => v,
-            None => return Err(AccountServiceError::EmptyTransactionAmount),
+        let pending = match (prev_tx.tx_type, self.policy.withdrawal_disputes) {
+            (TransactionType::Deposit, _) => account.held(amount, self.policy.dispute_shortfall)?,
+            (TransactionType::Withdrawal, WithdrawalDisputes::Reverse) => {
+                account.hold_withdrawn(amount)?;
+                Amount::ZERO
+            }
+            (tx_type, _) => {
+                return Err(AccountServiceError::DisputeWrongTransactionType(tx_type));
+            }
         };
+        prev_tx_state.disputed += amount;
+        prev_tx_state.pending += pending;
+        prev_tx_state.update_state();
+        tx_service.put(prev_tx_state)?;
 
-        account.held(amount)?;
-        prev_tx_state.state = TransactionState::Disputed;
-
-        Ok(())
+        Ok(Outcome::Applied)
     }
 
+    // amount can be omitted to resolve everything disputed
     fn resolve(
         account: &mut Account,
-        tx_service: &mut TransactionService,
+        tx_service: &mut impl TransactionStore,
         tx: Transaction,
-    ) -> Result<(), AccountServiceError> {
-        if tx.amount.is_some() {
-            return Err(AccountServiceError::TransactionAmountShouldBeEmpty);
-        };
-
-        let prev_tx_state = tx_service.get_mut(tx.tx_id)?;
-        let prev_tx = &prev_tx_state.tx;
+    ) -> Result<Outcome, AccountServiceError> {
+        let mut prev_tx_state = stored(tx_service, tx.tx_id)?;
+        let prev_tx = prev_tx_state.tx;
 
-        check_client(prev_tx, &tx)?;
+        check_client(&prev_tx, &tx)?;
+        check_asset(&prev_tx, &tx)?;
         // skip not disputed or already solved dispute
         if prev_tx_state.state != TransactionState::Disputed {
-            return Ok(());
+            return Ok(Outcome::Ignored(AccountServiceError::NotDisputed));
         }
 
-        let amount = match prev_tx.amount {
-            Some(v) => v,
-            None => return Err(AccountServiceError::EmptyTransactionAmount),
-        };
+        let amount = tx.amount.unwrap_or(prev_tx_state.disputed);
+        if amount > prev_tx_state.disputed {
+            return Err(AccountServiceError::DisputeAmountExceeded);
+        }
 
-        account.resolve(amount)?;
-        // can be disputed again
-        prev_tx_state.state = TransactionState::Valid;
+        // disputed deposit is available again, disputed withdrawal stays withdrawn
+        let pending = prev_tx_state.pending;
+        match prev_tx.tx_type {
+            TransactionType::Withdrawal => account.release_held(amount, pending)?,
+            _ => account.resolve(amount, pending)?,
+        }
+        // resolved part can be disputed again
+        prev_tx_state.disputed -= amount;
+        prev_tx_state.pending -= amount.min(pending);
+        prev_tx_state.update_state();
+        tx_service.put(prev_tx_state)?;
 
-        Ok(())
+        Ok(Outcome::Applied)
     }
 
+    // amount can be omitted to charge back everything disputed
     fn chargeback(
         account: &mut Account,
-        tx_service: &mut TransactionService,
+        tx_service: &mut impl TransactionStore,
         tx: Transaction,
-    ) -> Result<(), AccountServiceError> {
-        if tx.amount.is_some() {
-            return Err(AccountServiceError::TransactionAmountShouldBeEmpty);
-        };
+    ) -> Result<Outcome, AccountServiceError> {
+        let mut prev_tx_state = stored(tx_service, tx.tx_id)?;
+        let prev_tx = prev_tx_state.tx;
 
-        let prev_tx_state = tx_service.get_mut(tx.tx_id)?;
-        let prev_tx = &prev_tx_state.tx;
-
-        check_client(prev_tx, &tx)?;
+        check_client(&prev_tx, &tx)?;
+        check_asset(&prev_tx, &tx)?;
         // skip not disputed or already solved dispute
         if prev_tx_state.state != TransactionState::Disputed {
-            return Ok(());
+            return Ok(Outcome::Ignored(AccountServiceError::NotDisputed));
         }
 
-        let amount = match prev_tx.amount {
-            Some(v) => v,
-            None => return Err(AccountServiceError::EmptyTransactionAmount),
-        };
-
-        if account.held < amount {
-            return Err(AccountServiceError::InsufficientHeldBalance);
+        let amount = tx.amount.unwrap_or(prev_tx_state.disputed);
+        if amount > prev_tx_state.disputed {
+            return Err(AccountServiceError::DisputeAmountExceeded);
         }
 
-        account.held -= amount;
-        account.locked = true;
-        prev_tx_state.state = TransactionState::Refunded;
-        Ok(())
+        // disputed deposit is taken back, disputed withdrawal is returned to the client
+        let pending = prev_tx_state.pending;
+        match prev_tx.tx_type {
+            TransactionType::Withdrawal => account.resolve(amount, pending)?,
+            _ => account.release_held(amount, pending)?,
+        }
+        account.status = AccountStatus::Locked;
+        prev_tx_state.disputed -= amount;
+        prev_tx_state.pending -= amount.min(pending);
+        prev_tx_state.refunded += amount;
+        prev_tx_state.update_state();
+        tx_service.put(prev_tx_state)?;
+        Ok(Outcome::Applied)
     }
 }
 
+fn stored(
+    tx_service: &impl TransactionStore,
+    tx_id: TransactionId,
+) -> Result<TransactionWithState, AccountServiceError> {
+    tx_service
+        .get(tx_id)?
+        .ok_or(AccountServiceError::TransactionNotFound)
+}
+
 fn check_client(prev_tx: &Transaction, tx: &Transaction) -> Result<(), AccountServiceError> {
-    if prev_tx.client_id != prev_tx.client_id {
+    if prev_tx.client_id != tx.client_id {
         return Err(AccountServiceError::MismatchedClient(
             tx.client_id,
             prev_tx.client_id,
@@ -192,11 +323,26 @@ fn check_client(prev_tx: &Transaction, tx: &Transaction) -> Result<(), AccountSe
     Ok(())
 }
 
+// asset can be omitted on dispute/resolve/chargeback
+fn check_asset(prev_tx: &Transaction, tx: &Transaction) -> Result<(), AccountServiceError> {
+    if !tx.asset.is_default() && prev_tx.asset != tx.asset {
+        return Err(AccountServiceError::MismatchedAsset(
+            tx.asset,
+            prev_tx.asset,
+        ));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::account_service::AccountService;
+    use crate::policy::{DisputeShortfall, LockedAccounts};
     use crate::tx::TransactionType;
+    use crate::tx_service::TransactionService;
+    use std::io;
 
     #[test]
     fn resolve_dispute_and_open_dispute_again() {
@@ -207,56 +353,76 @@ mod tests {
             tx_id: 13,
             tx_type: TransactionType::Deposit,
             client_id: 7,
-            amount: Some(1000),
+            amount: Some(Amount::from_units(1000)),
+            asset: Asset::default(),
         };
-        TransactionProcessor::process(&mut accounts, &mut tx_service, deposit_trans).unwrap();
+        TransactionProcessor::default()
+            .process(&mut accounts, &mut tx_service, deposit_trans)
+            .unwrap();
 
         let dispute_trans = Transaction {
             tx_id: deposit_trans.tx_id,
             tx_type: TransactionType::Dispute,
             client_id: 7,
             amount: None,
+            asset: Asset::default(),
         };
-        TransactionProcessor::process(&mut accounts, &mut tx_service, dispute_trans).unwrap();
-        let account = accounts.ensure_account(7);
-        assert_eq!(0, account.available);
-        assert_eq!(1000, account.held);
+        TransactionProcessor::default()
+            .process(&mut accounts, &mut tx_service, dispute_trans)
+            .unwrap();
+        let account = accounts.ensure_account(7, Asset::default());
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::from_units(1000), account.held);
 
         let resolve_trans = Transaction {
             tx_id: deposit_trans.tx_id,
             tx_type: TransactionType::Resolve,
             client_id: 7,
             amount: None,
+            asset: Asset::default(),
         };
-        TransactionProcessor::process(&mut accounts, &mut tx_service, resolve_trans).unwrap();
-        let account = accounts.ensure_account(7);
-        assert_eq!(1000, account.available);
-        assert_eq!(0, account.held);
+        TransactionProcessor::default()
+            .process(&mut accounts, &mut tx_service, resolve_trans)
+            .unwrap();
+        let account = accounts.ensure_account(7, Asset::default());
+        assert_eq!(Amount::from_units(1000), account.available);
+        assert_eq!(Amount::ZERO, account.held);
 
         // dispute again
-        TransactionProcessor::process(&mut accounts, &mut tx_service, dispute_trans).unwrap();
-        TransactionProcessor::process(&mut accounts, &mut tx_service, dispute_trans).unwrap();
+        TransactionProcessor::default()
+            .process(&mut accounts, &mut tx_service, dispute_trans)
+            .unwrap();
+        let result =
+            TransactionProcessor::default().process(&mut accounts, &mut tx_service, dispute_trans);
+        assert_eq!(
+            Ok(Outcome::Ignored(AccountServiceError::AlreadyDisputed)),
+            result
+        );
 
-        let account = accounts.ensure_account(7);
-        assert_eq!(0, account.available);
-        assert_eq!(1000, account.held);
+        let account = accounts.ensure_account(7, Asset::default());
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::from_units(1000), account.held);
 
         let refound_trans = Transaction {
             tx_id: deposit_trans.tx_id,
             tx_type: TransactionType::Chargeback,
             client_id: 7,
             amount: None,
+            asset: Asset::default(),
         };
 
-        TransactionProcessor::process(&mut accounts, &mut tx_service, refound_trans).unwrap();
+        TransactionProcessor::default()
+            .process(&mut accounts, &mut tx_service, refound_trans)
+            .unwrap();
 
-        let result = TransactionProcessor::process(&mut accounts, &mut tx_service, dispute_trans);
+        let result =
+            TransactionProcessor::default().process(&mut accounts, &mut tx_service, dispute_trans);
         let expected = Err(AccountServiceError::AccountLocked);
         assert_eq!(expected, result);
 
-        let account = accounts.ensure_account(7);
-        assert_eq!(0, account.available);
-        assert_eq!(0, account.held);
+        let account = accounts.ensure_account(7, Asset::default());
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::ZERO, account.held);
     }
 
     #[test]
@@ -269,46 +435,810 @@ mod tests {
             tx_type: TransactionType::Chargeback,
             client_id: 7,
             amount: None,
+            asset: Asset::default(),
         };
 
-        let result = TransactionProcessor::process(&mut accounts, &mut tx_service, refound_trans);
+        let result =
+            TransactionProcessor::default().process(&mut accounts, &mut tx_service, refound_trans);
         let expected = Err(AccountServiceError::TransactionNotFound);
         assert_eq!(expected, result);
 
-        let account = accounts.ensure_account(7);
-        assert_eq!(0, account.available);
-        assert_eq!(0, account.held);
+        let account = accounts.ensure_account(7, Asset::default());
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::ZERO, account.held);
 
         let deposit_trans = Transaction {
             tx_id: 13,
             tx_type: TransactionType::Deposit,
             client_id: 7,
-            amount: Some(1000),
+            amount: Some(Amount::from_units(1000)),
+            asset: Asset::default(),
         };
-        TransactionProcessor::process(&mut accounts, &mut tx_service, deposit_trans).unwrap();
+        TransactionProcessor::default()
+            .process(&mut accounts, &mut tx_service, deposit_trans)
+            .unwrap();
 
-        let account = accounts.ensure_account(7);
-        assert_eq!(1000, account.available);
-        assert_eq!(0, account.held);
+        let account = accounts.ensure_account(7, Asset::default());
+        assert_eq!(Amount::from_units(1000), account.available);
+        assert_eq!(Amount::ZERO, account.held);
 
         // should be skipped
-        TransactionProcessor::process(&mut accounts, &mut tx_service, refound_trans).unwrap();
-        let account = accounts.ensure_account(7);
-        assert_eq!(1000, account.available);
-        assert_eq!(0, account.held);
+        let result =
+            TransactionProcessor::default().process(&mut accounts, &mut tx_service, refound_trans);
+        assert_eq!(
+            Ok(Outcome::Ignored(AccountServiceError::NotDisputed)),
+            result
+        );
+        let account = accounts.ensure_account(7, Asset::default());
+        assert_eq!(Amount::from_units(1000), account.available);
+        assert_eq!(Amount::ZERO, account.held);
 
         let dispute_trans = Transaction {
             tx_id: deposit_trans.tx_id,
             tx_type: TransactionType::Dispute,
             client_id: 7,
             amount: None,
+            asset: Asset::default(),
+        };
+        TransactionProcessor::default()
+            .process(&mut accounts, &mut tx_service, dispute_trans)
+            .unwrap();
+
+        TransactionProcessor::default()
+            .process(&mut accounts, &mut tx_service, refound_trans)
+            .unwrap();
+        let account = accounts.ensure_account(7, Asset::default());
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::ZERO, account.held);
+        assert_eq!(AccountStatus::Locked, account.status);
+    }
+
+    #[test]
+    fn dispute_uses_asset_of_deposit() {
+        let mut accounts = AccountService::new();
+        let mut tx_service = TransactionService::new();
+        let btc: Asset = "BTC".parse().unwrap();
+        let eur: Asset = "EUR".parse().unwrap();
+
+        let deposit = |tx_id, asset| Transaction {
+            tx_id,
+            tx_type: TransactionType::Deposit,
+            client_id: 7,
+            amount: Some(Amount::from_units(1000)),
+            asset,
+        };
+        TransactionProcessor::default()
+            .process(&mut accounts, &mut tx_service, deposit(1, btc))
+            .unwrap();
+        TransactionProcessor::default()
+            .process(&mut accounts, &mut tx_service, deposit(2, eur))
+            .unwrap();
+
+        // asset omitted on dispute
+        let dispute_trans = Transaction {
+            tx_id: 1,
+            tx_type: TransactionType::Dispute,
+            client_id: 7,
+            amount: None,
+            asset: Asset::default(),
+        };
+        TransactionProcessor::default()
+            .process(&mut accounts, &mut tx_service, dispute_trans)
+            .unwrap();
+
+        let account = accounts.ensure_account(7, btc);
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::from_units(1000), account.held);
+        let account = accounts.ensure_account(7, eur);
+        assert_eq!(Amount::from_units(1000), account.available);
+        assert_eq!(Amount::ZERO, account.held);
+
+        let dispute_trans = Transaction {
+            tx_id: 2,
+            asset: btc,
+            ..dispute_trans
+        };
+        let result =
+            TransactionProcessor::default().process(&mut accounts, &mut tx_service, dispute_trans);
+        assert_eq!(Err(AccountServiceError::MismatchedAsset(btc, eur)), result);
+    }
+
+    fn withdrawal_then(
+        processor: TransactionProcessor,
+        steps: &[TransactionType],
+    ) -> (Account, Result<Outcome, AccountServiceError>) {
+        let mut accounts = AccountService::new();
+        let mut tx_service = TransactionService::new();
+        let tx = |tx_id, tx_type, amount| Transaction {
+            tx_id,
+            tx_type,
+            client_id: 7,
+            amount,
+            asset: Asset::default(),
         };
-        TransactionProcessor::process(&mut accounts, &mut tx_service, dispute_trans).unwrap();
+        let amount = Some(Amount::from_units(400));
+        processor
+            .process(
+                &mut accounts,
+                &mut tx_service,
+                tx(1, TransactionType::Deposit, Some(Amount::from_units(1000))),
+            )
+            .unwrap();
+        processor
+            .process(
+                &mut accounts,
+                &mut tx_service,
+                tx(2, TransactionType::Withdrawal, amount),
+            )
+            .unwrap();
+        let mut result = Ok(Outcome::Applied);
+        for &tx_type in steps {
+            let amount = if tx_type == TransactionType::Withdrawal {
+                amount
+            } else {
+                None
+            };
+            result = processor.process(&mut accounts, &mut tx_service, tx(2, tx_type, amount));
+        }
+        (accounts.ensure_account(7, Asset::default()).clone(), result)
+    }
 
-        TransactionProcessor::process(&mut accounts, &mut tx_service, refound_trans).unwrap();
-        let account = accounts.ensure_account(7);
-        assert_eq!(0, account.available);
-        assert_eq!(0, account.held);
-        assert_eq!(true, account.locked);
+    #[test]
+    fn withdrawal_is_stored() {
+        let processor = TransactionProcessor::default();
+        let (account, result) = withdrawal_then(processor, &[TransactionType::Withdrawal]);
+        assert_eq!(Err(AccountServiceError::TransactionDuplicate), result);
+        assert_eq!(Amount::from_units(600), account.available);
+
+        let (account, result) = withdrawal_then(processor, &[TransactionType::Dispute]);
+        assert_eq!(
+            Err(AccountServiceError::DisputeWrongTransactionType(
+                TransactionType::Withdrawal
+            )),
+            result
+        );
+        assert_eq!(Amount::ZERO, account.held);
+    }
+
+    #[test]
+    fn reverse_disputed_withdrawal() {
+        let processor = TransactionProcessor {
+            policy: ProcessingPolicy {
+                withdrawal_disputes: WithdrawalDisputes::Reverse,
+                ..ProcessingPolicy::default()
+            },
+        };
+        let (account, _) = withdrawal_then(processor, &[TransactionType::Dispute]);
+        assert_eq!(Amount::from_units(600), account.available);
+        assert_eq!(Amount::from_units(400), account.held);
+
+        // withdrawal stands
+        let (account, result) = withdrawal_then(
+            processor,
+            &[TransactionType::Dispute, TransactionType::Resolve],
+        );
+        assert_eq!(Ok(Outcome::Applied), result);
+        assert_eq!(Amount::from_units(600), account.available);
+        assert_eq!(Amount::ZERO, account.held);
+        assert_eq!(AccountStatus::Active, account.status);
+
+        // withdrawal is reversed
+        let (account, result) = withdrawal_then(
+            processor,
+            &[
+                TransactionType::Dispute,
+                TransactionType::Resolve,
+                TransactionType::Dispute,
+                TransactionType::Chargeback,
+            ],
+        );
+        assert_eq!(Ok(Outcome::Applied), result);
+        assert_eq!(Amount::from_units(1000), account.available);
+        assert_eq!(Amount::ZERO, account.held);
+        assert_eq!(AccountStatus::Locked, account.status);
+    }
+
+    #[test]
+    fn partial_disputes() {
+        let mut accounts = AccountService::new();
+        let mut tx_service = TransactionService::new();
+        let processor = TransactionProcessor::default();
+        let mut process = |tx_type, units: Option<u64>| {
+            let tx = Transaction {
+                tx_id: 3,
+                tx_type,
+                client_id: 7,
+                amount: units.map(Amount::from_units),
+                asset: Asset::default(),
+            };
+            let result = processor.process(&mut accounts, &mut tx_service, tx);
+            let account = accounts.ensure_account(7, Asset::default()).clone();
+            let state = tx_service.get(3).unwrap().unwrap();
+            (result, account, state)
+        };
+        process(TransactionType::Deposit, Some(1000)).0.unwrap();
+        process(TransactionType::Dispute, Some(300)).0.unwrap();
+        let (_, account, state) = process(TransactionType::Dispute, Some(500));
+        assert_eq!(Amount::from_units(200), account.available);
+        assert_eq!(Amount::from_units(800), account.held);
+        assert_eq!(Amount::from_units(800), state.disputed);
+        assert_eq!(TransactionState::Disputed, state.state);
+
+        // only 200 is left undisputed
+        let (result, _, _) = process(TransactionType::Dispute, Some(300));
+        assert_eq!(Err(AccountServiceError::DisputeAmountExceeded), result);
+        let (result, _, _) = process(TransactionType::Dispute, None);
+        assert_eq!(
+            Ok(Outcome::Ignored(AccountServiceError::AlreadyDisputed)),
+            result
+        );
+        let (result, _, _) = process(TransactionType::Resolve, Some(900));
+        assert_eq!(Err(AccountServiceError::DisputeAmountExceeded), result);
+
+        let (_, account, state) = process(TransactionType::Resolve, Some(300));
+        assert_eq!(Amount::from_units(500), account.available);
+        assert_eq!(Amount::from_units(500), state.disputed);
+
+        // rest of the dispute is charged back
+        let (_, account, state) = process(TransactionType::Chargeback, None);
+        assert_eq!(Amount::from_units(500), account.available);
+        assert_eq!(Amount::ZERO, account.held);
+        assert_eq!(AccountStatus::Locked, account.status);
+        assert_eq!(Amount::ZERO, state.disputed);
+        assert_eq!(Amount::from_units(500), state.refunded);
+    }
+
+    // deposit of 1000, withdrawal of 700, dispute of the deposit, then `steps` of the deposit
+    fn shortfall_then(
+        dispute_shortfall: DisputeShortfall,
+        steps: &[(TransactionType, u64)],
+    ) -> (Account, Result<Outcome, AccountServiceError>) {
+        let mut accounts = AccountService::new();
+        let mut tx_service = TransactionService::new();
+        let processor = TransactionProcessor {
+            policy: ProcessingPolicy {
+                dispute_shortfall,
+                ..ProcessingPolicy::default()
+            },
+        };
+        let prefix = [
+            (TransactionType::Deposit, 1000),
+            (TransactionType::Withdrawal, 700),
+            (TransactionType::Dispute, 0),
+        ];
+        let mut result = Ok(Outcome::Applied);
+        for (i, &(tx_type, units)) in prefix.iter().chain(steps).enumerate() {
+            // deposits and withdrawals get new ids, disputes refer to the first deposit
+            let tx_id = match tx_type {
+                TransactionType::Deposit | TransactionType::Withdrawal => i as TransactionId + 1,
+                _ => 1,
+            };
+            let tx = Transaction {
+                tx_id,
+                tx_type,
+                client_id: 7,
+                amount: (units > 0).then(|| Amount::from_units(units)),
+                asset: Asset::default(),
+            };
+            result = processor.process(&mut accounts, &mut tx_service, tx);
+        }
+        let account = accounts.ensure_account(7, Asset::default()).clone();
+        (account, result)
+    }
+
+    #[test]
+    fn reject_dispute_shortfall() {
+        let (account, result) = shortfall_then(DisputeShortfall::Reject, &[]);
+        assert_eq!(Err(AccountServiceError::InsufficientBalance), result);
+        assert_eq!(Amount::from_units(300), account.available);
+        assert_eq!(Amount::ZERO, account.held);
+    }
+
+    #[test]
+    fn hold_target_dispute_shortfall() {
+        let (account, result) = shortfall_then(DisputeShortfall::HoldTarget, &[]);
+        assert_eq!(Ok(Outcome::Applied), result);
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::from_units(300), account.held);
+        assert_eq!(Amount::from_units(700), account.hold_pending);
+
+        // new deposits are held until the target is reached
+        let (account, _) = shortfall_then(
+            DisputeShortfall::HoldTarget,
+            &[
+                (TransactionType::Deposit, 500),
+                (TransactionType::Deposit, 500),
+            ],
+        );
+        assert_eq!(Amount::from_units(300), account.available);
+        assert_eq!(Amount::from_units(1000), account.held);
+        assert_eq!(Amount::ZERO, account.hold_pending);
+
+        // resolve cancels what is not held yet
+        let (account, _) = shortfall_then(
+            DisputeShortfall::HoldTarget,
+            &[
+                (TransactionType::Deposit, 500),
+                (TransactionType::Resolve, 0),
+            ],
+        );
+        assert_eq!(Amount::from_units(800), account.available);
+        assert_eq!(Amount::ZERO, account.held);
+        assert_eq!(Amount::ZERO, account.hold_pending);
+
+        // chargeback takes what is held, the rest is no longer pending
+        let (account, _) = shortfall_then(
+            DisputeShortfall::HoldTarget,
+            &[
+                (TransactionType::Deposit, 500),
+                (TransactionType::Chargeback, 0),
+            ],
+        );
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::ZERO, account.held);
+        assert_eq!(Amount::ZERO, account.hold_pending);
+        assert_eq!(AccountStatus::Locked, account.status);
+    }
+
+    #[test]
+    fn concurrent_hold_target_disputes() {
+        let mut accounts = AccountService::new();
+        let mut tx_service = TransactionService::new();
+        let processor = TransactionProcessor {
+            policy: ProcessingPolicy {
+                dispute_shortfall: DisputeShortfall::HoldTarget,
+                locked_accounts: LockedAccounts::AllowDisputes,
+                ..ProcessingPolicy::default()
+            },
+        };
+        let mut process = |tx_type, tx_id, units: u64| {
+            let tx = Transaction {
+                tx_id,
+                tx_type,
+                client_id: 7,
+                amount: (units > 0).then(|| Amount::from_units(units)),
+                asset: Asset::default(),
+            };
+            processor
+                .process(&mut accounts, &mut tx_service, tx)
+                .unwrap();
+            let account = accounts.ensure_account(7, Asset::default()).clone();
+            (account, tx_service.get(tx_id).unwrap().unwrap())
+        };
+        process(TransactionType::Deposit, 1, 1000);
+        process(TransactionType::Deposit, 2, 1000);
+        process(TransactionType::Withdrawal, 3, 1000);
+        // the first dispute is held, the second one is pending
+        process(TransactionType::Dispute, 2, 0);
+        let (account, state) = process(TransactionType::Dispute, 1, 0);
+        assert_eq!(Amount::from_units(1000), account.held);
+        assert_eq!(Amount::from_units(1000), account.hold_pending);
+        assert_eq!(Amount::from_units(1000), state.pending);
+
+        // chargeback of the pending dispute takes nothing, the other one stays held
+        let (account, state) = process(TransactionType::Chargeback, 1, 0);
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::from_units(1000), account.held);
+        assert_eq!(Amount::ZERO, account.hold_pending);
+        assert_eq!(Amount::ZERO, state.pending);
+
+        // held dispute is available again
+        let (account, _) = process(TransactionType::Resolve, 2, 0);
+        assert_eq!(Amount::from_units(1000), account.available);
+        assert_eq!(Amount::ZERO, account.held);
+    }
+
+    #[test]
+    fn resolve_of_hold_target_dispute_covers_the_other() {
+        let mut accounts = AccountService::new();
+        let mut tx_service = TransactionService::new();
+        let processor = TransactionProcessor {
+            policy: ProcessingPolicy {
+                dispute_shortfall: DisputeShortfall::HoldTarget,
+                ..ProcessingPolicy::default()
+            },
+        };
+        let mut process = |tx_type, tx_id, units: u64| {
+            let tx = Transaction {
+                tx_id,
+                tx_type,
+                client_id: 7,
+                amount: (units > 0).then(|| Amount::from_units(units)),
+                asset: Asset::default(),
+            };
+            processor
+                .process(&mut accounts, &mut tx_service, tx)
+                .unwrap();
+            accounts.ensure_account(7, Asset::default()).clone()
+        };
+        process(TransactionType::Deposit, 1, 1000);
+        process(TransactionType::Deposit, 2, 1000);
+        process(TransactionType::Withdrawal, 3, 1500);
+        // 500 of the first dispute is held, the rest and the second one are pending
+        process(TransactionType::Dispute, 1, 0);
+        process(TransactionType::Dispute, 2, 0);
+
+        // the first dispute releases its held part, which covers the second one first
+        let account = process(TransactionType::Resolve, 1, 0);
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::from_units(500), account.held);
+        assert_eq!(Amount::from_units(500), account.hold_pending);
+
+        // a deposit completes the hold of the second dispute
+        let account = process(TransactionType::Deposit, 4, 800);
+        assert_eq!(Amount::from_units(300), account.available);
+        assert_eq!(Amount::from_units(1000), account.held);
+        assert_eq!(Amount::ZERO, account.hold_pending);
+    }
+
+    #[test]
+    fn negative_dispute_shortfall() {
+        let (account, result) = shortfall_then(DisputeShortfall::Negative, &[]);
+        assert_eq!(Ok(Outcome::Applied), result);
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::from_units(1000), account.held);
+        assert_eq!(Amount::from_units(700), account.receivable);
+        assert_eq!(300, account.net_total());
+
+        // new deposits pay the receivable first
+        let (account, _) = shortfall_then(
+            DisputeShortfall::Negative,
+            &[(TransactionType::Deposit, 500)],
+        );
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::from_units(200), account.receivable);
+
+        // resolve pays the receivable back from the held amount
+        let (account, _) =
+            shortfall_then(DisputeShortfall::Negative, &[(TransactionType::Resolve, 0)]);
+        assert_eq!(Amount::from_units(300), account.available);
+        assert_eq!(Amount::ZERO, account.held);
+        assert_eq!(Amount::ZERO, account.receivable);
+
+        // after a chargeback the client still owes the withdrawn part
+        let (account, _) = shortfall_then(
+            DisputeShortfall::Negative,
+            &[(TransactionType::Chargeback, 0)],
+        );
+        assert_eq!(Amount::ZERO, account.available);
+        assert_eq!(Amount::ZERO, account.held);
+        assert_eq!(Amount::from_units(700), account.receivable);
+        assert_eq!(-700, account.net_total());
+        assert_eq!(AccountStatus::Locked, account.status);
+    }
+
+    #[test]
+    fn account_administration() {
+        let mut accounts = AccountService::new();
+        let mut tx_service = TransactionService::new();
+        let processor = TransactionProcessor::default();
+        let mut process = |tx_type, tx_id, units: Option<u64>| {
+            let tx = Transaction {
+                tx_id,
+                tx_type,
+                client_id: 7,
+                amount: units.map(Amount::from_units),
+                asset: Asset::default(),
+            };
+            processor.process(&mut accounts, &mut tx_service, tx)
+        };
+        process(TransactionType::Deposit, 1, Some(1000)).unwrap();
+        process(TransactionType::Deposit, 2, Some(500)).unwrap();
+        process(TransactionType::Dispute, 1, None).unwrap();
+        process(TransactionType::Chargeback, 1, None).unwrap();
+        assert_eq!(
+            Err(AccountServiceError::AccountLocked),
+            process(TransactionType::Deposit, 3, Some(100))
+        );
+        assert_eq!(
+            Ok(Outcome::Administered(StatusChange {
+                from: AccountStatus::Locked,
+                to: AccountStatus::Active,
+                payout: Amount::ZERO,
+            })),
+            process(TransactionType::Unlock, 100, None)
+        );
+        assert_eq!(
+            Ok(Outcome::Ignored(AccountServiceError::NotLocked)),
+            process(TransactionType::Unlock, 101, None)
+        );
+
+        // frozen account still handles disputes
+        process(TransactionType::Freeze, 102, None).unwrap();
+        assert_eq!(
+            Ok(Outcome::Ignored(AccountServiceError::AlreadyFrozen)),
+            process(TransactionType::Freeze, 103, None)
+        );
+        assert_eq!(
+            Err(AccountServiceError::AccountFrozen),
+            process(TransactionType::Withdrawal, 4, Some(100))
+        );
+        assert_eq!(
+            Ok(Outcome::Applied),
+            process(TransactionType::Dispute, 2, None)
+        );
+
+        // close needs settled disputes and pays out available funds
+        assert_eq!(
+            Err(AccountServiceError::AccountNotSettled),
+            process(TransactionType::Close, 104, None)
+        );
+        process(TransactionType::Resolve, 2, None).unwrap();
+        assert_eq!(
+            Err(AccountServiceError::PayoutMismatch),
+            process(TransactionType::Close, 105, Some(100))
+        );
+        assert_eq!(
+            Ok(Outcome::Administered(StatusChange {
+                from: AccountStatus::Frozen,
+                to: AccountStatus::Closed,
+                payout: Amount::from_units(500),
+            })),
+            process(TransactionType::Close, 106, Some(500))
+        );
+        assert_eq!(
+            Err(AccountServiceError::AccountClosed),
+            process(TransactionType::Unlock, 107, None)
+        );
+        assert_eq!(
+            Err(AccountServiceError::AccountClosed),
+            process(TransactionType::Deposit, 5, Some(100))
+        );
+        let account = accounts.ensure_account(7, Asset::default());
+        assert_eq!(Amount::ZERO, account.total());
+        assert!(account.is_locked());
+
+        // operations of an unknown account do not create it
+        let freeze = Transaction {
+            tx_type: TransactionType::Freeze,
+            client_id: 8,
+            tx_id: 108,
+            amount: None,
+            asset: Asset::default(),
+        };
+        assert_eq!(
+            Err(AccountServiceError::AccountNotFound),
+            processor.process(&mut accounts, &mut tx_service, freeze)
+        );
+        assert_eq!(accounts.accounts().count(), 1);
+    }
+
+    #[test]
+    fn policy_combinations() {
+        let mut policies = Vec::new();
+        for withdrawal_disputes in [WithdrawalDisputes::Reject, WithdrawalDisputes::Reverse] {
+            for dispute_shortfall in [
+                DisputeShortfall::Reject,
+                DisputeShortfall::HoldTarget,
+                DisputeShortfall::Negative,
+            ] {
+                for repeat_disputes in [RepeatDisputes::Ignore, RepeatDisputes::Reject] {
+                    for locked_accounts in
+                        [LockedAccounts::RejectAll, LockedAccounts::AllowDisputes]
+                    {
+                        for resolved_disputes in [ResolvedDisputes::Allow, ResolvedDisputes::Reject]
+                        {
+                            policies.push(ProcessingPolicy {
+                                withdrawal_disputes,
+                                dispute_shortfall,
+                                repeat_disputes,
+                                locked_accounts,
+                                resolved_disputes,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        assert_eq!(policies.len(), 48);
+
+        for policy in policies {
+            let mut accounts = AccountService::new();
+            let mut tx_service = TransactionService::new();
+            let processor = TransactionProcessor { policy };
+            let mut process = |tx_type, tx_id, units: Option<u64>| {
+                let tx = Transaction {
+                    tx_id,
+                    tx_type,
+                    client_id: 7,
+                    amount: units.map(Amount::from_units),
+                    asset: Asset::default(),
+                };
+                processor.process(&mut accounts, &mut tx_service, tx)
+            };
+            let applied = Ok(Outcome::Applied);
+            process(TransactionType::Deposit, 1, Some(1000)).unwrap();
+            process(TransactionType::Deposit, 2, Some(500)).unwrap();
+            process(TransactionType::Withdrawal, 3, Some(100)).unwrap();
+
+            let expected = match policy.withdrawal_disputes {
+                WithdrawalDisputes::Reject => Err(
+                    AccountServiceError::DisputeWrongTransactionType(TransactionType::Withdrawal),
+                ),
+                WithdrawalDisputes::Reverse => Ok(Outcome::Applied),
+            };
+            assert_eq!(expected, process(TransactionType::Dispute, 3, None));
+
+            assert_eq!(applied, process(TransactionType::Dispute, 1, None));
+            let expected = match policy.repeat_disputes {
+                RepeatDisputes::Ignore => {
+                    Ok(Outcome::Ignored(AccountServiceError::AlreadyDisputed))
+                }
+                RepeatDisputes::Reject => Err(AccountServiceError::AlreadyDisputed),
+            };
+            assert_eq!(expected, process(TransactionType::Dispute, 1, None));
+            assert_eq!(applied, process(TransactionType::Resolve, 1, None));
+
+            let redispute = match policy.resolved_disputes {
+                ResolvedDisputes::Allow => Ok(Outcome::Applied),
+                ResolvedDisputes::Reject => Err(AccountServiceError::AlreadyResolved),
+            };
+            assert_eq!(redispute, process(TransactionType::Dispute, 1, Some(100)));
+            if redispute.is_ok() {
+                process(TransactionType::Resolve, 1, None).unwrap();
+            }
+
+            // chargeback locks the account
+            process(TransactionType::Dispute, 2, None).unwrap();
+            assert_eq!(applied, process(TransactionType::Chargeback, 2, None));
+            assert_eq!(
+                Err(AccountServiceError::AccountLocked),
+                process(TransactionType::Deposit, 4, Some(100))
+            );
+            let expected = match policy.locked_accounts {
+                LockedAccounts::RejectAll => Err(AccountServiceError::AccountLocked),
+                LockedAccounts::AllowDisputes => redispute,
+            };
+            assert_eq!(expected, process(TransactionType::Dispute, 1, Some(100)));
+
+            // dispute of a deposit which was partly withdrawn
+            let tx = |tx_type, tx_id, amount: Option<u64>| Transaction {
+                tx_id,
+                tx_type,
+                client_id: 8,
+                amount: amount.map(Amount::from_units),
+                asset: Asset::default(),
+            };
+            for tx in [
+                tx(TransactionType::Deposit, 10, Some(300)),
+                tx(TransactionType::Withdrawal, 11, Some(200)),
+            ] {
+                processor
+                    .process(&mut accounts, &mut tx_service, tx)
+                    .unwrap();
+            }
+            let expected = match policy.dispute_shortfall {
+                DisputeShortfall::Reject => Err(AccountServiceError::InsufficientBalance),
+                DisputeShortfall::HoldTarget | DisputeShortfall::Negative => applied,
+            };
+            let dispute = tx(TransactionType::Dispute, 10, None);
+            assert_eq!(
+                expected,
+                processor.process(&mut accounts, &mut tx_service, dispute)
+            );
+        }
+    }
+
+    // instrumented store, counts writes of the wrapped in-memory store, or fails them
+    #[derive(Default)]
+    struct CountingStore {
+        inner: TransactionService,
+        puts: usize,
+        failing: bool,
+    }
+
+    impl TransactionStore for CountingStore {
+        fn ids(&self) -> &crate::tx_ids::TxIdSet {
+            self.inner.ids()
+        }
+
+        fn get(&self, transaction_id: TransactionId) -> io::Result<Option<TransactionWithState>> {
+            self.inner.get(transaction_id)
+        }
+
+        fn put(&mut self, tx_state: TransactionWithState) -> io::Result<()> {
+            if self.failing {
+                return Err(io::Error::other("disk full"));
+            }
+            self.puts += 1;
+            self.inner.put(tx_state)
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = io::Result<TransactionWithState>> + '_> {
+            self.inner.iter()
+        }
+    }
+
+    #[test]
+    fn process_with_custom_store() {
+        let mut accounts = AccountService::new();
+        let mut store = CountingStore::default();
+        let processor = TransactionProcessor::default();
+        for (tx_type, tx_id, amount) in [
+            (TransactionType::Deposit, 1, Some(Amount::from_units(1000))),
+            (TransactionType::Deposit, 1, Some(Amount::from_units(1000))),
+            (TransactionType::Dispute, 1, None),
+            (TransactionType::Dispute, 1, None),
+            (TransactionType::Resolve, 1, None),
+        ] {
+            let tx = Transaction {
+                tx_id,
+                tx_type,
+                client_id: 7,
+                amount,
+                asset: Asset::default(),
+            };
+            let _ = processor.process(&mut accounts, &mut store, tx);
+        }
+        // duplicate and skipped dispute are not written
+        assert_eq!(3, store.puts);
+        assert_eq!(
+            TransactionState::Resolved,
+            store.get(1).unwrap().unwrap().state
+        );
+        assert_eq!(1, store.iter().count());
+    }
+
+    #[test]
+    fn storage_error_leaves_account_unchanged() {
+        let mut accounts = AccountService::new();
+        let mut store = CountingStore::default();
+        let processor = TransactionProcessor::default();
+        let tx = |tx_type, tx_id| Transaction {
+            tx_id,
+            tx_type,
+            client_id: 7,
+            amount: Some(Amount::from_units(1000)),
+            asset: Asset::default(),
+        };
+        processor
+            .process(&mut accounts, &mut store, tx(TransactionType::Deposit, 1))
+            .unwrap();
+        store.failing = true;
+        for tx in [
+            tx(TransactionType::Deposit, 2),
+            tx(TransactionType::Withdrawal, 3),
+            tx(TransactionType::Dispute, 1),
+        ] {
+            let result = processor.process(&mut accounts, &mut store, tx);
+            assert_eq!(
+                Err(AccountServiceError::StorageFailed("disk full".to_string())),
+                result
+            );
+        }
+        let account = accounts.ensure_account(7, Asset::default());
+        assert_eq!(Amount::from_units(1000), account.available);
+        assert_eq!(Amount::ZERO, account.held);
+        assert_eq!(
+            TransactionState::Valid,
+            store.get(1).unwrap().unwrap().state
+        );
+    }
+
+    #[test]
+    fn dispute_of_other_client_is_rejected() {
+        let mut accounts = AccountService::new();
+        let mut tx_service = TransactionService::new();
+        let processor = TransactionProcessor::default();
+        let deposit = Transaction {
+            tx_id: 21,
+            tx_type: TransactionType::Deposit,
+            client_id: 7,
+            amount: Some(Amount::from_units(1000)),
+            asset: Asset::default(),
+        };
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            client_id: 8,
+            amount: None,
+            ..deposit
+        };
+        processor
+            .process(&mut accounts, &mut tx_service, deposit)
+            .unwrap();
+        assert!(matches!(
+            processor.process(&mut accounts, &mut tx_service, dispute),
+            Err(AccountServiceError::MismatchedClient(8, 7))
+        ));
     }
 }
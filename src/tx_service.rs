@@ -1,41 +1,187 @@
-use crate::account_service::AccountServiceError;
+use crate::amount::{AmountFormat, FormattedAmount};
 use crate::tx::*;
+use crate::tx_ids::TxIdSet;
 
+use serde::Serialize;
 use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum TransactionState {
     Valid,
     Disputed,
     Refunded,
+    // no dispute is open, but the transaction was disputed before
+    Resolved,
     // Rejected, // we store only disputable transactions in this impl
 }
 
+#[derive(Debug, Copy, Clone)]
 pub struct TransactionWithState {
     pub tx: Transaction,
     pub state: TransactionState,
+    // currently disputed part of the amount, disputes can be partial
+    pub disputed: Amount,
+    // part of the amount taken back by chargebacks
+    pub refunded: Amount,
+    // part of the disputed amount which was not held (DisputeShortfall::HoldTarget), it is
+    // held from later deposits of the account, so it is at most this much
+    pub pending: Amount,
+}
+
+impl TransactionWithState {
+    pub fn new(tx: Transaction) -> Self {
+        Self {
+            tx,
+            state: TransactionState::Valid,
+            disputed: Amount::ZERO,
+            refunded: Amount::ZERO,
+            pending: Amount::ZERO,
+        }
+    }
+
+    // part of the amount which can still be disputed
+    pub fn undisputed(&self) -> Amount {
+        self.tx.amount.unwrap_or(Amount::ZERO) - self.disputed - self.refunded
+    }
+
+    // state follows the disputed and refunded parts
+    pub fn update_state(&mut self) {
+        self.state = if Some(self.refunded) == self.tx.amount {
+            TransactionState::Refunded
+        } else if self.disputed > Amount::ZERO {
+            TransactionState::Disputed
+        } else if self.state == TransactionState::Valid {
+            TransactionState::Valid
+        } else {
+            TransactionState::Resolved
+        };
+    }
+}
+
+// stored transaction with its state, as returned by queries
+#[derive(Debug, Serialize)]
+pub struct TransactionResult {
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub amount: Option<FormattedAmount>,
+    pub asset: Asset,
+    pub state: TransactionState,
+    pub disputed: FormattedAmount,
+    pub refunded: FormattedAmount,
+}
+
+impl TransactionResult {
+    pub fn new(t: &TransactionWithState, format: AmountFormat) -> Self {
+        Self {
+            tx_type: t.tx.tx_type,
+            client: t.tx.client_id,
+            tx: t.tx.tx_id,
+            amount: t.tx.amount.map(|a| format.display(a)),
+            asset: t.tx.asset,
+            state: t.state,
+            disputed: format.display(t.disputed),
+            refunded: format.display(t.refunded),
+        }
+    }
+}
+
+// storage of deposits and withdrawals with their dispute state, TransactionProcessor works
+// against it, so stores can keep transactions in memory, on disk or packed, stores which
+// can fail (ie. on disk) return the i/o error instead of panicking
+pub trait TransactionStore {
+    // ids of deposits and withdrawals, shared by the shards of an engine
+    fn ids(&self) -> &TxIdSet;
+
+    fn get(&self, transaction_id: TransactionId) -> io::Result<Option<TransactionWithState>>;
+
+    // adds or replaces the transaction with the same id, the id must be claimed already
+    fn put(&mut self, tx_state: TransactionWithState) -> io::Result<()>;
+
+    // every stored transaction, in no particular order, iteration may stop after an error
+    fn iter(&self) -> Box<dyn Iterator<Item = io::Result<TransactionWithState>> + '_>;
+
+    // put() of a transaction whose id is not claimed yet, ie. from a snapshot
+    fn insert(&mut self, tx_state: TransactionWithState) -> io::Result<()> {
+        self.ids().insert(tx_state.tx.tx_id);
+        self.put(tx_state)
+    }
+
+    // false when the id is already used by a deposit or withdrawal, in any shard
+    fn claim(&self, transaction_id: TransactionId) -> bool {
+        self.ids().insert(transaction_id)
+    }
+}
+
+// store picked at run time, ie. in memory or on disk
+pub type BoxedTransactionStore = Box<dyn TransactionStore + Send>;
+
+impl<T: TransactionStore + ?Sized> TransactionStore for Box<T> {
+    fn ids(&self) -> &TxIdSet {
+        (**self).ids()
+    }
+
+    fn get(&self, transaction_id: TransactionId) -> io::Result<Option<TransactionWithState>> {
+        (**self).get(transaction_id)
+    }
+
+    fn put(&mut self, tx_state: TransactionWithState) -> io::Result<()> {
+        (**self).put(tx_state)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = io::Result<TransactionWithState>> + '_> {
+        (**self).iter()
+    }
 }
 
 type TransactionStorage = HashMap<TransactionId, TransactionWithState>;
 
+// in-memory transaction store
 pub struct TransactionService {
-    // TODO: remove pub (provide access method to vacant entry)
-    pub trans: TransactionStorage,
+    trans: TransactionStorage,
+    // ids of deposits and withdrawals, shared by the shards of an engine
+    ids: Arc<TxIdSet>,
+}
+
+impl Default for TransactionService {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TransactionService {
     pub fn new() -> Self {
+        Self::with_ids(Arc::new(TxIdSet::new()))
+    }
+
+    // transaction ids are unique among all services sharing `ids`
+    pub fn with_ids(ids: Arc<TxIdSet>) -> Self {
         Self {
             trans: TransactionStorage::default(),
+            ids,
         }
     }
+}
+
+impl TransactionStore for TransactionService {
+    fn ids(&self) -> &TxIdSet {
+        &self.ids
+    }
+
+    fn get(&self, transaction_id: TransactionId) -> io::Result<Option<TransactionWithState>> {
+        Ok(self.trans.get(&transaction_id).copied())
+    }
+
+    fn put(&mut self, tx_state: TransactionWithState) -> io::Result<()> {
+        self.trans.insert(tx_state.tx.tx_id, tx_state);
+        Ok(())
+    }
 
-    pub fn get_mut(
-        &mut self,
-        transaction_id: TransactionId,
-    ) -> Result<&mut TransactionWithState, AccountServiceError> {
-        self.trans
-            .get_mut(&transaction_id)
-            .ok_or(AccountServiceError::TransactionNotFound)
+    fn iter(&self) -> Box<dyn Iterator<Item = io::Result<TransactionWithState>> + '_> {
+        Box::new(self.trans.values().copied().map(Ok))
     }
 }
@@ -0,0 +1,372 @@
+use crate::codec;
+use crate::tx::Transaction;
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const MAGIC: &[u8; 8] = b"TXWAL\0\0\0";
+const VERSION: u32 = 2;
+const HEADER_LEN: u64 = 28;
+// framed transaction, see codec::write_record()
+const RECORD_LEN: u64 = codec::TRANSACTION_LEN as u64 + 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SyncPolicy {
+    // fsync after every record
+    Always,
+    // fsync after every N records
+    EveryRecords(u64),
+    // fsync only when the shard is joined
+    OnJoin,
+    // appends after N records fail, as on a full disk
+    #[cfg(test)]
+    FailAfter(u64),
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "on-join" => Ok(SyncPolicy::OnJoin),
+            n => match n.parse::<u64>() {
+                Ok(n) if n > 0 => Ok(SyncPolicy::EveryRecords(n)),
+                _ => Err(format!(
+                    "unknown sync policy '{}', expected always, on-join or number of records",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WalConfig {
+    // directory with one log file per shard
+    pub dir: PathBuf,
+    pub sync: SyncPolicy,
+}
+
+impl WalConfig {
+    pub fn shard_path(&self, shard: usize) -> PathBuf {
+        self.dir.join(format!("shard-{}.wal", shard))
+    }
+
+    // copy of the snapshot loaded into the empty logs, the records continue from its state
+    pub fn base_path(&self) -> PathBuf {
+        self.dir.join("base.snap")
+    }
+
+    // true when a log of any of the shards has records
+    pub fn has_records(&self, shards: usize) -> io::Result<bool> {
+        for shard in 0..shards {
+            match std::fs::metadata(self.shard_path(shard)) {
+                Ok(meta) if meta.len() > HEADER_LEN => return Ok(true),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(false)
+    }
+}
+
+// engine setup the log was written with, replay into a different setup is refused
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WalHeader {
+    pub shard: u32,
+    pub shards: u32,
+    pub scale: u32,
+    // ProcessingPolicy::fingerprint(), replay with other business rules would diverge
+    pub policy: u32,
+}
+
+impl WalHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN as usize);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&self.shard.to_le_bytes());
+        buf.extend_from_slice(&self.shards.to_le_bytes());
+        buf.extend_from_slice(&self.scale.to_le_bytes());
+        buf.extend_from_slice(&self.policy.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; HEADER_LEN as usize]) -> io::Result<Self> {
+        let field = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        if &buf[..8] != MAGIC || field(8) != VERSION {
+            return Err(codec::invalid_data(
+                "not a write-ahead log or unsupported version",
+            ));
+        }
+        Ok(WalHeader {
+            shard: field(12),
+            shards: field(16),
+            scale: field(20),
+            policy: field(24),
+        })
+    }
+}
+
+// per-shard append-only log of transactions received by the shard
+pub struct WriteAheadLog {
+    file: BufWriter<File>,
+    sync: SyncPolicy,
+    unsynced: u64,
+    buf: Vec<u8>,
+}
+
+impl WriteAheadLog {
+    // replays every complete record of an existing log through `apply`, an error of it ends
+    // the replay, drops a torn tail left by a crash and opens the log for appending,
+    // a broken record followed by more records is corruption, the log is left as it is
+    pub fn open<F>(path: &Path, header: WalHeader, sync: SyncPolicy, apply: F) -> io::Result<Self>
+    where
+        F: FnMut(Transaction) -> io::Result<()>,
+    {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        // a log shorter than its header has no records yet
+        if file.metadata()?.len() < HEADER_LEN {
+            file.set_len(0)?;
+            file.write_all(&header.encode())?;
+            file.sync_data()?;
+        } else {
+            let end = replay(&file, header, apply)?;
+            if end < file.metadata()?.len() {
+                eprintln!(
+                    "Write-ahead log {} has a torn tail, truncating at byte {}",
+                    path.display(),
+                    end
+                );
+                file.set_len(end)?;
+                file.sync_data()?;
+            }
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            file: BufWriter::new(file),
+            sync,
+            unsynced: 0,
+            buf: Vec::with_capacity(codec::TRANSACTION_LEN),
+        })
+    }
+
+    pub fn append(&mut self, tx: &Transaction) -> io::Result<()> {
+        #[cfg(test)]
+        if matches!(self.sync, SyncPolicy::FailAfter(n) if self.unsynced >= n) {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, "no space left"));
+        }
+        self.buf.clear();
+        codec::encode_transaction(tx, &mut self.buf);
+        codec::write_record(&mut self.file, &self.buf)?;
+        self.unsynced += 1;
+        match self.sync {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::EveryRecords(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    // called on join, whatever the policy nothing stays unsynced
+    pub fn close(mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.sync()?;
+        }
+        Ok(())
+    }
+}
+
+// returns offset of the end of the last complete record
+fn replay<F>(file: &File, expected: WalHeader, mut apply: F) -> io::Result<u64>
+where
+    F: FnMut(Transaction) -> io::Result<()>,
+{
+    let mut reader = BufReader::new(file);
+    let mut header = [0; HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let header = WalHeader::decode(&header)?;
+    if header != expected {
+        return Err(codec::invalid_data(&format!(
+            "write-ahead log written with {:?}, engine runs with {:?}",
+            header, expected
+        )));
+    }
+
+    let len = file.metadata()?.len();
+    let mut end = HEADER_LEN;
+    let mut payload = Vec::with_capacity(codec::TRANSACTION_LEN);
+    loop {
+        let tx = match codec::read_record(&mut reader, &mut payload, codec::TRANSACTION_LEN) {
+            Ok(Some(())) => codec::decode_transaction(&payload),
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => None,
+            Err(e) => return Err(e),
+        };
+        match tx {
+            Some(tx) => apply(tx)?,
+            // records have the same length, a torn one is the last
+            None if len - end <= RECORD_LEN => break,
+            None => {
+                return Err(codec::invalid_data(&format!(
+                    "write-ahead log has a corrupted record at byte {}",
+                    end
+                )))
+            }
+        }
+        end += RECORD_LEN;
+    }
+    Ok(end)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tx::*;
+
+    const HEADER: WalHeader = WalHeader {
+        shard: 1,
+        shards: 4,
+        scale: 3,
+        policy: 0,
+    };
+
+    fn deposit(tx_id: TransactionId) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Deposit,
+            client_id: 5,
+            tx_id,
+            amount: Some(Amount::from_units(1000)),
+            asset: Asset::default(),
+        }
+    }
+
+    fn reopen(path: &Path) -> io::Result<(WriteAheadLog, Vec<TransactionId>)> {
+        let mut replayed = Vec::new();
+        let wal = WriteAheadLog::open(path, HEADER, SyncPolicy::OnJoin, |tx| {
+            replayed.push(tx.tx_id);
+            Ok(())
+        })?;
+        Ok((wal, replayed))
+    }
+
+    #[test]
+    fn replay_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shard-1.wal");
+
+        let (mut wal, replayed) = reopen(&path).unwrap();
+        assert!(replayed.is_empty());
+        for i in 0..3 {
+            wal.append(&deposit(i)).unwrap();
+        }
+        wal.close().unwrap();
+
+        let (mut wal, replayed) = reopen(&path).unwrap();
+        assert_eq!(replayed, vec![0, 1, 2]);
+        wal.append(&deposit(3)).unwrap();
+        wal.close().unwrap();
+
+        let (_, replayed) = reopen(&path).unwrap();
+        assert_eq!(replayed, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shard-1.wal");
+
+        let (mut wal, _) = reopen(&path).unwrap();
+        wal.append(&deposit(0)).unwrap();
+        wal.append(&deposit(1)).unwrap();
+        wal.close().unwrap();
+
+        // simulate crash in the middle of a record
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let (mut wal, replayed) = reopen(&path).unwrap();
+        assert_eq!(replayed, vec![0]);
+        wal.append(&deposit(2)).unwrap();
+        wal.close().unwrap();
+
+        let (_, replayed) = reopen(&path).unwrap();
+        assert_eq!(replayed, vec![0, 2]);
+    }
+
+    #[test]
+    fn corrupted_record_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shard-1.wal");
+
+        let (mut wal, _) = reopen(&path).unwrap();
+        for tx_id in 0..3 {
+            wal.append(&deposit(tx_id)).unwrap();
+        }
+        wal.close().unwrap();
+
+        // damaged payload of the first record, later records are intact
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER_LEN as usize + 6] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        assert!(reopen(&path).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn header_mismatch_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shard-1.wal");
+        reopen(&path).unwrap().0.close().unwrap();
+
+        for other in [
+            WalHeader {
+                shards: 8,
+                ..HEADER
+            },
+            WalHeader {
+                policy: 1,
+                ..HEADER
+            },
+        ] {
+            let result = WriteAheadLog::open(&path, other, SyncPolicy::Always, |_| Ok(()));
+            assert_eq!(
+                result.err().map(|e| e.kind()),
+                Some(io::ErrorKind::InvalidData)
+            );
+        }
+    }
+
+    #[test]
+    fn sync_policy_from_str() {
+        assert_eq!("always".parse(), Ok(SyncPolicy::Always));
+        assert_eq!("on-join".parse(), Ok(SyncPolicy::OnJoin));
+        assert_eq!("100".parse(), Ok(SyncPolicy::EveryRecords(100)));
+        assert!("0".parse::<SyncPolicy>().is_err());
+    }
+}